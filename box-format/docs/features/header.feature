@@ -5,6 +5,8 @@ Feature: Archive header
     * a 32-bit unsigned LE integer field named `version`
     * a 64-bit unsigned LE integer field named `alignment`
     * a 64-bit unsigned LE non-zero integer field named `trailer`
+    * a 32-bit unsigned LE integer field named `trailer_checksum`, the CRC32 of the trailer and index
+    * a 32-bit unsigned LE integer field named `flags`
 
   Scenario: A valid .box header
     Given a valid .box header
@@ -0,0 +1,37 @@
+Feature: Header `flags` field
+
+  Rule: Flags MUST only contain known bits
+
+    | Bit    | Name                 | Meaning                                                                  |
+    | 0x1    | `encrypted_metadata` | The trailer and index are encrypted with the content key                 |
+    | 0x2    | `attr_sets`          | Records take some of their attributes from shared attribute sets         |
+    | 0x4    | `compressed_trailer` | The trailer and index are compressed, starting with the compression used |
+    | 0x8    | `record_index`       | The trailer is preceded by an index of the offset of every record        |
+
+    Scenario: Known flags are found
+      Given a box file with a valid header
+      When the header is parsed
+      Then a valid header is returned
+
+    Scenario: Unknown flags are found
+      Given a box file with a flag bit that is not known
+      When the header is parsed
+      Then an error regarding unsupported flags is returned
+
+  Rule: Encrypted metadata MUST NOT be readable without the content key
+
+    Scenario: Archive with encrypted metadata is opened without a key
+      Given a box file with the `encrypted_metadata` flag set
+      When the trailer is loaded
+      Then only the attributes needed to recover the content key are returned
+
+    Scenario: Archive with encrypted metadata is unlocked
+      Given a box file with the `encrypted_metadata` flag set
+      When it is unlocked with a password or identity of one of its recipients
+      Then the trailer and index are decrypted and loaded
+
+    Scenario: Attributes needed to recover the content key are modified
+      Given a box file with the `encrypted_metadata` flag set
+      And anything before its sealed trailer has been modified
+      When it is unlocked
+      Then an error is returned
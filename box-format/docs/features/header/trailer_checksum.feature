@@ -0,0 +1,13 @@
+Feature: Header `trailer_checksum` field
+
+  Rule: Trailer checksum MUST be the CRC32 of all bytes from the trailer to the end of the file
+
+    Scenario: Valid trailer checksum is found
+      Given a box file with a valid header
+      When the trailer is loaded
+      Then the trailer parses successfully
+
+    Scenario: Invalid trailer checksum is found
+      Given a box file whose trailer or index has been modified
+      When the trailer is loaded
+      Then an error regarding corrupted metadata is returned
@@ -0,0 +1,131 @@
+//! POSIX ACLs, which are kept in an encoding of their own rather than that of any one
+//! platform, and are only read and written on Linux.
+
+#[cfg(feature = "writer")]
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// The attribute key under which the access ACL of a file is kept. Each entry is 6 bytes: its
+/// tag (1 for the owner, 2 for a named user, 3 for the owning group, 4 for a named group, 5
+/// for the mask and 6 for others), its permissions (4 for read, 2 for write and 1 for
+/// execute, added together), and the uid or gid it names as 4 LE bytes, which is zero for
+/// entries that name none.
+pub const ACL_ATTR: &str = "unix.acl";
+
+/// The attribute key under which the default ACL of a directory is kept, which files created
+/// in it inherit. It is encoded as for `ACL_ATTR`.
+pub const DEFAULT_ACL_ATTR: &str = "unix.acl.default";
+
+/// Each ACL attribute and the Linux extended attribute it is kept in.
+#[cfg(target_os = "linux")]
+const POSIX_ACL_XATTRS: [(&str, &str); 2] = [
+    (ACL_ATTR, "system.posix_acl_access"),
+    (DEFAULT_ACL_ATTR, "system.posix_acl_default"),
+];
+
+/// Linux ACL tags in the order of the tags of the encoding, from 1.
+#[cfg(target_os = "linux")]
+const POSIX_ACL_TAGS: [u16; 6] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20];
+
+#[cfg(target_os = "linux")]
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+#[cfg(target_os = "linux")]
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed {}", what))
+}
+
+/// Converts a Linux `system.posix_acl_*` extended attribute to an ACL attribute.
+#[cfg(all(feature = "writer", target_os = "linux"))]
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn from_posix_xattr(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    if bytes.len() < 4 || (bytes.len() - 4) % 8 != 0 {
+        return Err(malformed("POSIX ACL"));
+    }
+    if u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) != POSIX_ACL_XATTR_VERSION {
+        return Err(malformed("POSIX ACL version"));
+    }
+
+    let mut acl = Vec::with_capacity((bytes.len() - 4) / 8 * 6);
+    for entry in bytes[4..].chunks(8) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let tag = POSIX_ACL_TAGS
+            .iter()
+            .position(|x| *x == tag)
+            .ok_or_else(|| malformed("POSIX ACL tag"))?;
+        let id = match tag {
+            // Named users and groups
+            1 | 3 => [entry[4], entry[5], entry[6], entry[7]],
+            _ => [0; 4],
+        };
+
+        acl.push(tag as u8 + 1);
+        acl.push(entry[2] & 0o7);
+        acl.extend_from_slice(&id);
+    }
+    Ok(acl)
+}
+
+/// Converts an ACL attribute to a Linux `system.posix_acl_*` extended attribute.
+#[cfg(all(feature = "reader", target_os = "linux"))]
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn to_posix_xattr(acl: &[u8]) -> io::Result<Vec<u8>> {
+    if acl.len() % 6 != 0 {
+        return Err(malformed("ACL attribute"));
+    }
+
+    let mut bytes = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for entry in acl.chunks(6) {
+        let tag = match entry[0] {
+            x @ 1..=6 => POSIX_ACL_TAGS[x as usize - 1],
+            _ => return Err(malformed("ACL attribute tag")),
+        };
+        let id = match entry[0] {
+            2 | 4 => [entry[2], entry[3], entry[4], entry[5]],
+            _ => u32::MAX.to_le_bytes(),
+        };
+
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&u16::from(entry[1] & 0o7).to_le_bytes());
+        bytes.extend_from_slice(&id);
+    }
+    Ok(bytes)
+}
+
+/// Reads the access and default ACLs of a file, keyed by their attribute keys. A file without
+/// an ACL beyond its mode has neither.
+#[cfg(all(feature = "writer", target_os = "linux"))]
+pub fn read_acls<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, Vec<u8>>> {
+    let mut acls = HashMap::new();
+    for (key, name) in POSIX_ACL_XATTRS.iter() {
+        if let Some(value) = crate::xattr::read_xattr(path.as_ref(), name)? {
+            acls.insert(key.to_string(), from_posix_xattr(&value)?);
+        }
+    }
+    Ok(acls)
+}
+
+#[cfg(all(feature = "writer", not(target_os = "linux")))]
+pub fn read_acls<P: AsRef<Path>>(_path: P) -> io::Result<HashMap<String, Vec<u8>>> {
+    Ok(HashMap::new())
+}
+
+/// Sets an access or default ACL on a file, given its attribute key.
+#[cfg(all(feature = "reader", target_os = "linux"))]
+pub(crate) fn write_acl(path: &Path, key: &str, acl: &[u8]) -> io::Result<()> {
+    let name = POSIX_ACL_XATTRS
+        .iter()
+        .find(|(x, _)| *x == key)
+        .map(|(_, name)| *name)
+        .ok_or_else(|| malformed("ACL attribute key"))?;
+    crate::xattr::write_xattr(path, name, &to_posix_xattr(acl)?)
+}
+
+#[cfg(all(feature = "reader", not(target_os = "linux")))]
+pub(crate) fn write_acl(_path: &Path, _key: &str, _acl: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "POSIX ACLs are not supported on this platform",
+    ))
+}
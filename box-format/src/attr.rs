@@ -0,0 +1,184 @@
+//! Typed attribute values, and the well-known attribute keys with the type each of their
+//! values is expected to have. Attributes are stored as bytes, which `AttrValue` decodes and
+//! checks against the length of its type.
+
+use std::convert::TryFrom;
+
+use crate::{
+    acl::{ACL_ATTR, DEFAULT_ACL_ATTR},
+    attr_set::{ATTR_SETS_ATTR, ATTR_SET_ATTR},
+    blocks::BLOCKS_ATTR,
+    checksum::{CRC32_ATTR, HASH_ALGORITHM_ATTR, HASH_ATTR, KEYED_HASH_ATTR},
+    chunks::{CHUNKS_ATTR, CHUNK_TABLE_ATTR},
+    encryption::{ENCRYPTION_ATTR, ENCRYPTION_NONCE_ATTR},
+    inline::INLINE_ATTR,
+    owner::{GID_ATTR, GROUP_ATTR, UID_ATTR, USER_ATTR},
+    signature::SIGNATURE_ATTR,
+    solid::SOLID_ATTR,
+    sparse::SPARSE_ATTR,
+    time::{Timestamp, ACCESSED_ATTR, CHANGED_ATTR, CREATED_ATTR, MODIFIED_ATTR},
+};
+
+/// The attribute key under which the Unix mode of a file is kept, as 4 LE bytes holding both
+/// its type and permission bits.
+pub const MODE_ATTR: &str = "unix.mode";
+
+/// The type of an attribute value, which sets how it is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrType {
+    /// 4 LE bytes.
+    U32,
+    /// 8 LE bytes.
+    U64,
+    /// 8 LE bytes.
+    I64,
+    /// As described in `Timestamp`.
+    Timestamp,
+    /// UTF-8, without a terminator.
+    String,
+    /// Anything, including structures with encodings of their own.
+    Bytes,
+}
+
+/// The well-known attribute keys and the types of their values. Keys that are not here,
+/// such as those of extended attributes, are read as bytes.
+pub const WELL_KNOWN_ATTRS: &[(&str, AttrType)] = &[
+    (ACCESSED_ATTR, AttrType::Timestamp),
+    (ACL_ATTR, AttrType::Bytes),
+    (ATTR_SETS_ATTR, AttrType::Bytes),
+    (ATTR_SET_ATTR, AttrType::Bytes),
+    (BLOCKS_ATTR, AttrType::Bytes),
+    (CHANGED_ATTR, AttrType::Timestamp),
+    (CHUNKS_ATTR, AttrType::Bytes),
+    (CHUNK_TABLE_ATTR, AttrType::Bytes),
+    (CRC32_ATTR, AttrType::U32),
+    (CREATED_ATTR, AttrType::Timestamp),
+    (DEFAULT_ACL_ATTR, AttrType::Bytes),
+    (ENCRYPTION_ATTR, AttrType::String),
+    (ENCRYPTION_NONCE_ATTR, AttrType::Bytes),
+    (GID_ATTR, AttrType::U32),
+    (GROUP_ATTR, AttrType::String),
+    (HASH_ALGORITHM_ATTR, AttrType::String),
+    (HASH_ATTR, AttrType::Bytes),
+    (INLINE_ATTR, AttrType::Bytes),
+    (KEYED_HASH_ATTR, AttrType::Bytes),
+    (MODE_ATTR, AttrType::U32),
+    (MODIFIED_ATTR, AttrType::Timestamp),
+    (SIGNATURE_ATTR, AttrType::Bytes),
+    (SOLID_ATTR, AttrType::U64),
+    (SPARSE_ATTR, AttrType::Bytes),
+    (UID_ATTR, AttrType::U32),
+    (USER_ATTR, AttrType::String),
+];
+
+impl AttrType {
+    /// The type of the values of an attribute key, which is `Bytes` for keys that are not
+    /// well known.
+    pub fn of_key(key: &str) -> AttrType {
+        WELL_KNOWN_ATTRS
+            .iter()
+            .find(|(x, _)| *x == key)
+            .map(|(_, ty)| *ty)
+            .unwrap_or(AttrType::Bytes)
+    }
+}
+
+/// A decoded attribute value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttrValue {
+    U32(u32),
+    U64(u64),
+    I64(i64),
+    Timestamp(Timestamp),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl AttrValue {
+    pub fn attr_type(&self) -> AttrType {
+        match self {
+            AttrValue::U32(_) => AttrType::U32,
+            AttrValue::U64(_) => AttrType::U64,
+            AttrValue::I64(_) => AttrType::I64,
+            AttrValue::Timestamp(_) => AttrType::Timestamp,
+            AttrValue::String(_) => AttrType::String,
+            AttrValue::Bytes(_) => AttrType::Bytes,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            AttrValue::U32(x) => x.to_le_bytes().to_vec(),
+            AttrValue::U64(x) => x.to_le_bytes().to_vec(),
+            AttrValue::I64(x) => x.to_le_bytes().to_vec(),
+            AttrValue::Timestamp(x) => x.to_bytes(),
+            AttrValue::String(x) => x.as_bytes().to_vec(),
+            AttrValue::Bytes(x) => x.clone(),
+        }
+    }
+
+    /// Decodes an attribute value of the given type, which is `None` if it has the wrong
+    /// length or is not UTF-8 where a string is expected.
+    pub fn from_bytes(ty: AttrType, bytes: &[u8]) -> Option<AttrValue> {
+        let eight = || <[u8; 8]>::try_from(bytes).ok();
+
+        Some(match ty {
+            AttrType::U32 => match *bytes {
+                [a, b, c, d] => AttrValue::U32(u32::from_le_bytes([a, b, c, d])),
+                _ => return None,
+            },
+            AttrType::U64 => AttrValue::U64(u64::from_le_bytes(eight()?)),
+            AttrType::I64 => AttrValue::I64(i64::from_le_bytes(eight()?)),
+            AttrType::Timestamp => AttrValue::Timestamp(Timestamp::from_bytes(bytes)?),
+            AttrType::String => AttrValue::String(std::str::from_utf8(bytes).ok()?.to_string()),
+            AttrType::Bytes => AttrValue::Bytes(bytes.to_vec()),
+        })
+    }
+
+    /// Decodes the value of an attribute with the type its key is known to have.
+    pub fn from_key(key: &str, bytes: &[u8]) -> Option<AttrValue> {
+        AttrValue::from_bytes(AttrType::of_key(key), bytes)
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            AttrValue::U32(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            AttrValue::U64(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            AttrValue::I64(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_timestamp(&self) -> Option<Timestamp> {
+        match self {
+            AttrValue::Timestamp(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            AttrValue::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            AttrValue::Bytes(x) => Some(x),
+            _ => None,
+        }
+    }
+}
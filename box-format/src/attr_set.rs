@@ -0,0 +1,185 @@
+//! Attribute sets, which keep the values of attributes that many records have in common once
+//! in the trailer, with each record referring to its set by index. Sets are expanded back into
+//! the attributes of each record when an archive is read.
+
+#[cfg(feature = "reader")]
+use std::io;
+
+#[cfg(feature = "writer")]
+use crate::{
+    acl::{ACL_ATTR, DEFAULT_ACL_ATTR},
+    attr::MODE_ATTR,
+    owner::{GID_ATTR, GROUP_ATTR, UID_ATTR, USER_ATTR},
+};
+use crate::{AttrMap, BoxMetadata};
+
+/// The archive attribute key under which the attribute sets of an archive are kept, as a
+/// vu64 count followed by each set as an attribute map.
+pub const ATTR_SETS_ATTR: &str = "attrs.sets";
+
+/// The attribute key under which a record names the set it takes attributes from, as a vu64
+/// index into `ATTR_SETS_ATTR`.
+pub const ATTR_SET_ATTR: &str = "attrs.set";
+
+/// The attribute keys that are kept in sets, as their values tend to be the same for most
+/// records in an archive.
+#[cfg(feature = "writer")]
+pub(crate) const SHARED_ATTR_KEYS: &[&str] = &[
+    MODE_ATTR,
+    UID_ATTR,
+    GID_ATTR,
+    USER_ATTR,
+    GROUP_ATTR,
+    ACL_ATTR,
+    DEFAULT_ACL_ATTR,
+];
+
+/// The attribute sets of the records of an archive, and the set each record refers to.
+#[cfg(feature = "writer")]
+pub(crate) struct AttrSets {
+    pub(crate) sets: Vec<AttrMap>,
+    records: Vec<Option<usize>>,
+    shared_keys: Vec<usize>,
+    set_key: usize,
+}
+
+#[cfg(feature = "writer")]
+impl AttrSets {
+    /// Gathers the shared attributes of each record into sets, which is `None` if the keys
+    /// the sets are kept under have not been interned.
+    pub(crate) fn new(meta: &BoxMetadata) -> Option<AttrSets> {
+        use std::collections::HashMap;
+
+        let set_key = meta.attr_key(ATTR_SET_ATTR)?;
+        meta.attr_key(ATTR_SETS_ATTR)?;
+        let shared_keys = SHARED_ATTR_KEYS
+            .iter()
+            .filter_map(|x| meta.attr_key(x))
+            .collect::<Vec<_>>();
+
+        // Records of unknown kinds are written back as they were read, so while there are any
+        // the sets they may refer to keep their indices.
+        let mut sets = vec![];
+        let mut index = HashMap::new();
+        if meta.inodes.iter().any(|x| x.as_unknown().is_some()) {
+            for set in meta.attr_sets.iter() {
+                let mut shared = set
+                    .iter()
+                    .map(|(key, value)| (*key, &**value))
+                    .collect::<Vec<_>>();
+                shared.sort_unstable();
+                index.insert(shared, sets.len());
+                sets.push(set.clone());
+            }
+        }
+        let records = meta
+            .inodes
+            .iter()
+            .map(|record| {
+                let mut shared = shared_keys
+                    .iter()
+                    .filter_map(|key| record.attrs().get(key).map(|value| (*key, &**value)))
+                    .collect::<Vec<_>>();
+                if shared.is_empty() {
+                    return None;
+                }
+                shared.sort_unstable();
+
+                Some(*index.entry(shared).or_insert_with_key(|shared| {
+                    sets.push(
+                        shared
+                            .iter()
+                            .map(|(key, value)| (*key, value.to_vec()))
+                            .collect(),
+                    );
+                    sets.len() - 1
+                }))
+            })
+            .collect();
+
+        Some(AttrSets {
+            sets,
+            records,
+            shared_keys,
+            set_key,
+        })
+    }
+
+    /// The attributes to write for the record at the given index of the inodes, which refer
+    /// to its set in place of the attributes kept there, or `None` if it has no set.
+    pub(crate) fn record_attrs(&self, index: usize, attrs: &AttrMap) -> Option<AttrMap> {
+        use fastvlq::WriteVu64Ext;
+
+        let set = (*self.records.get(index)?)?;
+        let mut attrs = attrs
+            .iter()
+            .filter(|(key, _)| !self.shared_keys.contains(key))
+            .map(|(key, value)| (*key, value.clone()))
+            .collect::<AttrMap>();
+
+        let mut value = vec![];
+        value.write_vu64(set as u64).ok()?;
+        attrs.insert(self.set_key, value);
+        Some(attrs)
+    }
+}
+
+/// Moves the attributes of each set back into the records that refer to it.
+#[cfg(feature = "reader")]
+pub(crate) fn expand_attr_sets(meta: &mut BoxMetadata) -> io::Result<()> {
+    let (set_key, sets) = match take_attr_sets(meta)? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    for record in meta.inodes.iter_mut() {
+        expand_record_attrs(record.attrs_mut(), set_key, &sets)?;
+    }
+
+    meta.attr_sets = sets;
+    Ok(())
+}
+
+/// Takes the attribute sets out of the archive attributes, along with the key under which
+/// records refer to them, if the archive has any.
+#[cfg(feature = "reader")]
+pub(crate) fn take_attr_sets(meta: &mut BoxMetadata) -> io::Result<Option<(usize, Vec<AttrMap>)>> {
+    use crate::de::DeserializeOwned;
+
+    let sets = match meta
+        .attr_key(ATTR_SETS_ATTR)
+        .and_then(|key| meta.attrs.remove(&key))
+    {
+        Some(v) => Vec::<AttrMap>::deserialize_owned(&mut &*v)?,
+        None => return Ok(None),
+    };
+
+    Ok(meta.attr_key(ATTR_SET_ATTR).map(|key| (key, sets)))
+}
+
+/// Moves the attributes of the set a record refers to, if any, back into its attributes.
+#[cfg(feature = "reader")]
+pub(crate) fn expand_record_attrs(
+    attrs: &mut AttrMap,
+    set_key: usize,
+    sets: &[AttrMap],
+) -> io::Result<()> {
+    use fastvlq::ReadVu64Ext;
+
+    let set = match attrs.remove(&set_key) {
+        Some(v) => (&mut &*v).read_vu64()?,
+        None => return Ok(()),
+    };
+    let set = sets.get(set as usize).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown attribute set: {}", set),
+        )
+    })?;
+
+    for (key, value) in set.iter() {
+        attrs.entry(*key).or_insert_with(|| value.clone());
+    }
+
+    Ok(())
+}
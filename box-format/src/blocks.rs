@@ -0,0 +1,100 @@
+//! Storage of file data as independently compressed blocks, so that any part of a file can be
+//! read by decompressing only the blocks that hold it.
+
+#[cfg(feature = "reader")]
+use std::io;
+#[cfg(feature = "reader")]
+use std::ops::Range;
+
+/// The attribute key under which the block table of a file stored in blocks is kept: the
+/// number of decompressed bytes in every block but the last, as 4 LE bytes, followed by the
+/// offset of the end of each compressed block from the start of the file data, as 8 LE bytes.
+pub const BLOCKS_ATTR: &str = "blocks";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct BlockTable {
+    pub(crate) block_size: u32,
+    pub(crate) ends: Vec<u64>,
+}
+
+impl BlockTable {
+    #[cfg(feature = "writer")]
+    pub(crate) fn new(block_size: u32) -> BlockTable {
+        BlockTable {
+            block_size,
+            ends: vec![],
+        }
+    }
+
+    #[cfg(feature = "writer")]
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.ends.len() * 8);
+        bytes.extend_from_slice(&self.block_size.to_le_bytes());
+        for end in self.ends.iter() {
+            bytes.extend_from_slice(&end.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Parses a block table, checking it against the length of the compressed data it indexes.
+    #[cfg(feature = "reader")]
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    pub(crate) fn from_bytes(bytes: &[u8], length: u64) -> io::Result<BlockTable> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed block table");
+
+        if bytes.len() < 4 || (bytes.len() - 4) % 8 != 0 {
+            return Err(malformed());
+        }
+
+        let block_size = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if block_size == 0 {
+            return Err(malformed());
+        }
+
+        let ends = bytes[4..]
+            .chunks(8)
+            .map(|x| u64::from_le_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]))
+            .collect::<Vec<_>>();
+
+        let mut start = 0;
+        for end in ends.iter() {
+            if *end < start || *end > length {
+                return Err(malformed());
+            }
+            start = *end;
+        }
+
+        Ok(BlockTable { block_size, ends })
+    }
+
+    #[cfg(feature = "reader")]
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// The range of the compressed bytes of a block, relative to the start of the file data.
+    #[cfg(feature = "reader")]
+    #[inline(always)]
+    pub(crate) fn compressed_range(&self, index: usize) -> Range<usize> {
+        let start = match index {
+            0 => 0,
+            i => self.ends[i - 1],
+        };
+        start as usize..self.ends[index] as usize
+    }
+
+    /// The offset of the first decompressed byte of a block within the file.
+    #[cfg(feature = "reader")]
+    #[inline(always)]
+    pub(crate) fn decompressed_offset(&self, index: usize) -> u64 {
+        index as u64 * self.block_size as u64
+    }
+
+    /// The index of the block holding the decompressed byte at `offset`.
+    #[cfg(feature = "reader")]
+    #[inline(always)]
+    pub(crate) fn block_index(&self, offset: u64) -> usize {
+        (offset / self.block_size as u64) as usize
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+use std::io;
+#[cfg(feature = "writer")]
+use std::io::Read;
+#[cfg(feature = "reader")]
+use std::io::Write;
+
+#[cfg(feature = "reader")]
+use crate::{BoxMetadata, FileRecord};
+
+/// The attribute key under which the CRC32 of the uncompressed data of a file is stored,
+/// as 4 little-endian bytes.
+pub const CRC32_ATTR: &str = "crc32";
+
+/// The attribute key under which the strong digest of the uncompressed data of a file is stored.
+pub const HASH_ATTR: &str = "hash";
+
+/// The attribute key under which the digest of an encrypted file is stored instead of
+/// `HASH_ATTR`. It is keyed with a key derived from the content key: BLAKE3 in keyed mode,
+/// or HMAC-SHA-256.
+pub const KEYED_HASH_ATTR: &str = "hash.keyed";
+
+/// The archive attribute key naming the `HashAlgorithm` used for `HASH_ATTR` digests.
+pub const HASH_ALGORITHM_ATTR: &str = "hash.algorithm";
+
+/// A cryptographic hash algorithm used for the digests of file records.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn available_variants() -> &'static [&'static str] {
+        &["blake3", "sha256"]
+    }
+
+    /// The name of the algorithm as stored in the `HASH_ALGORITHM_ATTR` attribute.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn hasher(self) -> io::Result<Hasher> {
+        match self {
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Ok(Hasher::Blake3(Box::new(blake3::Hasher::new()))),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => Ok(Hasher::Sha256(sha2::Sha256::default())),
+            #[allow(unreachable_patterns)]
+            missing => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Compiled without support for {}", missing),
+            )),
+        }
+    }
+
+    /// A hasher for the digests of encrypted data, made with `key`.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(
+        not(any(feature = "blake3", feature = "sha256")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn keyed_hasher(self, key: &[u8; 32]) -> io::Result<Hasher> {
+        match self {
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => Ok(Hasher::Blake3(Box::new(blake3::Hasher::new_keyed(key)))),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => {
+                use hmac::NewMac;
+
+                let mac = hmac::Hmac::new_from_slice(key).expect("HMAC takes keys of any length");
+                Ok(Hasher::HmacSha256(Box::new(mac)))
+            }
+            #[allow(unreachable_patterns)]
+            missing => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Compiled without support for {}", missing),
+            )),
+        }
+    }
+}
+
+/// BLAKE3 if support for it was compiled in, and otherwise SHA-256.
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        if cfg!(feature = "blake3") {
+            HashAlgorithm::Blake3
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            HashAlgorithm::Blake3 => "BLAKE3",
+            HashAlgorithm::Sha256 => "SHA-256",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+pub(crate) enum Hasher {
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+    #[cfg(feature = "sha256")]
+    Sha256(sha2::Sha256),
+    #[cfg(all(feature = "sha256", feature = "encryption"))]
+    HmacSha256(Box<hmac::Hmac<sha2::Sha256>>),
+}
+
+impl Hasher {
+    #[cfg_attr(
+        not(any(feature = "blake3", feature = "sha256")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn update(&mut self, buf: &[u8]) {
+        match *self {
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(ref mut hasher) => {
+                hasher.update(buf);
+            }
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(ref mut hasher) => sha2::Digest::update(hasher, buf),
+            #[cfg(all(feature = "sha256", feature = "encryption"))]
+            Hasher::HmacSha256(ref mut hasher) => hmac::Mac::update(&mut **hasher, buf),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            #[cfg(feature = "sha256")]
+            Hasher::Sha256(hasher) => sha2::Digest::finalize(hasher).to_vec(),
+            #[cfg(all(feature = "sha256", feature = "encryption"))]
+            Hasher::HmacSha256(hasher) => hmac::Mac::finalize(*hasher).into_bytes().to_vec(),
+        }
+    }
+}
+
+#[cfg(feature = "writer")]
+/// Hashes everything read through it, so data can be checksummed as it is compressed.
+pub(crate) struct ChecksumReader<R> {
+    inner: R,
+    crc32: crc32fast::Hasher,
+    hasher: Option<Hasher>,
+}
+
+#[cfg(feature = "writer")]
+impl<R: Read> ChecksumReader<R> {
+    pub(crate) fn new(inner: R, hasher: Option<Hasher>) -> ChecksumReader<R> {
+        ChecksumReader {
+            inner,
+            crc32: crc32fast::Hasher::new(),
+            hasher,
+        }
+    }
+
+    /// Returns the CRC32 and, if a hasher was provided, the digest of the data read.
+    pub(crate) fn finalize(self) -> (u32, Option<Vec<u8>>) {
+        (self.crc32.finalize(), self.hasher.map(Hasher::finalize))
+    }
+}
+
+#[cfg(feature = "writer")]
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.crc32.update(&buf[..len]);
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "reader")]
+/// Hashes and counts everything written through it, so decompressed data can be verified.
+pub(crate) struct ChecksumWriter<W> {
+    inner: W,
+    crc32: crc32fast::Hasher,
+    hasher: Option<Hasher>,
+    len: u64,
+}
+
+#[cfg(feature = "reader")]
+impl<W: Write> ChecksumWriter<W> {
+    pub(crate) fn new(inner: W, hasher: Option<Hasher>) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner,
+            crc32: crc32fast::Hasher::new(),
+            hasher,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Checksums {
+        Checksums {
+            crc32: self.crc32.finalize(),
+            digest: self.hasher.map(Hasher::finalize),
+            len: self.len,
+        }
+    }
+}
+
+#[cfg(feature = "reader")]
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc32.update(&buf[..len]);
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..len]);
+        }
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "reader")]
+/// The checksums computed over decompressed data.
+pub(crate) struct Checksums {
+    pub(crate) crc32: u32,
+    pub(crate) digest: Option<Vec<u8>>,
+    pub(crate) len: u64,
+}
+
+#[cfg(feature = "reader")]
+/// Checks the length, CRC32 and, if computed, the digest of decompressed data against what was
+/// recorded for the file, which is keyed if the file is encrypted. A record without a CRC32
+/// attribute, or whose CRC32 is not to be trusted, is not checked against one.
+pub(crate) fn verify(
+    meta: &BoxMetadata,
+    record: &FileRecord,
+    checksums: &Checksums,
+    check_crc32: bool,
+) -> io::Result<()> {
+    if checksums.len != record.decompressed_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "length mismatch for `{}`: expected {} bytes, got {} bytes",
+                record.name, record.decompressed_length, checksums.len
+            ),
+        ));
+    }
+
+    match record.attr(meta, CRC32_ATTR).filter(|_| check_crc32) {
+        Some(x) if x.len() == 4 => {
+            let expected = u32::from_le_bytes([x[0], x[1], x[2], x[3]]);
+            if expected != checksums.crc32 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "CRC32 mismatch for `{}`: expected {:08x}, got {:08x}",
+                        record.name, expected, checksums.crc32
+                    ),
+                ));
+            }
+        }
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed CRC32 attribute for `{}`", record.name),
+            ))
+        }
+        None => {}
+    }
+
+    if let Some(digest) = checksums.digest.as_ref() {
+        let matches = match record.attr(meta, KEYED_HASH_ATTR) {
+            Some(expected) => Some(keyed_digest_eq(expected, digest)),
+            None => record.attr(meta, HASH_ATTR).map(|x| x == &**digest),
+        };
+        match matches {
+            Some(true) => {}
+            Some(false) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("digest mismatch for `{}`", record.name),
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no digest recorded for `{}`", record.name),
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Compares keyed digests in constant time, so that how long it takes does not tell how much
+/// of a forged digest was right.
+#[cfg(all(feature = "reader", feature = "encryption"))]
+fn keyed_digest_eq(expected: &[u8], digest: &[u8]) -> bool {
+    use subtle::ConstantTimeEq;
+
+    expected.ct_eq(digest).into()
+}
+
+#[cfg(all(feature = "reader", not(feature = "encryption")))]
+fn keyed_digest_eq(expected: &[u8], digest: &[u8]) -> bool {
+    expected == digest
+}
@@ -0,0 +1,146 @@
+//! Content-defined chunking, which splits file data where its content says to rather than at
+//! fixed offsets, so that chunks shared between files or versions of a file are stored once
+//! even when what comes before them has changed.
+
+#[cfg(feature = "reader")]
+use std::io;
+#[cfg(feature = "writer")]
+use std::io::prelude::*;
+
+use crate::compression::Compression;
+#[cfg(feature = "reader")]
+use crate::{de::DeserializeOwned, file::BoxMetadata};
+
+/// The attribute key under which a file stored as chunks lists them: the index of each chunk
+/// in the chunk table, in order, as 8 LE bytes.
+pub const CHUNKS_ATTR: &str = "chunks";
+
+/// The archive attribute key under which the chunk table is kept: the number of chunks as a
+/// vu64, then for each its data offset, length and decompressed length as 8 LE bytes, its
+/// compression, and its encryption nonce and digest as vu64-prefixed bytes.
+pub const CHUNK_TABLE_ATTR: &str = "chunks.table";
+
+/// A stored chunk, which is compressed and encrypted on its own.
+#[derive(Debug, Clone)]
+pub(crate) struct Chunk {
+    pub(crate) data: u64,
+    pub(crate) length: u64,
+    pub(crate) decompressed_length: u64,
+    pub(crate) compression: Compression,
+    /// The nonce prefix of an encrypted chunk, or empty.
+    pub(crate) nonce: Vec<u8>,
+    /// The digest of the decompressed chunk, made with the hash algorithm of the archive. It is
+    /// keyed with the content key if the chunk is encrypted, as the table may not be sealed.
+    pub(crate) digest: Vec<u8>,
+}
+
+/// Reads the chunk table of an archive, which is empty if no file is stored as chunks.
+#[cfg(feature = "reader")]
+pub(crate) fn read_chunk_table(meta: &BoxMetadata) -> io::Result<Vec<Chunk>> {
+    match meta.file_attr(CHUNK_TABLE_ATTR) {
+        Some(bytes) => Vec::<Chunk>::deserialize_owned(&mut &bytes[..]),
+        None => Ok(vec![]),
+    }
+}
+
+/// Parses the chunk list of a file into indices in the chunk table.
+#[cfg(feature = "reader")]
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+pub(crate) fn read_chunk_list(bytes: &[u8]) -> io::Result<Vec<u64>> {
+    if bytes.len() % 8 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed chunk list",
+        ));
+    }
+
+    Ok(bytes
+        .chunks(8)
+        .map(|x| u64::from_le_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]))
+        .collect())
+}
+
+#[cfg(feature = "writer")]
+pub(crate) fn chunk_list_bytes(indices: &[u64]) -> Vec<u8> {
+    indices
+        .iter()
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Splits data into chunks where a rolling hash of the bytes just read has its top bits
+/// clear, so that the same content is split the same way wherever it appears. Chunks are
+/// between a quarter of and four times the average size asked for.
+#[cfg(feature = "writer")]
+pub(crate) struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    gear: [u64; 256],
+    mask: u64,
+    min: usize,
+    max: usize,
+}
+
+#[cfg(feature = "writer")]
+impl<R: Read> Chunker<R> {
+    pub(crate) fn new(reader: R, average: u32) -> Chunker<R> {
+        let average = average.max(64);
+        let bits = 31 - average.leading_zeros();
+
+        Chunker {
+            reader,
+            buf: vec![],
+            gear: gear_table(),
+            mask: !0u64 << (64 - bits),
+            min: average as usize / 4,
+            max: average as usize * 4,
+        }
+    }
+
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buf.len() < self.max {
+            let wanted = (self.max - self.buf.len()) as u64;
+            (&mut self.reader).take(wanted).read_to_end(&mut self.buf)?;
+        }
+
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let rest = self.buf.split_off(self.boundary());
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+
+    fn boundary(&self) -> usize {
+        let end = self.buf.len().min(self.max);
+        if end <= self.min {
+            return end;
+        }
+
+        let mut hash = 0u64;
+        for (i, byte) in self.buf[..end].iter().enumerate().skip(self.min) {
+            hash = (hash << 1).wrapping_add(self.gear[*byte as usize]);
+            if hash & self.mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Random values for the rolling hash, from a fixed seed. Changing them only moves where
+/// files are split, so chunks written before would no longer be matched.
+#[cfg(feature = "writer")]
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    for value in table.iter_mut() {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *value = z ^ (z >> 31);
+    }
+    table
+}
@@ -6,11 +6,17 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use fastvlq::ReadVu64Ext;
 
 use crate::{
-    AttrMap, BoxHeader, BoxMetadata, BoxPath, Compression, DirectoryRecord, FileRecord, LinkRecord,
-    Record,
+    AttrMap, BoxHeader, BoxMetadata, BoxPath, Compression, DirectoryRecord, FileRecord,
+    HardLinkRecord, LinkRecord, Record, SpecialFileType, SpecialRecord, UnknownRecord,
 };
 
+use crate::attr_set::expand_attr_sets;
+use crate::chunks::Chunk;
 use crate::compression::constants::*;
+use crate::record::{
+    RECORD_DIRECTORY, RECORD_FILE, RECORD_FRAMED, RECORD_HARD_LINK, RECORD_KIND_MASK, RECORD_LINK,
+    RECORD_OPTIONAL, RECORD_SPECIAL,
+};
 
 pub(crate) trait DeserializeOwned {
     fn deserialize_owned<R: Read>(reader: &mut R) -> std::io::Result<Self>
@@ -120,23 +126,108 @@ impl DeserializeOwned for LinkRecord {
     }
 }
 
-impl DeserializeOwned for Record {
+impl DeserializeOwned for HardLinkRecord {
     fn deserialize_owned<R: Read>(reader: &mut R) -> std::io::Result<Self> {
-        let ty = reader.read_u8()?;
-        match ty {
-            0 => Ok(Record::File(FileRecord::deserialize_owned(reader)?)),
-            1 => Ok(Record::Directory(DirectoryRecord::deserialize_owned(
-                reader,
-            )?)),
-            2 => Ok(Record::Link(LinkRecord::deserialize_owned(reader)?)),
-            _ => Err(std::io::Error::new(
+        let name = String::deserialize_owned(reader)?;
+        let target = Inode::deserialize_owned(reader)?;
+        let attrs = HashMap::deserialize_owned(reader)?;
+
+        Ok(HardLinkRecord {
+            name,
+            target,
+            attrs,
+        })
+    }
+}
+
+impl DeserializeOwned for SpecialFileType {
+    fn deserialize_owned<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        match reader.read_u8()? {
+            0 => Ok(SpecialFileType::Fifo),
+            1 => Ok(SpecialFileType::CharDevice),
+            2 => Ok(SpecialFileType::BlockDevice),
+            3 => Ok(SpecialFileType::Socket),
+            x => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("invalid or unsupported field type: {}", ty),
+                format!("invalid or unsupported special file type: {}", x),
             )),
         }
     }
 }
 
+impl DeserializeOwned for SpecialRecord {
+    fn deserialize_owned<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let name = String::deserialize_owned(reader)?;
+        let file_type = SpecialFileType::deserialize_owned(reader)?;
+        let major = reader.read_u32::<LittleEndian>()?;
+        let minor = reader.read_u32::<LittleEndian>()?;
+        let attrs = HashMap::deserialize_owned(reader)?;
+
+        Ok(SpecialRecord {
+            name,
+            file_type,
+            major,
+            minor,
+            attrs,
+        })
+    }
+}
+
+impl DeserializeOwned for Record {
+    fn deserialize_owned<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let ty = reader.read_u8()?;
+        if ty & RECORD_FRAMED == 0 {
+            return Record::deserialize_kind(ty, reader)?.ok_or_else(|| unsupported_record(ty));
+        }
+
+        let len = reader.read_vu64()?;
+        let mut body = reader.take(len);
+        let kind = ty & RECORD_KIND_MASK;
+        let record = match Record::deserialize_kind(kind, &mut body)? {
+            Some(record) => record,
+            None if ty & RECORD_OPTIONAL != 0 => {
+                let mut data = Vec::with_capacity(len as usize);
+                body.read_to_end(&mut data)?;
+                Record::Unknown(UnknownRecord {
+                    kind,
+                    data,
+                    attrs: AttrMap::new(),
+                })
+            }
+            None => return Err(unsupported_record(ty)),
+        };
+
+        // Fields added to a kind of record after this version are skipped over
+        std::io::copy(&mut body, &mut std::io::sink())?;
+        if body.limit() != 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(record)
+    }
+}
+
+impl Record {
+    /// Reads a record of the given kind, which is `None` if the kind is not known.
+    fn deserialize_kind<R: Read>(kind: u8, reader: &mut R) -> std::io::Result<Option<Self>> {
+        Ok(Some(match kind {
+            RECORD_FILE => Record::File(FileRecord::deserialize_owned(reader)?),
+            RECORD_DIRECTORY => Record::Directory(DirectoryRecord::deserialize_owned(reader)?),
+            RECORD_LINK => Record::Link(LinkRecord::deserialize_owned(reader)?),
+            RECORD_HARD_LINK => Record::HardLink(HardLinkRecord::deserialize_owned(reader)?),
+            RECORD_SPECIAL => Record::Special(SpecialRecord::deserialize_owned(reader)?),
+            _ => return Ok(None),
+        }))
+    }
+}
+
+fn unsupported_record(ty: u8) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid or unsupported field type: {}", ty),
+    )
+}
+
 impl DeserializeOwned for BoxHeader {
     fn deserialize_owned<R: Read>(reader: &mut R) -> std::io::Result<Self> {
         let magic_bytes = reader.read_u32::<LittleEndian>()?.to_le_bytes();
@@ -149,15 +240,41 @@ impl DeserializeOwned for BoxHeader {
         }
 
         let version = reader.read_u32::<LittleEndian>()?;
+        if version > crate::header::VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported archive version: {}", version),
+            ));
+        }
+
         let alignment = reader.read_u64::<LittleEndian>()?;
         let trailer = reader.read_u64::<LittleEndian>()?;
+        // Version 0 headers end here
+        let (trailer_checksum, flags) = match version {
+            0 => (None, 0),
+            _ => (
+                Some(reader.read_u32::<LittleEndian>()?),
+                reader.read_u32::<LittleEndian>()?,
+            ),
+        };
 
-        Ok(BoxHeader {
+        let header = BoxHeader {
             magic_bytes,
             version,
             alignment,
             trailer: NonZeroU64::new(trailer),
-        })
+            trailer_checksum,
+            flags,
+        };
+
+        if header.unknown_flags() != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported header flags: {:#x}", header.unknown_flags()),
+            ));
+        }
+
+        Ok(header)
     }
 }
 
@@ -168,12 +285,34 @@ impl DeserializeOwned for BoxMetadata {
         let attr_keys = Vec::deserialize_owned(reader)?;
         let attrs = HashMap::deserialize_owned(reader)?;
 
-        Ok(BoxMetadata {
+        let mut meta = BoxMetadata {
             root,
             inodes,
-            attr_keys,
             attrs,
-            index: None,
+            ..Default::default()
+        };
+        meta.set_attr_keys(attr_keys);
+        expand_attr_sets(&mut meta)?;
+        Ok(meta)
+    }
+}
+
+impl DeserializeOwned for Chunk {
+    fn deserialize_owned<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let data = reader.read_u64::<LittleEndian>()?;
+        let length = reader.read_u64::<LittleEndian>()?;
+        let decompressed_length = reader.read_u64::<LittleEndian>()?;
+        let compression = Compression::deserialize_owned(reader)?;
+        let nonce = Vec::<u8>::deserialize_owned(reader)?;
+        let digest = Vec::<u8>::deserialize_owned(reader)?;
+
+        Ok(Chunk {
+            data,
+            length,
+            decompressed_length,
+            compression,
+            nonce,
+            digest,
         })
     }
 }
@@ -0,0 +1,804 @@
+//! Encryption of file data with ChaCha20-Poly1305.
+//!
+//! Every encrypted archive has a random content key. The key itself is never stored; it is
+//! wrapped with a key derived from a password using Argon2id, and the wrapped key, salt and KDF
+//! parameters are kept in the archive attributes. It can also be wrapped for any number of
+//! X25519 recipients, each with a key agreed between an ephemeral key and the recipient.
+//!
+//! File data is compressed first, then split into segments of `SEGMENT_SIZE` bytes which are
+//! sealed individually. The nonce of each segment is built from a random per-file prefix, stored
+//! in the `ENCRYPTION_NONCE_ATTR` attribute of the record, the segment counter and a flag marking
+//! the final segment, so that reordering or truncating segments fails authentication. Each
+//! segment also authenticates the offset of its stream in the archive and its own index, so
+//! that a stream cannot be passed off as another one stored elsewhere.
+//!
+//! Encrypted files have no CRC32, and their digest is a MAC keyed with a key derived from the
+//! content key, so that neither can be checked against guesses of the data without the key.
+//! Their sizes can still be read unless the metadata is sealed too.
+//!
+//! The trailer and index can be sealed the same way, compressed as one blob, leaving only the
+//! attributes needed to recover the content key readable. Those are authenticated along with
+//! the blob, so that KDF parameters or recipients cannot be swapped without it failing.
+
+#[cfg(any(feature = "reader", feature = "encryption"))]
+use std::io;
+#[cfg(feature = "reader")]
+use std::io::Read;
+#[cfg(feature = "encryption")]
+use std::io::{prelude::*, SeekFrom};
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+#[cfg(feature = "encryption")]
+use rand::{rngs::OsRng, RngCore};
+#[cfg(feature = "encryption")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "encryption")]
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+
+#[cfg(all(feature = "encryption", feature = "writer"))]
+use crate::ser::Serialize;
+#[cfg(any(feature = "reader", feature = "encryption"))]
+use crate::BoxMetadata;
+#[cfg(all(feature = "encryption", feature = "reader"))]
+use crate::{
+    compression::Compression,
+    file::reader::{LimitWriter, MAX_TRAILER_SIZE},
+};
+#[cfg(feature = "reader")]
+use crate::{de::DeserializeOwned, AttrMap};
+
+/// The archive attribute key naming the cipher used for encrypted file data.
+pub const ENCRYPTION_ATTR: &str = "encryption";
+
+/// The attribute key under which the nonce prefix of an encrypted file is stored.
+pub const ENCRYPTION_NONCE_ATTR: &str = "encryption.nonce";
+
+#[cfg(feature = "encryption")]
+pub(crate) const ENCRYPTION_KDF_ATTR: &str = "encryption.kdf";
+#[cfg(feature = "encryption")]
+pub(crate) const ENCRYPTION_KDF_SALT_ATTR: &str = "encryption.kdf.salt";
+#[cfg(feature = "encryption")]
+pub(crate) const ENCRYPTION_KDF_PARAMS_ATTR: &str = "encryption.kdf.params";
+#[cfg(feature = "encryption")]
+pub(crate) const ENCRYPTION_PASSWORD_ATTR: &str = "encryption.password";
+#[cfg(feature = "encryption")]
+pub(crate) const ENCRYPTION_RECIPIENTS_ATTR: &str = "encryption.recipients";
+
+#[cfg(feature = "encryption")]
+const CIPHER_NAME: &str = "chacha20poly1305";
+#[cfg(feature = "encryption")]
+const KDF_NAME: &str = "argon2id";
+
+#[cfg(feature = "encryption")]
+const KEY_LEN: usize = 32;
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+#[cfg(feature = "encryption")]
+const TAG_LEN: usize = 16;
+#[cfg(feature = "encryption")]
+const SALT_LEN: usize = 16;
+#[cfg(feature = "encryption")]
+pub(crate) const NONCE_PREFIX_LEN: usize = 7;
+#[cfg(feature = "encryption")]
+const WRAPPED_KEY_LEN: usize = NONCE_LEN + KEY_LEN + TAG_LEN;
+/// An ephemeral public key followed by the content key wrapped for one recipient.
+#[cfg(feature = "encryption")]
+const RECIPIENT_STANZA_LEN: usize = KEY_LEN + WRAPPED_KEY_LEN;
+
+/// The compression of sealed trailers, which are mostly names and attributes.
+#[cfg(all(feature = "encryption", feature = "writer", feature = "zstd"))]
+const METADATA_COMPRESSION: Compression = Compression::Zstd;
+#[cfg(all(
+    feature = "encryption",
+    feature = "writer",
+    not(feature = "zstd"),
+    feature = "deflate"
+))]
+const METADATA_COMPRESSION: Compression = Compression::Deflate;
+#[cfg(all(
+    feature = "encryption",
+    feature = "writer",
+    not(any(feature = "zstd", feature = "deflate"))
+))]
+const METADATA_COMPRESSION: Compression = Compression::Stored;
+
+/// The number of plaintext bytes in every segment but the last.
+#[cfg(feature = "encryption")]
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// The largest Argon2 memory cost, in KiB, that will be honoured when opening an archive.
+#[cfg(feature = "encryption")]
+const MAX_KDF_MEM_COST: u32 = 1024 * 1024;
+/// The largest number of Argon2 passes that will be honoured when opening an archive.
+#[cfg(feature = "encryption")]
+const MAX_KDF_TIME_COST: u32 = 64;
+/// The largest Argon2 parallelism that will be honoured when opening an archive.
+#[cfg(feature = "encryption")]
+const MAX_KDF_LANES: u32 = 64;
+
+#[cfg(feature = "encryption")]
+pub(crate) type NoncePrefix = [u8; NONCE_PREFIX_LEN];
+
+#[cfg(feature = "encryption")]
+#[allow(unknown_lints, clippy::io_other_error)]
+fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    OsRng
+        .try_fill_bytes(buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+#[cfg(feature = "encryption")]
+pub(crate) fn generate_nonce_prefix() -> io::Result<NoncePrefix> {
+    let mut prefix = [0u8; NONCE_PREFIX_LEN];
+    random_bytes(&mut prefix)?;
+    Ok(prefix)
+}
+
+#[cfg(feature = "encryption")]
+fn segment_nonce(prefix: &NoncePrefix, counter: u32, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+/// The associated data of a segment: the offset of its stream in the archive, or whatever else
+/// the stream is bound to, followed by the segment counter.
+#[cfg(feature = "encryption")]
+fn segment_aad(context: &[u8], counter: u32) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(context.len() + 4);
+    aad.extend_from_slice(context);
+    aad.extend_from_slice(&counter.to_le_bytes());
+    aad
+}
+
+#[cfg(feature = "encryption")]
+fn authentication_failed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "authentication failed: wrong key or corrupted data",
+    )
+}
+
+/// The key that file data of an archive is encrypted with.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct ContentKey([u8; KEY_LEN]);
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for ContentKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ContentKey(..)")
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl ContentKey {
+    pub(crate) fn generate() -> io::Result<ContentKey> {
+        let mut key = [0u8; KEY_LEN];
+        random_bytes(&mut key)?;
+        Ok(ContentKey(key))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&Key::from(self.0))
+    }
+
+    /// The key that digests of encrypted data are made with.
+    pub(crate) fn hash_key(&self) -> [u8; KEY_LEN] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"box-hash");
+        hasher.update(self.0);
+
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&hasher.finalize());
+        key
+    }
+
+    /// Seals the key with `wrapping_key`, returning the random nonce followed by the ciphertext.
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn wrap(&self, wrapping_key: &[u8; KEY_LEN]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        random_bytes(&mut nonce)?;
+
+        let mut buf = self.0.to_vec();
+        ChaCha20Poly1305::new(&Key::from(*wrapping_key))
+            .encrypt_in_place(&Nonce::from(nonce), b"", &mut buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "cannot wrap content key"))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.append(&mut buf);
+        Ok(wrapped)
+    }
+
+    /// Opens a key sealed by `wrap`, returning `None` if `wrapping_key` does not match.
+    fn unwrap(wrapping_key: &[u8; KEY_LEN], wrapped: &[u8]) -> io::Result<Option<ContentKey>> {
+        if wrapped.len() != WRAPPED_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed wrapped content key",
+            ));
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&wrapped[..NONCE_LEN]);
+        let mut buf = wrapped[NONCE_LEN..].to_vec();
+        let result = ChaCha20Poly1305::new(&Key::from(*wrapping_key)).decrypt_in_place(
+            &Nonce::from(nonce),
+            b"",
+            &mut buf,
+        );
+
+        if result.is_err() {
+            return Ok(None);
+        }
+
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&buf);
+        Ok(Some(ContentKey(key)))
+    }
+}
+
+/// The Argon2id parameters used to derive a key from a password.
+#[cfg(feature = "encryption")]
+#[derive(Debug, Clone, Copy)]
+struct KdfParams {
+    /// Memory cost in KiB
+    mem_cost: u32,
+    time_cost: u32,
+    lanes: u32,
+}
+
+#[cfg(feature = "encryption")]
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            mem_cost: 19 * 1024,
+            time_cost: 2,
+            lanes: 1,
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl KdfParams {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12);
+        bytes.extend_from_slice(&self.mem_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.time_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.lanes.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<KdfParams> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed KDF parameters");
+
+        if bytes.len() != 12 {
+            return Err(invalid());
+        }
+
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let params = KdfParams {
+            mem_cost: u32_at(0),
+            time_cost: u32_at(4),
+            lanes: u32_at(8),
+        };
+
+        if params.mem_cost > MAX_KDF_MEM_COST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("KDF memory cost too large: {} KiB", params.mem_cost),
+            ));
+        }
+        if params.time_cost > MAX_KDF_TIME_COST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("KDF time cost too large: {} passes", params.time_cost),
+            ));
+        }
+        if params.lanes > MAX_KDF_LANES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("KDF parallelism too large: {} lanes", params.lanes),
+            ));
+        }
+
+        Ok(params)
+    }
+
+    fn derive_key(self, password: &str, salt: &[u8]) -> io::Result<[u8; KEY_LEN]> {
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            hash_length: KEY_LEN as u32,
+            ..Default::default()
+        };
+
+        let hash = argon2::hash_raw(password.as_bytes(), salt, &config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&hash);
+        Ok(key)
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn check_cipher(meta: &BoxMetadata) -> io::Result<()> {
+    match meta.file_attr(ENCRYPTION_ATTR) {
+        Some(name) if &**name == CIPHER_NAME.as_bytes() => Ok(()),
+        Some(name) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported cipher: {}", String::from_utf8_lossy(name)),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "archive is not encrypted",
+        )),
+    }
+}
+
+/// Recovers the content key of an archive from its password.
+#[cfg(feature = "encryption")]
+pub(crate) fn unlock_with_password(meta: &BoxMetadata, password: &str) -> io::Result<ContentKey> {
+    check_cipher(meta)?;
+
+    let attr = |key: &str| {
+        meta.file_attr(key).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive cannot be opened with a password",
+            )
+        })
+    };
+
+    if &**attr(ENCRYPTION_KDF_ATTR)? != KDF_NAME.as_bytes() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported KDF: {}",
+                String::from_utf8_lossy(attr(ENCRYPTION_KDF_ATTR)?)
+            ),
+        ));
+    }
+
+    let params = KdfParams::from_bytes(attr(ENCRYPTION_KDF_PARAMS_ATTR)?)?;
+    let wrapping_key = params.derive_key(password, attr(ENCRYPTION_KDF_SALT_ATTR)?)?;
+
+    ContentKey::unwrap(&wrapping_key, attr(ENCRYPTION_PASSWORD_ATTR)?)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "incorrect password"))
+}
+
+/// Stores `key` in the archive attributes, wrapped with a key derived from `password`.
+#[cfg(feature = "encryption")]
+pub(crate) fn lock_with_password(
+    meta: &mut BoxMetadata,
+    key: &ContentKey,
+    password: &str,
+) -> io::Result<()> {
+    let mut salt = [0u8; SALT_LEN];
+    random_bytes(&mut salt)?;
+    let params = KdfParams::default();
+    let wrapped = key.wrap(&params.derive_key(password, &salt)?)?;
+
+    set_file_attr(meta, ENCRYPTION_ATTR, CIPHER_NAME.as_bytes().to_vec());
+    set_file_attr(meta, ENCRYPTION_KDF_ATTR, KDF_NAME.as_bytes().to_vec());
+    set_file_attr(meta, ENCRYPTION_KDF_SALT_ATTR, salt.to_vec());
+    set_file_attr(meta, ENCRYPTION_KDF_PARAMS_ATTR, params.to_bytes());
+    set_file_attr(meta, ENCRYPTION_PASSWORD_ATTR, wrapped);
+
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+fn set_file_attr(meta: &mut BoxMetadata, key: &str, value: Vec<u8>) {
+    let key = meta.attr_key_or_create(key);
+    meta.attrs.insert(key, value);
+}
+
+/// An X25519 public key that archives can be encrypted to.
+#[cfg(feature = "encryption")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+#[cfg(feature = "encryption")]
+impl Recipient {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Recipient {
+        Recipient(PublicKey::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        *self.0.as_bytes()
+    }
+}
+
+/// An X25519 secret key that can open archives encrypted to its `Recipient`.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+#[cfg(feature = "encryption")]
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Identity").field(&self.recipient()).finish()
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity(StaticSecret::new(OsRng))
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Identity {
+        Identity(StaticSecret::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.0.to_bytes()
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn recipient_wrapping_key(
+    shared: &SharedSecret,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> io::Result<[u8; KEY_LEN]> {
+    // A low-order public key leads to a predictable shared secret.
+    if shared.as_bytes().iter().all(|x| *x == 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid X25519 public key",
+        ));
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"box-x25519");
+    hasher.update(shared.as_bytes());
+    hasher.update(ephemeral.as_bytes());
+    hasher.update(recipient.as_bytes());
+
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&hasher.finalize());
+    Ok(key)
+}
+
+/// Adds `key` to the archive attributes, wrapped so that only `recipient` can recover it.
+#[cfg(feature = "encryption")]
+pub(crate) fn add_recipient(
+    meta: &mut BoxMetadata,
+    key: &ContentKey,
+    recipient: &Recipient,
+) -> io::Result<()> {
+    let ephemeral = EphemeralSecret::new(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient.0);
+    let wrapping_key = recipient_wrapping_key(&shared, &ephemeral_public, &recipient.0)?;
+
+    let mut stanza = ephemeral_public.as_bytes().to_vec();
+    stanza.append(&mut key.wrap(&wrapping_key)?);
+
+    set_file_attr(meta, ENCRYPTION_ATTR, CIPHER_NAME.as_bytes().to_vec());
+    let attr_key = meta.attr_key_or_create(ENCRYPTION_RECIPIENTS_ATTR);
+    meta.attrs.entry(attr_key).or_default().append(&mut stanza);
+
+    Ok(())
+}
+
+/// Recovers the content key of an archive from the identity of one of its recipients.
+#[cfg(feature = "encryption")]
+pub(crate) fn unlock_with_identity(
+    meta: &BoxMetadata,
+    identity: &Identity,
+) -> io::Result<ContentKey> {
+    check_cipher(meta)?;
+
+    let stanzas = meta.file_attr(ENCRYPTION_RECIPIENTS_ATTR).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "archive cannot be opened with an identity",
+        )
+    })?;
+
+    if stanzas.len() % RECIPIENT_STANZA_LEN != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed encryption recipients",
+        ));
+    }
+
+    let recipient = identity.recipient();
+    for stanza in stanzas.chunks(RECIPIENT_STANZA_LEN) {
+        let mut ephemeral = [0u8; KEY_LEN];
+        ephemeral.copy_from_slice(&stanza[..KEY_LEN]);
+        let ephemeral = PublicKey::from(ephemeral);
+
+        let shared = identity.0.diffie_hellman(&ephemeral);
+        let wrapping_key = match recipient_wrapping_key(&shared, &ephemeral, &recipient.0) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        if let Some(key) = ContentKey::unwrap(&wrapping_key, &stanza[KEY_LEN..])? {
+            return Ok(key);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "identity is not a recipient of this archive",
+    ))
+}
+
+/// Reads the attributes at the start of a sealed trailer, which are all that is known of the
+/// archive until it is unlocked.
+#[cfg(feature = "reader")]
+pub(crate) fn read_metadata_envelope<R: Read>(reader: &mut R) -> io::Result<BoxMetadata> {
+    let attr_keys = Vec::<String>::deserialize_owned(reader)?;
+    let attrs = AttrMap::deserialize_owned(reader)?;
+
+    let mut meta = BoxMetadata {
+        attrs,
+        ..Default::default()
+    };
+    meta.set_attr_keys(attr_keys);
+    Ok(meta)
+}
+
+/// Seals the serialized trailer and index of `meta`, compressed with `compression` or by
+/// default with `METADATA_COMPRESSION`. The result starts with the archive attributes needed
+/// to recover the content key, followed by the compression and nonce prefix of the sealed
+/// blob, which runs to the end of the file. Everything before the blob is authenticated
+/// along with it.
+#[cfg(all(feature = "encryption", feature = "writer"))]
+pub(crate) fn seal_metadata(
+    meta: &BoxMetadata,
+    key: &ContentKey,
+    trailer: &[u8],
+    compression: Option<Compression>,
+) -> io::Result<Vec<u8>> {
+    let mut key_attrs = meta
+        .attrs
+        .iter()
+        .filter_map(|(k, v)| meta.attr_keys.get(*k).map(|name| (name, v)))
+        .filter(|(name, _)| *name == ENCRYPTION_ATTR || name.starts_with("encryption."))
+        .collect::<Vec<_>>();
+    key_attrs.sort();
+
+    let mut attr_keys = Vec::with_capacity(key_attrs.len());
+    let mut attrs = AttrMap::new();
+    for (name, value) in key_attrs {
+        attrs.insert(attr_keys.len(), value.clone());
+        attr_keys.push(name.clone());
+    }
+
+    let mut out = io::Cursor::new(vec![]);
+    attr_keys.write(&mut out)?;
+    attrs.write(&mut out)?;
+    let compression = compression.unwrap_or(METADATA_COMPRESSION);
+    compression.write(&mut out)?;
+
+    let nonce = generate_nonce_prefix()?;
+    out.write_all(&nonce)?;
+
+    let envelope = out.get_ref().clone();
+    let mut writer = EncryptWriter::new(&mut out, key, nonce, &envelope);
+    compression.compress(&mut writer, &mut &*trailer)?;
+    writer.finish()?;
+
+    Ok(out.into_inner())
+}
+
+/// Opens a trailer sealed by `seal_metadata`, returning the serialized trailer and index,
+/// which may be no larger than `MAX_TRAILER_SIZE` bytes.
+#[cfg(all(feature = "encryption", feature = "reader"))]
+pub(crate) fn open_metadata(sealed: &[u8], key: &ContentKey) -> io::Result<Vec<u8>> {
+    let mut cursor = io::Cursor::new(sealed);
+    read_metadata_envelope(&mut cursor)?;
+    let compression = Compression::deserialize_owned(&mut cursor)?;
+    let mut nonce = [0u8; NONCE_PREFIX_LEN];
+    cursor.read_exact(&mut nonce)?;
+
+    let envelope = &sealed[..cursor.position() as usize];
+    let length = sealed.len() as u64 - cursor.position();
+    let mut trailer = LimitWriter::new(MAX_TRAILER_SIZE);
+    compression.decompress_write(
+        DecryptReader::new(cursor, key, &nonce, length, envelope)?,
+        &mut trailer,
+    )?;
+    Ok(trailer.buf)
+}
+
+#[cfg(all(feature = "encryption", feature = "writer"))]
+/// Encrypts everything written through it in sealed segments. `finish` must be called to
+/// write the final segment.
+pub(crate) struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: NoncePrefix,
+    context: Vec<u8>,
+    counter: u32,
+    buf: Vec<u8>,
+    plaintext_len: u64,
+    written: u64,
+}
+
+#[cfg(all(feature = "encryption", feature = "writer"))]
+impl<W: Write> EncryptWriter<W> {
+    /// `context` is authenticated along with every segment, and must be given again to decrypt.
+    pub(crate) fn new(
+        inner: W,
+        key: &ContentKey,
+        nonce_prefix: NoncePrefix,
+        context: &[u8],
+    ) -> EncryptWriter<W> {
+        EncryptWriter {
+            inner,
+            cipher: key.cipher(),
+            nonce_prefix,
+            context: context.to_vec(),
+            counter: 0,
+            buf: Vec::with_capacity(SEGMENT_SIZE + TAG_LEN),
+            plaintext_len: 0,
+            written: 0,
+        }
+    }
+
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn write_segment(&mut self, last: bool) -> io::Result<()> {
+        let nonce = segment_nonce(&self.nonce_prefix, self.counter, last);
+        let aad = segment_aad(&self.context, self.counter);
+        self.cipher
+            .encrypt_in_place(&Nonce::from(nonce), &aad, &mut self.buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+        self.inner.write_all(&self.buf)?;
+        self.written += self.buf.len() as u64;
+        self.buf.clear();
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "too much data to encrypt"))?;
+        Ok(())
+    }
+
+    /// Writes the final segment, returning the number of encrypted bytes written in total.
+    pub(crate) fn finish(mut self) -> io::Result<u64> {
+        self.write_segment(true)?;
+        self.inner.flush()?;
+        Ok(self.written)
+    }
+}
+
+#[cfg(all(feature = "encryption", feature = "writer"))]
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full segment is only written once more data arrives, as the last one must be flagged.
+        if self.buf.len() == SEGMENT_SIZE {
+            self.write_segment(false)?;
+        }
+
+        let len = data.len().min(SEGMENT_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        self.plaintext_len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(all(feature = "encryption", feature = "writer"))]
+/// Compressors may query the stream position, which is reported in plaintext bytes.
+/// Any other seek is unsupported.
+impl<W: Write> Seek for EncryptWriter<W> {
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.plaintext_len),
+            SeekFrom::Start(x) if x == self.plaintext_len => Ok(x),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot seek while encrypting",
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "encryption")]
+/// Decrypts and authenticates the segments written by `EncryptWriter`.
+pub(crate) struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: NoncePrefix,
+    context: Vec<u8>,
+    counter: u32,
+    remaining: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> DecryptReader<R> {
+    /// `length` is the number of encrypted bytes to read from `inner`, and `context` what was
+    /// given to the `EncryptWriter` that wrote them.
+    pub(crate) fn new(
+        inner: R,
+        key: &ContentKey,
+        nonce_prefix: &[u8],
+        length: u64,
+        context: &[u8],
+    ) -> io::Result<DecryptReader<R>> {
+        if nonce_prefix.len() != NONCE_PREFIX_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed encryption nonce",
+            ));
+        }
+
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        prefix.copy_from_slice(nonce_prefix);
+
+        Ok(DecryptReader {
+            inner,
+            cipher: key.cipher(),
+            nonce_prefix: prefix,
+            context: context.to_vec(),
+            counter: 0,
+            remaining: length,
+            buf: Vec::with_capacity(SEGMENT_SIZE + TAG_LEN),
+            pos: 0,
+        })
+    }
+
+    fn read_segment(&mut self) -> io::Result<()> {
+        let len = self.remaining.min((SEGMENT_SIZE + TAG_LEN) as u64) as usize;
+        if len < TAG_LEN {
+            return Err(authentication_failed());
+        }
+
+        self.buf.resize(len, 0);
+        self.inner.read_exact(&mut self.buf)?;
+        self.remaining -= len as u64;
+
+        let nonce = segment_nonce(&self.nonce_prefix, self.counter, self.remaining == 0);
+        let aad = segment_aad(&self.context, self.counter);
+        self.cipher
+            .decrypt_in_place(&Nonce::from(nonce), &aad, &mut self.buf)
+            .map_err(|_| authentication_failed())?;
+        self.pos = 0;
+        self.counter = self.counter.wrapping_add(1);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            // A stream always ends in a segment of its own, so one with none was cut short.
+            if self.remaining == 0 && self.counter != 0 {
+                return Ok(0);
+            }
+            self.read_segment()?;
+        }
+
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
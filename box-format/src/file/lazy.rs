@@ -0,0 +1,267 @@
+//! Reading the records of an archive on demand, so that opening an archive and looking up a
+//! few of its files takes about as long whatever the number of records.
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::num::NonZeroU64;
+use std::path::Path;
+
+use fastvlq::ReadVu64Ext;
+use memmap::MmapOptions;
+
+use super::reader::{decompress_trailer, map_anon, map_trailer, read_header, BoxFileReader};
+use super::{AttrMap, BoxMetadata, Inode};
+use crate::{
+    attr_set::{expand_record_attrs, take_attr_sets},
+    de::DeserializeOwned,
+    path::BoxPath,
+    record::Record,
+};
+
+/// Where each record lies in the trailer, after the offset of the attribute keys.
+#[derive(Debug)]
+enum RecordOffsets {
+    /// The record index written before the trailer.
+    Mapped(memmap::Mmap),
+    /// Found by reading every record once, for archives without a record index.
+    Scanned(Vec<u64>),
+}
+
+impl RecordOffsets {
+    fn get(&self, index: u64) -> Option<u64> {
+        match self {
+            RecordOffsets::Mapped(map) => {
+                let start = usize::try_from(index).ok()?.checked_mul(8)?;
+                let bytes = map.get(start..start.checked_add(8)?)?;
+                <[u8; 8]>::try_from(bytes).ok().map(u64::from_le_bytes)
+            }
+            RecordOffsets::Scanned(offsets) => offsets.get(index as usize).copied(),
+        }
+    }
+}
+
+/// A reader of an archive that maps its trailer and decodes records only when they are asked
+/// for, by inode or through the path index. An archive written with a record index is opened
+/// without reading any of its records; any other archive has its records read once to find
+/// where each begins. Archives with encrypted metadata cannot be read this way.
+#[derive(Debug)]
+pub struct LazyBoxFileReader {
+    reader: BoxFileReader,
+    trailer: memmap::Mmap,
+    records: RecordOffsets,
+    record_count: u64,
+    index: Option<pathtrie::fst::Fst<u64>>,
+    attr_sets: Option<(usize, Vec<AttrMap>)>,
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed {}", what))
+}
+
+impl LazyBoxFileReader {
+    /// This will open an existing `.box` file for reading, and error if the file is not valid.
+    /// The trailer is not checked against its checksum, as that would read all of it.
+    pub fn open_at_offset<P: AsRef<Path>>(path: P, offset: u64) -> io::Result<LazyBoxFileReader> {
+        let mut file = OpenOptions::new().read(true).open(path.as_ref())?;
+        let header = read_header(&mut file, offset)?;
+        let ptr = header
+            .trailer
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no trailer found"))?;
+        if header.has_encrypted_metadata() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archive metadata is encrypted and cannot be read lazily",
+            ));
+        }
+
+        // A compressed trailer can only be read once it is decompressed in full.
+        let mapped = unsafe { map_trailer(&file, ptr, offset)? };
+        let trailer = if header.has_compressed_trailer() {
+            map_anon(&decompress_trailer(&mapped)?)?
+        } else {
+            mapped
+        };
+
+        let mut cursor = &trailer[..];
+        let root = Vec::<Inode>::deserialize_owned(&mut cursor)?;
+        let record_count = cursor.read_vu64()?;
+        let first = (trailer.len() - cursor.len()) as u64;
+
+        let records = if header.has_record_index() {
+            RecordOffsets::Mapped(map_record_index(&mut file, ptr, offset, record_count)?)
+        } else {
+            RecordOffsets::Scanned(scan_records(&trailer, first, record_count)?)
+        };
+
+        let attrs_start = records
+            .get(0)
+            .and_then(|x| usize::try_from(x).ok())
+            .filter(|x| *x <= trailer.len())
+            .ok_or_else(|| malformed("record index"))?;
+        let mut cursor = &trailer[attrs_start..];
+        let attr_keys = Vec::<String>::deserialize_owned(&mut cursor)?;
+        let attrs = AttrMap::deserialize_owned(&mut cursor)?;
+
+        // The path index runs to the end of the trailer, and is mapped from the file if it can be.
+        let index_start = trailer.len() - cursor.len();
+        let index = if cursor.is_empty() {
+            None
+        } else if header.has_compressed_trailer() {
+            Some(map_anon(cursor)?)
+        } else {
+            Some(unsafe {
+                MmapOptions::new()
+                    .offset(offset + ptr.get() + index_start as u64)
+                    .map(&file)?
+            })
+        };
+        let index = index.and_then(|x| pathtrie::fst::Fst::new(x).ok());
+
+        let mut meta = BoxMetadata {
+            root,
+            attrs,
+            ..Default::default()
+        };
+        meta.set_attr_keys(attr_keys);
+        let attr_sets = take_attr_sets(&mut meta)?;
+
+        Ok(LazyBoxFileReader {
+            reader: BoxFileReader::from_parts(file, path.as_ref(), header, meta, offset)?,
+            trailer,
+            records,
+            record_count,
+            index,
+            attr_sets,
+        })
+    }
+
+    /// This will open an existing `.box` file for reading, and error if the file is not valid.
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<LazyBoxFileReader> {
+        Self::open_at_offset(path, 0)
+    }
+
+    /// The reader for the data of the records read from the archive. Its metadata holds the
+    /// root inodes, attribute keys and archive attributes, but no records.
+    #[inline(always)]
+    pub fn reader(&self) -> &BoxFileReader {
+        &self.reader
+    }
+
+    /// The attribute keys and archive attributes, as for `reader`.
+    #[inline(always)]
+    pub fn metadata(&self) -> &BoxMetadata {
+        self.reader.metadata()
+    }
+
+    /// The total number of records stored in the archive, reachable or not.
+    #[inline(always)]
+    pub fn inode_count(&self) -> u64 {
+        self.record_count
+    }
+
+    /// Decodes the record of an inode, which is `None` if the archive has no such inode.
+    pub fn record(&self, inode: Inode) -> io::Result<Option<Record>> {
+        if inode.get() > self.record_count {
+            return Ok(None);
+        }
+
+        let mut bytes = self
+            .records
+            .get(inode.get())
+            .and_then(|x| usize::try_from(x).ok())
+            .and_then(|x| self.trailer.get(x..))
+            .ok_or_else(|| malformed("record index"))?;
+        let mut record = Record::deserialize_owned(&mut bytes)?;
+
+        if let Some((set_key, sets)) = self.attr_sets.as_ref() {
+            expand_record_attrs(record.attrs_mut(), *set_key, sets)?;
+        }
+        Ok(Some(record))
+    }
+
+    /// Finds the inode of a path through the path index, or by decoding each directory along
+    /// the path if the index does not have it.
+    pub fn inode(&self, path: &BoxPath) -> io::Result<Option<Inode>> {
+        if let Some(inode) = self.index.as_ref().and_then(|x| x.get(path)) {
+            return Ok(Inode::new(inode).ok());
+        }
+
+        let mut inodes = self.metadata().root_inodes().to_vec();
+        let mut names = path.iter().peekable();
+        while let Some(name) = names.next() {
+            let mut found = None;
+            for inode in inodes.iter().copied() {
+                match self.record(inode)? {
+                    Some(record) if record.name() == name => {
+                        found = Some((inode, record));
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            match found {
+                Some((inode, _)) if names.peek().is_none() => return Ok(Some(inode)),
+                Some((_, Record::Directory(dir))) => inodes = dir.inodes,
+                _ => return Ok(None),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Decodes the record at a path, if there is one.
+    pub fn get(&self, path: &BoxPath) -> io::Result<Option<Record>> {
+        match self.inode(path)? {
+            Some(inode) => self.record(inode),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Maps the offsets of the record index, which ends just before the trailer with the number
+/// of offsets it holds.
+fn map_record_index(
+    file: &mut File,
+    ptr: NonZeroU64,
+    offset: u64,
+    record_count: u64,
+) -> io::Result<memmap::Mmap> {
+    let end = ptr
+        .get()
+        .checked_sub(8)
+        .ok_or_else(|| malformed("record index"))?;
+    file.seek(SeekFrom::Start(offset + end))?;
+    let mut len = [0u8; 8];
+    file.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+
+    // The attribute keys come first, then every record.
+    let start = len
+        .checked_mul(8)
+        .and_then(|x| end.checked_sub(x))
+        .filter(|_| Some(len) == record_count.checked_add(1))
+        .ok_or_else(|| malformed("record index"))?;
+    unsafe {
+        MmapOptions::new()
+            .offset(offset + start)
+            .len((len * 8) as usize)
+            .map(file)
+    }
+}
+
+/// Finds where each record begins by reading them all, for archives without a record index.
+fn scan_records(trailer: &[u8], first: u64, record_count: u64) -> io::Result<Vec<u64>> {
+    let mut cursor = io::Cursor::new(trailer);
+    cursor.set_position(first);
+
+    let mut offsets = vec![0];
+    for _ in 0..record_count {
+        offsets.push(cursor.position());
+        Record::deserialize_owned(&mut cursor)?;
+    }
+    offsets[0] = cursor.position();
+    Ok(offsets)
+}
@@ -1,9 +1,10 @@
 use super::AttrMap;
+use crate::checksum::{HashAlgorithm, HASH_ALGORITHM_ATTR};
 use crate::file::Inode;
 use crate::path::BoxPath;
 use crate::record::DirectoryRecord;
 use crate::Record;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 #[derive(Debug, Default)]
 pub struct BoxMetadata {
@@ -17,11 +18,18 @@ pub struct BoxMetadata {
     /// The index of the attribute key is its interned identifier throughout this file.
     pub(crate) attr_keys: Vec<String>,
 
+    /// The interned identifier of each attribute key, so that keys are found without a scan.
+    pub(crate) attr_key_index: HashMap<String, usize>,
+
     /// The global attributes that apply to this entire box file.
     pub(crate) attrs: AttrMap,
 
     /// The index of paths to files.
     pub(crate) index: Option<pathtrie::fst::Fst<u64>>,
+
+    /// The attribute sets the archive was read with, which records of unknown kinds may
+    /// refer to by index.
+    pub(crate) attr_sets: Vec<AttrMap>,
 }
 
 pub struct Records<'a> {
@@ -68,14 +76,15 @@ impl<'a> Iterator for Records<'a> {
             // If nothing left, clear it here.
             self.cur_dir = None;
         }
-        let inode = match self.inodes.get(self.cur_inode) {
-            Some(i) => *i,
-            None => return None,
-        };
+        let (inode, record) = loop {
+            let inode = *self.inodes.get(self.cur_inode)?;
+            let record = self.meta.record(inode)?;
 
-        let record = match self.meta.record(inode) {
-            Some(v) => v,
-            None => return None,
+            // Records of unknown kinds have no name to make a path from
+            if record.as_unknown().is_none() {
+                break (inode, record);
+            }
+            self.cur_inode += 1;
         };
 
         let base_path = match self.base_path.as_ref() {
@@ -165,15 +174,30 @@ impl BoxMetadata {
         Records::new(self, &*self.root, None)
     }
 
+    /// The records at the root of the archive, leaving out those of unknown kinds.
     #[inline(always)]
     pub fn root_records(&self) -> Vec<(Inode, &Record)> {
         self.root
             .iter()
             .copied()
             .filter_map(|x| self.record(x).map(|r| (x, r)))
+            .filter(|(_, r)| r.as_unknown().is_none())
             .collect()
     }
 
+    /// The inodes at the root of the archive, including any that do not resolve to a record.
+    #[inline(always)]
+    pub fn root_inodes(&self) -> &[Inode] {
+        &self.root
+    }
+
+    /// The total number of records stored in the archive, reachable or not.
+    #[inline(always)]
+    pub fn inode_count(&self) -> u64 {
+        self.inodes.len() as u64
+    }
+
+    /// The records in a directory, leaving out those of unknown kinds.
     #[inline(always)]
     pub fn records(&self, dir_record: &DirectoryRecord) -> Vec<(Inode, &Record)> {
         dir_record
@@ -181,6 +205,7 @@ impl BoxMetadata {
             .iter()
             .copied()
             .filter_map(|x| self.record(x).map(|r| (x, r)))
+            .filter(|(_, r)| r.as_unknown().is_none())
             .collect()
     }
 
@@ -193,6 +218,24 @@ impl BoxMetadata {
         FindRecord::new(self, path.iter().map(str::to_string).collect(), &*self.root).next()
     }
 
+    /// The paths of the files that hard links point to, found in a single walk of the tree.
+    pub(crate) fn hard_link_targets(&self) -> HashMap<Inode, BoxPath> {
+        let targets = self
+            .inodes
+            .iter()
+            .filter_map(Record::as_hard_link)
+            .map(|link| link.target)
+            .collect::<HashSet<_>>();
+        if targets.is_empty() {
+            return HashMap::new();
+        }
+
+        self.iter()
+            .filter(|item| targets.contains(&item.inode) && item.record.as_file().is_some())
+            .map(|item| (item.inode, item.path))
+            .collect()
+    }
+
     #[inline(always)]
     pub fn record(&self, inode: Inode) -> Option<&Record> {
         self.inodes.get(inode.get() as usize - 1)
@@ -227,20 +270,54 @@ impl BoxMetadata {
         self.attrs.get(&key)
     }
 
+    /// The algorithm used for the digests of file records, if the archive records any.
+    /// Returns an error if the archive names an algorithm that is not known.
+    pub fn hash_algorithm(&self) -> std::io::Result<Option<HashAlgorithm>> {
+        let value = match self.file_attr(HASH_ALGORITHM_ATTR) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(HashAlgorithm::from_name)
+            .map(Some)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown hash algorithm: {}", String::from_utf8_lossy(value)),
+                )
+            })
+    }
+
     #[inline(always)]
     pub fn attr_key(&self, key: &str) -> Option<usize> {
-        self.attr_keys.iter().position(|r| r == key)
+        self.attr_key_index.get(key).copied()
     }
 
     #[inline(always)]
     pub fn attr_key_or_create(&mut self, key: &str) -> usize {
-        match self.attr_keys.iter().position(|r| r == key) {
+        match self.attr_key(key) {
             Some(v) => v,
             None => {
                 let len = self.attr_keys.len();
                 self.attr_keys.push(key.to_string());
+                self.attr_key_index.insert(key.to_string(), len);
                 len
             }
         }
     }
+
+    /// Replaces the interned attribute keys, as read from an archive. A key listed more than
+    /// once is identified by its first position.
+    #[cfg(feature = "reader")]
+    pub(crate) fn set_attr_keys(&mut self, attr_keys: Vec<String>) {
+        self.attr_key_index = attr_keys
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, key)| (key.clone(), index))
+            .collect();
+        self.attr_keys = attr_keys;
+    }
 }
@@ -20,6 +20,8 @@ impl Inode {
         self.0.get()
     }
 }
+#[cfg(feature = "reader")]
+pub mod lazy;
 mod meta;
 #[cfg(feature = "reader")]
 pub mod reader;
@@ -36,7 +38,7 @@ mod tests {
     use std::collections::HashMap;
     use std::io::prelude::*;
     use std::io::Cursor;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
     fn create_test_box<F: AsRef<Path>>(filename: F) {
         let _ = std::fs::remove_file(filename.as_ref());
@@ -104,11 +106,13 @@ mod tests {
         assert_eq!(vec, b"hello\0\0\0")
     }
 
-    fn insert_impl<F>(filename: &str, f: F)
+    fn insert_impl<P, F>(filename: P, f: F)
     where
-        F: Fn(&str) -> BoxFileWriter,
+        P: AsRef<Path>,
+        F: Fn(&Path) -> BoxFileWriter,
     {
-        let _ = std::fs::remove_file(&filename);
+        let filename = filename.as_ref();
+        let _ = std::fs::remove_file(filename);
         let v =
             "This, this, this, this, this is a compressable string string string string string.\n"
                 .to_string();
@@ -166,6 +170,36 @@ mod tests {
         );
     }
 
+    /// An empty directory of a test's own, as tests run concurrently.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join("box-format-tests").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_box(name: &str) -> PathBuf {
+        test_dir(name).join("test.box")
+    }
+
+    fn insert_bytes(bf: &mut BoxFileWriter, name: &str, data: &[u8]) {
+        bf.insert(
+            Compression::Stored,
+            BoxPath::new(name).unwrap(),
+            &mut Cursor::new(data.to_vec()),
+            HashMap::new(),
+        )
+        .unwrap();
+    }
+
+    fn file_record<'a>(bf: &'a BoxFileReader, name: &str) -> &'a FileRecord {
+        bf.metadata()
+            .inode(&BoxPath::new(name).unwrap())
+            .and_then(|x| bf.metadata().record(x))
+            .and_then(Record::as_file)
+            .unwrap()
+    }
+
     #[test]
     fn insert() {
         insert_impl("./insert_garbage.box", |n| {
@@ -179,6 +213,1593 @@ mod tests {
         });
     }
 
+    #[test]
+    fn verify_checksums() {
+        let filename = &test_box("verify_checksums");
+        insert_impl(filename, |n| BoxFileWriter::create(n).unwrap());
+
+        let data = {
+            let bf = BoxFileReader::open(filename).unwrap();
+            let record = bf.meta.inodes[1].as_file().unwrap();
+            assert_eq!(
+                record.attr(bf.metadata(), CRC32_ATTR).map(|x| x.len()),
+                Some(4)
+            );
+            record.data.get()
+        };
+
+        // Flip a byte of the first file's stored data
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(filename)
+                .unwrap();
+            let mut byte = [0u8; 1];
+            file.seek(std::io::SeekFrom::Start(data + 1)).unwrap();
+            file.read_exact(&mut byte).unwrap();
+            file.seek(std::io::SeekFrom::Start(data + 1)).unwrap();
+            file.write_all(&[byte[0] ^ 0xff]).unwrap();
+        }
+
+        let mut bf = BoxFileReader::open(filename).unwrap();
+        let record = bf.meta.inodes[1].as_file().unwrap();
+        let err = bf.decompress(record, std::io::sink()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(bf.decompress_value::<Vec<u8>>(record).is_err());
+
+        bf.set_verify_checksums(false);
+        let record = bf.meta.inodes[1].as_file().unwrap();
+        bf.decompress(record, std::io::sink()).unwrap();
+
+        // A forged length is not allocated for up front
+        if let Record::File(record) = &mut bf.meta.inodes[1] {
+            record.decompressed_length = u64::MAX >> 1;
+        }
+        let record = bf.meta.inodes[1].as_file().unwrap();
+        assert!(bf.decompress_value::<Vec<u8>>(record).is_ok());
+    }
+
+    #[test]
+    fn corrupted_trailer() {
+        let filename = &test_box("corrupted_trailer");
+        insert_impl(filename, |n| BoxFileWriter::create(n).unwrap());
+
+        let trailer = {
+            let bf = BoxFileReader::open(filename).unwrap();
+            bf.header.trailer.unwrap().get()
+        };
+
+        // Flip a byte inside the serialized trailer
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(filename)
+                .unwrap();
+            let mut byte = [0u8; 1];
+            file.seek(std::io::SeekFrom::Start(trailer + 2)).unwrap();
+            file.read_exact(&mut byte).unwrap();
+            file.seek(std::io::SeekFrom::Start(trailer + 2)).unwrap();
+            file.write_all(&[byte[0] ^ 0xff]).unwrap();
+        }
+
+        let err = BoxFileReader::open(filename).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("metadata corrupted"));
+        assert!(BoxFileWriter::open(filename).is_err());
+    }
+
+    #[test]
+    fn signatures() {
+        let filename = &test_box("signatures");
+        let keypair = |seed| {
+            let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+            let public = PublicKey::from(&secret);
+            Keypair { secret, public }
+        };
+        let (signer, other) = (keypair(1), keypair(2));
+
+        // Without digests the signature would not cover the data of files
+        insert_impl(filename, |n| BoxFileWriter::create(n).unwrap());
+        {
+            let bf = BoxFileReader::open(filename).unwrap();
+            assert!(bf.sign(&signer).is_err());
+            let mut bf = BoxFileWriter::open(filename).unwrap();
+            bf.set_signing_key(keypair(1)).unwrap();
+            assert!(bf.finish().is_err());
+        }
+
+        insert_impl(filename, |n| {
+            let mut bf = BoxFileWriter::create(n).unwrap();
+            bf.set_hash_algorithm(HashAlgorithm::default()).unwrap();
+            bf
+        });
+
+        // Unsigned archives are refused, but can be signed with a detached signature
+        {
+            let bf = BoxFileReader::open(filename).unwrap();
+            assert!(bf.signature().unwrap().is_none());
+            assert!(bf.verify_signature(&signer.public).is_err());
+            let detached = bf.sign(&signer).unwrap();
+            bf.verify_detached_signature(&signer.public, &detached)
+                .unwrap();
+            assert!(bf
+                .verify_detached_signature(&other.public, &detached)
+                .is_err());
+        }
+
+        {
+            let mut bf = BoxFileWriter::open(filename).unwrap();
+            bf.set_signing_key(keypair(1)).unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open_signed(filename, &signer.public).unwrap();
+        assert!(bf.verify_signature(&other.public).is_err());
+        let data = bf.meta.inodes[1].as_file().unwrap().data.get();
+        drop(bf);
+
+        // Tampered data still verifies against the trailer, but not against its digest
+        let original = std::fs::read(filename).unwrap();
+        {
+            let mut raw = original.clone();
+            raw[data as usize] ^= 0xff;
+            std::fs::write(filename, raw).unwrap();
+            let bf = BoxFileReader::open_signed(filename, &signer.public).unwrap();
+            let record = bf.meta.inodes[1].as_file().unwrap();
+            assert!(bf.verify(record).is_err());
+            assert!(bf.decompress_value::<Vec<u8>>(record).is_err());
+        }
+        std::fs::write(filename, original).unwrap();
+
+        // Changing the archive drops the signature that no longer applies to it
+        {
+            let mut bf = BoxFileWriter::open(filename).unwrap();
+            bf.mkdir(BoxPath::new("new").unwrap(), HashMap::new())
+                .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert!(bf.signature().unwrap().is_none());
+        assert!(BoxFileReader::open_signed(filename, &signer.public).is_err());
+    }
+
+    #[test]
+    fn compressed_trailer() {
+        let dir = test_dir("compressed_trailer");
+        let keypair = || {
+            let secret = SecretKey::from_bytes(&[1; 32]).unwrap();
+            let public = PublicKey::from(&secret);
+            Keypair { secret, public }
+        };
+        let create = |filename: &Path, compression: Option<Compression>| {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.set_trailer_compression(compression);
+            bf.set_signing_key(keypair()).unwrap();
+            for i in 0..500 {
+                bf.insert(
+                    Compression::Stored,
+                    BoxPath::new(format!("a_rather_long_file_name_number_{}.txt", i)).unwrap(),
+                    &mut std::io::Cursor::new(format!("{}", i)),
+                    HashMap::new(),
+                )
+                .unwrap();
+            }
+            bf.finish().unwrap();
+        };
+
+        create(&dir.join("compressed_trailer.box"), Some(Compression::Zstd));
+        create(&dir.join("compressed_trailer_none.box"), None);
+        let bf = BoxFileReader::open(dir.join("compressed_trailer_none.box")).unwrap();
+        assert!(!bf.header.has_compressed_trailer());
+        drop(bf);
+
+        let read = |bf: &BoxFileReader, i: usize| {
+            let record = file_record(bf, &format!("a_rather_long_file_name_number_{}.txt", i));
+            bf.decompress_value::<String>(record).unwrap()
+        };
+
+        let bf = BoxFileReader::open_signed(dir.join("compressed_trailer.box"), &keypair().public)
+            .unwrap();
+        assert!(bf.header.has_compressed_trailer());
+        assert_eq!(bf.metadata().inodes.len(), 500);
+        assert!(bf.metadata().index.is_some());
+        assert_eq!(read(&bf, 123), "123");
+        drop(bf);
+
+        // Reopened archives keep their trailer compressed
+        {
+            let mut bf = BoxFileWriter::open(dir.join("compressed_trailer.box")).unwrap();
+            bf.mkdir(BoxPath::new("new").unwrap(), HashMap::new())
+                .unwrap();
+            bf.finish().unwrap();
+        }
+        let bf = BoxFileReader::open(dir.join("compressed_trailer.box")).unwrap();
+        assert!(bf.header.has_compressed_trailer());
+        assert!(bf.metadata().inode(&BoxPath::new("new").unwrap()).is_some());
+        assert_eq!(read(&bf, 499), "499");
+
+        // Decompressed trailers are bounded in size
+        let mut out = super::reader::LimitWriter::new(8);
+        out.write_all(b"12345678").unwrap();
+        let err = out.write_all(b"9").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(out.buf, b"12345678");
+    }
+
+    #[test]
+    fn encryption() {
+        let filename = &test_box("encryption");
+
+        // Spans several encrypted segments, with a partial one at the end
+        let data = (0..200_000u32).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.set_password("hunter2").unwrap();
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("data.bin").unwrap(),
+                &mut std::io::Cursor::new(data.clone()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert!(bf.is_encrypted());
+        let record = bf.meta.inodes[0].as_file().unwrap();
+        assert_ne!(&*unsafe { bf.memory_map(record).unwrap() }, &*data);
+        let err = bf.decompress(record, std::io::sink()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        let err = BoxFileReader::open_with_password(filename, "hunter3").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        let bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        let record = bf.meta.inodes[0].as_file().unwrap();
+        assert_eq!(bf.decompress_value::<Vec<u8>>(record).unwrap(), data);
+        bf.verify(record).unwrap();
+        let data_offset = record.data.get() + 10;
+        drop(bf);
+
+        // Tampering with the encrypted data fails authentication
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(filename)
+                .unwrap();
+            let mut byte = [0u8; 1];
+            file.seek(std::io::SeekFrom::Start(data_offset)).unwrap();
+            file.read_exact(&mut byte).unwrap();
+            file.seek(std::io::SeekFrom::Start(data_offset)).unwrap();
+            file.write_all(&[byte[0] ^ 0xff]).unwrap();
+        }
+
+        let mut bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        bf.set_verify_checksums(false);
+        let record = bf.meta.inodes[0].as_file().unwrap();
+        let mut output = vec![];
+        let err = bf.decompress(record, &mut output).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn encryption_binding() {
+        use crate::encryption::{ContentKey, DecryptReader, EncryptWriter};
+
+        // Segments only decrypt for the stream offset they were written at
+        let key = ContentKey::generate().unwrap();
+        let nonce = crate::encryption::generate_nonce_prefix().unwrap();
+        let mut sealed = vec![];
+        let mut writer = EncryptWriter::new(&mut sealed, &key, nonce, &100u64.to_le_bytes());
+        writer.write_all(b"hello").unwrap();
+        writer.finish().unwrap();
+
+        let open = |offset: u64| {
+            let mut out = vec![];
+            let len = sealed.len() as u64;
+            DecryptReader::new(&*sealed, &key, &nonce, len, &offset.to_le_bytes())
+                .unwrap()
+                .read_to_end(&mut out)
+                .map(|_| out)
+        };
+        assert_eq!(open(100).unwrap(), b"hello");
+        assert_eq!(
+            open(200).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        // Keyed SHA-256 digests are HMAC-SHA-256
+        let mut hasher = HashAlgorithm::Sha256.keyed_hasher(&[7; 32]).unwrap();
+        hasher.update(b"some data");
+        let expected = "aa281a67f152fda6d39a332a04057b7cf0a71f55338cc193b8225ea725ad45ed";
+        let digest = hasher
+            .finalize()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>();
+        assert_eq!(digest, expected);
+
+        // Encrypted files have a keyed digest and no CRC32
+        let filename = &test_box("encryption_binding");
+        for algorithm in &[HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+            {
+                let mut bf = BoxFileWriter::create(filename).unwrap();
+                bf.set_hash_algorithm(*algorithm).unwrap();
+                bf.set_password("hunter2").unwrap();
+                bf.insert(
+                    Compression::Stored,
+                    BoxPath::new("a").unwrap(),
+                    &mut std::io::Cursor::new(b"some data".to_vec()),
+                    HashMap::new(),
+                )
+                .unwrap();
+                bf.finish().unwrap();
+            }
+
+            let bf = BoxFileReader::open(filename).unwrap();
+            let record = bf.meta.inodes[0].as_file().unwrap();
+            assert!(record.attr(bf.metadata(), CRC32_ATTR).is_none());
+            assert!(record.attr(bf.metadata(), HASH_ATTR).is_none());
+            let mut hasher = algorithm.hasher().unwrap();
+            hasher.update(b"some data");
+            let digest = record.attr(bf.metadata(), KEYED_HASH_ATTR).unwrap();
+            assert_eq!(digest.len(), 32);
+            assert_ne!(digest, &*hasher.finalize());
+            assert_eq!(
+                bf.verify(record).unwrap_err().kind(),
+                std::io::ErrorKind::PermissionDenied
+            );
+
+            let mut bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+            bf.verify(bf.meta.inodes[0].as_file().unwrap()).unwrap();
+
+            // A stream truncated to nothing is not taken to be empty
+            if let Record::File(record) = &mut bf.meta.inodes[0] {
+                record.length = 0;
+            }
+            bf.set_verify_checksums(false);
+            let record = bf.meta.inodes[0].as_file().unwrap();
+            assert_eq!(
+                bf.decompress(record, std::io::sink()).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData
+            );
+            std::fs::remove_file(filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn encryption_recipients() {
+        let filename = &test_box("encryption_recipients");
+
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let data = b"for your eyes only".to_vec();
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.add_recipient(&alice.recipient()).unwrap();
+            bf.add_recipient(&bob.recipient()).unwrap();
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("secret.txt").unwrap(),
+                &mut std::io::Cursor::new(data.clone()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        for identity in &[&alice, &bob] {
+            let bf = BoxFileReader::open_with_identity(filename, identity).unwrap();
+            let record = bf.meta.inodes[0].as_file().unwrap();
+            assert_eq!(bf.decompress_value::<Vec<u8>>(record).unwrap(), data);
+        }
+
+        let err = BoxFileReader::open_with_identity(filename, &Identity::generate()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // Appending requires unlocking, and shares the existing key
+        {
+            let mut bf = BoxFileWriter::open(filename).unwrap();
+            let err = bf.set_password("hunter2").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+            bf.unlock_with_identity(&bob).unwrap();
+            bf.set_password("hunter2").unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        let record = bf.meta.inodes[0].as_file().unwrap();
+        assert_eq!(bf.decompress_value::<Vec<u8>>(record).unwrap(), data);
+        BoxFileReader::open_with_identity(filename, &alice).unwrap();
+    }
+
+    #[test]
+    fn encrypted_metadata() {
+        let filename = &test_box("encrypted_metadata");
+
+        let secret = SecretKey::from_bytes(&[1; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.set_password("hunter2").unwrap();
+            bf.set_encrypted_metadata(true);
+            bf.set_signing_key(Keypair { secret, public }).unwrap();
+            bf.mkdir(BoxPath::new("hidden").unwrap(), HashMap::new())
+                .unwrap();
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("hidden/plans.txt").unwrap(),
+                &mut std::io::Cursor::new(b"world domination".to_vec()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let raw = std::fs::read(filename).unwrap();
+        assert!(!raw.windows(5).any(|x| x == b"plans"));
+
+        // Only the attributes that lock the archive are visible without the key
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert!(bf.is_encrypted());
+        assert!(bf.has_encrypted_metadata());
+        assert_eq!(bf.metadata().iter().count(), 0);
+
+        let err = BoxFileReader::open_with_password(filename, "hunter3").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        let bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        let record = file_record(&bf, "hidden/plans.txt");
+        assert_eq!(
+            bf.decompress_value::<Vec<u8>>(record).unwrap(),
+            b"world domination"
+        );
+        bf.verify_signature(&public).unwrap();
+
+        // Appending requires the key, and keeps the metadata encrypted
+        let err = BoxFileWriter::open(filename).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        {
+            let mut bf = BoxFileWriter::open_with_password(filename, "hunter2").unwrap();
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("hidden/more.txt").unwrap(),
+                &mut std::io::Cursor::new(b"and then some".to_vec()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert_eq!(bf.metadata().iter().count(), 0);
+        let bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        assert_eq!(bf.metadata().iter().count(), 3);
+
+        // The attributes left readable are authenticated along with the sealed trailer
+        let key = bf.content_key.clone().unwrap();
+        let salt = bf.meta.file_attr("encryption.kdf.salt").unwrap().clone();
+        let raw = std::fs::read(filename).unwrap();
+        let mut sealed = raw[bf.header.trailer.unwrap().get() as usize..].to_vec();
+        crate::encryption::open_metadata(&sealed, &key).unwrap();
+        let pos = sealed
+            .windows(salt.len())
+            .position(|x| x == &*salt)
+            .unwrap();
+        sealed[pos] ^= 0xff;
+        assert!(crate::encryption::open_metadata(&sealed, &key).is_err());
+    }
+
+    #[test]
+    fn block_reads() {
+        let filename = &test_box("block_reads");
+
+        let data = (0..10_000u32).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.set_password("hunter2").unwrap();
+            bf.set_block_size(std::num::NonZeroU32::new(1024));
+            bf.insert(
+                Compression::Zstd,
+                BoxPath::new("blocked.bin").unwrap(),
+                &mut std::io::Cursor::new(data.clone()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.set_block_size(None);
+            bf.insert(
+                Compression::Deflate,
+                BoxPath::new("stream.bin").unwrap(),
+                &mut std::io::Cursor::new(data.clone()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        let blocked = file_record(&bf, "blocked.bin");
+        assert_eq!(
+            blocked.attr(bf.metadata(), BLOCKS_ATTR).unwrap().len(),
+            4 + 10 * 8
+        );
+        assert_eq!(bf.decompress_value::<Vec<u8>>(blocked).unwrap(), data);
+        bf.verify(blocked).unwrap();
+
+        let stream = file_record(&bf, "stream.bin");
+        assert!(stream.attr(bf.metadata(), BLOCKS_ATTR).is_none());
+        assert!(bf.is_random_access(blocked));
+        assert!(!bf.is_random_access(stream));
+
+        for record in [blocked, stream].iter() {
+            assert_eq!(bf.read_at(record, 1000, 100).unwrap(), &data[1000..1100]);
+            assert_eq!(bf.read_at(record, 1000, 3000).unwrap(), &data[1000..4000]);
+            assert_eq!(bf.read_at(record, 9990, 100).unwrap(), &data[9990..]);
+            assert!(bf.read_at(record, 10_000, 100).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn solid_blocks() {
+        let filename = &test_box("solid_blocks");
+
+        let small = |i: u8| format!("small file number {} says hello\n", i).repeat(i as usize + 1);
+        let large = vec![7u8; 4096];
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.set_password("hunter2").unwrap();
+            bf.set_solid_size(std::num::NonZeroU32::new(1024)).unwrap();
+            for i in 0..4 {
+                bf.insert(
+                    Compression::Zstd,
+                    BoxPath::new(format!("small{}.txt", i)).unwrap(),
+                    &mut std::io::Cursor::new(small(i)),
+                    HashMap::new(),
+                )
+                .unwrap();
+            }
+            bf.insert(
+                Compression::Zstd,
+                BoxPath::new("large.bin").unwrap(),
+                &mut std::io::Cursor::new(large.clone()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.insert(
+                Compression::Deflate,
+                BoxPath::new("small4.txt").unwrap(),
+                &mut std::io::Cursor::new(small(4)),
+                HashMap::new(),
+            )
+            .unwrap();
+
+            let record = bf
+                .metadata()
+                .inodes
+                .iter()
+                .filter_map(Record::as_file)
+                .next();
+            let err = unsafe { bf.data(record.unwrap()).unwrap_err() };
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        let first = file_record(&bf, "small0.txt");
+        for i in 0..5 {
+            let record = file_record(&bf, &format!("small{}.txt", i));
+            assert!(record.attr(bf.metadata(), SOLID_ATTR).is_some());
+            assert_eq!(record.data == first.data, i < 4);
+            assert_eq!(
+                bf.decompress_value::<String>(record).unwrap(),
+                small(i as u8)
+            );
+            bf.verify(record).unwrap();
+        }
+
+        let record2 = file_record(&bf, "small2.txt");
+        assert_eq!(
+            bf.read_at(record2, 5, 10).unwrap(),
+            &small(2).as_bytes()[5..15]
+        );
+
+        let large_record = file_record(&bf, "large.bin");
+        assert!(large_record.attr(bf.metadata(), SOLID_ATTR).is_none());
+        assert_eq!(bf.decompress_value::<Vec<u8>>(large_record).unwrap(), large);
+    }
+
+    #[test]
+    fn inline_files() {
+        let dir = test_dir("inline_files");
+        let create = |filename: &Path, inline_size: Option<u32>| {
+            let mut bf = BoxFileWriter::create_with_alignment(filename, 4096).unwrap();
+            bf.set_inline_size(inline_size.and_then(std::num::NonZeroU32::new));
+            for i in 0..16u8 {
+                bf.insert(
+                    Compression::Zstd,
+                    BoxPath::new(format!("{}.txt", i)).unwrap(),
+                    &mut std::io::Cursor::new(vec![i; i as usize]),
+                    HashMap::new(),
+                )
+                .unwrap();
+            }
+            bf.insert(
+                Compression::Zstd,
+                BoxPath::new("large.bin").unwrap(),
+                &mut std::io::Cursor::new(vec![7u8; 4096]),
+                HashMap::new(),
+            )
+            .unwrap();
+
+            let records = bf.metadata().inodes.iter().filter_map(Record::as_file);
+            for record in records.filter(|_| inline_size.is_some()) {
+                if let Some(data) = record.attr(bf.metadata(), INLINE_ATTR) {
+                    assert_eq!(&*unsafe { bf.data(record).unwrap() }, data);
+                }
+            }
+            bf.finish().unwrap()
+        };
+
+        let with = create(&dir.join("inline_files.box"), Some(16));
+        let without = create(&dir.join("inline_files_none.box"), None);
+        assert!(with < without, "{} >= {}", with, without);
+
+        let bf = BoxFileReader::open(dir.join("inline_files.box")).unwrap();
+        for i in 0..16u8 {
+            let record = file_record(&bf, &format!("{}.txt", i));
+            let data = vec![i; i as usize];
+            assert_eq!(record.attr(bf.metadata(), INLINE_ATTR), Some(&*data));
+            assert_eq!(record.length, 0);
+            assert_eq!(bf.decompress_value::<Vec<u8>>(record).unwrap(), data);
+            assert_eq!(
+                bf.read_at(record, 2, 4).unwrap(),
+                &data[2.min(data.len())..6.min(data.len())]
+            );
+            bf.verify(record).unwrap();
+
+            let mut bytes = vec![];
+            bf.read_bytes(record)
+                .unwrap()
+                .read_to_end(&mut bytes)
+                .unwrap();
+            assert_eq!(bytes, data);
+            assert_eq!(&*unsafe { bf.memory_map(record).unwrap() }, &*data);
+        }
+
+        let large = file_record(&bf, "large.bin");
+        assert!(large.attr(bf.metadata(), INLINE_ATTR).is_none());
+        assert_eq!(
+            bf.decompress_value::<Vec<u8>>(large).unwrap(),
+            vec![7u8; 4096]
+        );
+    }
+
+    #[test]
+    fn lazy_reader() {
+        let dir = test_dir("lazy_reader");
+        let create = |filename: &Path, record_index: bool, compression: Option<Compression>| {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.set_record_index(record_index);
+            bf.set_trailer_compression(compression);
+            for dir in 0..10 {
+                let dir = BoxPath::new(format!("dir{}", dir)).unwrap();
+                bf.mkdir(dir.clone(), HashMap::new()).unwrap();
+                for i in 0..20 {
+                    let mut attrs = HashMap::new();
+                    attrs.insert(MODE_ATTR.to_string(), 0o100644u32.to_le_bytes().to_vec());
+                    bf.insert(
+                        Compression::Stored,
+                        dir.join(format!("{}.txt", i)).unwrap(),
+                        &mut std::io::Cursor::new(format!("{} in {}", i, dir)),
+                        attrs,
+                    )
+                    .unwrap();
+                }
+            }
+            bf.finish().unwrap();
+        };
+
+        let files = [
+            (&dir.join("lazy_reader.box"), true, None),
+            (&dir.join("lazy_reader_scanned.box"), false, None),
+            (
+                &dir.join("lazy_reader_compressed.box"),
+                true,
+                Some(Compression::Zstd),
+            ),
+        ];
+        for (filename, record_index, compression) in files.iter() {
+            create(filename, *record_index, *compression);
+
+            let eager = BoxFileReader::open(filename).unwrap();
+            let bf = LazyBoxFileReader::open(filename).unwrap();
+            assert_eq!(bf.reader().header.has_record_index(), *record_index);
+            assert_eq!(bf.inode_count(), 210);
+            assert_eq!(bf.metadata().root_inodes().len(), 10);
+            assert!(bf.metadata().inodes.is_empty());
+
+            let path = BoxPath::new("dir7/13.txt").unwrap();
+            let inode = bf.inode(&path).unwrap().unwrap();
+            assert_eq!(Some(inode), eager.metadata().inode(&path));
+            let record = bf.record(inode).unwrap().unwrap();
+            assert_eq!(record.name(), "13.txt");
+            assert_eq!(record.mode(bf.metadata()), Some(0o100644));
+
+            let file = record.as_file().unwrap();
+            assert_eq!(
+                bf.reader().decompress_value::<String>(file).unwrap(),
+                "13 in dir7"
+            );
+            assert!(bf
+                .get(&BoxPath::new("dir7/missing").unwrap())
+                .unwrap()
+                .is_none());
+            assert!(bf.record(Inode::new(211).unwrap()).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn unknown_records() {
+        use crate::de::DeserializeOwned;
+        use crate::ser::Serialize;
+        use fastvlq::WriteVu64Ext;
+
+        let link = LinkRecord {
+            name: "link".into(),
+            target: BoxPath::new("target").unwrap(),
+            attrs: HashMap::new(),
+        };
+        let mut body = Cursor::new(vec![]);
+        link.write(&mut body).unwrap();
+        let body = body.into_inner();
+
+        // Records are framed with their length, and records from before that still read
+        let mut framed = Cursor::new(vec![]);
+        link.upcast().write(&mut framed).unwrap();
+        let framed = framed.into_inner();
+        assert_eq!(framed[0], 0x82);
+        let mut legacy = vec![0x2];
+        legacy.extend_from_slice(&body);
+
+        // Fields added to known kinds are skipped over
+        let mut extended = vec![0x82];
+        extended.write_vu64(body.len() as u64 + 3).unwrap();
+        extended.extend_from_slice(&body);
+        extended.extend_from_slice(&[1, 2, 3]);
+
+        let mut optional = vec![0xc0 | 0x21];
+        optional.write_vu64(3).unwrap();
+        optional.extend_from_slice(&[7, 8, 9]);
+
+        let mut bytes = [&*framed, &*legacy, &*extended, &*optional, &*framed].concat();
+        let mut cursor = &bytes[..];
+        for _ in 0..3 {
+            let record = Record::deserialize_owned(&mut cursor).unwrap();
+            let link = record.as_link().unwrap();
+            assert_eq!(link.name, "link");
+            assert_eq!(link.target, BoxPath::new("target").unwrap());
+        }
+        let record = Record::deserialize_owned(&mut cursor).unwrap();
+        let unknown = record.as_unknown().unwrap();
+        assert_eq!(unknown.kind, 0x21);
+        assert_eq!(unknown.data, [7, 8, 9]);
+        assert_eq!(record.name(), "");
+        assert!(Record::deserialize_owned(&mut cursor)
+            .unwrap()
+            .as_link()
+            .is_some());
+        assert!(cursor.is_empty());
+
+        // Unknown records are written back as they were read
+        let mut written = Cursor::new(vec![]);
+        record.write(&mut written).unwrap();
+        assert_eq!(written.into_inner(), optional);
+
+        // Unknown records that are not optional cannot be skipped
+        let mut required = optional.clone();
+        required[0] = 0x80 | 0x21;
+        assert!(Record::deserialize_owned(&mut &required[..]).is_err());
+        assert!(Record::deserialize_owned(&mut &[0x21u8][..]).is_err());
+
+        // A truncated record is not read as a shorter one
+        bytes.truncate(framed.len() - 1);
+        assert!(Record::deserialize_owned(&mut &bytes[..]).is_err());
+
+        // Unknown records are kept in the metadata but left out when iterating by path
+        let mut meta = BoxMetadata {
+            root: vec![Inode::new(1).unwrap(), Inode::new(3).unwrap()],
+            ..Default::default()
+        };
+        let mut dir = DirectoryRecord::new("dir".into());
+        dir.inodes.push(Inode::new(2).unwrap());
+        meta.inodes.push(dir.upcast());
+        meta.inodes
+            .push(Record::deserialize_owned(&mut &optional[..]).unwrap());
+        meta.inodes
+            .push(Record::deserialize_owned(&mut &framed[..]).unwrap());
+
+        let mut trailer = Cursor::new(vec![]);
+        meta.write(&mut trailer).unwrap();
+        let meta = BoxMetadata::deserialize_owned(&mut &trailer.into_inner()[..]).unwrap();
+        assert_eq!(meta.inode_count(), 3);
+        assert!(meta
+            .record(Inode::new(2).unwrap())
+            .unwrap()
+            .as_unknown()
+            .is_some());
+        let paths = meta.iter().map(|x| x.path.to_string()).collect::<Vec<_>>();
+        assert_eq!(paths, ["dir", "link"]);
+        let dir = meta.record(Inode::new(1).unwrap()).unwrap();
+        assert!(meta.records(dir.as_directory().unwrap()).is_empty());
+
+        // Appending keeps unknown records, and the attribute sets they may refer to in place
+        let filename = &test_box("unknown_records");
+        let mode = |mode: u32| {
+            let mut attrs = HashMap::new();
+            attrs.insert(MODE_ATTR.to_string(), mode.to_le_bytes().to_vec());
+            attrs
+        };
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.mkdir(BoxPath::new("a").unwrap(), mode(0o40755)).unwrap();
+            bf.mkdir(BoxPath::new("b").unwrap(), mode(0o40700)).unwrap();
+            bf.meta
+                .inodes
+                .push(Record::deserialize_owned(&mut &optional[..]).unwrap());
+            bf.finish().unwrap();
+        }
+        let sets = BoxFileReader::open(filename).unwrap().meta.attr_sets;
+        assert_eq!(sets.len(), 2);
+        {
+            let mut bf = BoxFileWriter::open(filename).unwrap();
+            let path = BoxPath::new("a").unwrap();
+            bf.set_attr(&path, MODE_ATTR, 0o40711u32.to_le_bytes().to_vec())
+                .unwrap();
+            bf.mkdir(BoxPath::new("c").unwrap(), mode(0o40755)).unwrap();
+            bf.finish().unwrap();
+        }
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert_eq!(bf.meta.attr_sets.len(), 3);
+        assert_eq!(bf.meta.attr_sets[..2], sets[..]);
+        let unknown = bf.meta.inodes.iter().find_map(Record::as_unknown).unwrap();
+        assert_eq!(unknown.data, [7, 8, 9]);
+        let a = bf.metadata().inode(&BoxPath::new("a").unwrap()).unwrap();
+        let a = bf.metadata().record(a).unwrap();
+        assert_eq!(a.mode(bf.metadata()), Some(0o40711));
+    }
+
+    #[test]
+    fn deduplication() {
+        let filename = &test_box("deduplication");
+
+        let vendored = vec![42u8; 8192];
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            let err = bf.set_deduplicate(true).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+            bf.set_hash_algorithm(HashAlgorithm::Sha256).unwrap();
+            bf.set_deduplicate(true).unwrap();
+            bf.set_solid_size(std::num::NonZeroU32::new(1024)).unwrap();
+            insert_bytes(&mut bf, "a.bin", &vendored);
+            insert_bytes(&mut bf, "small1.txt", b"tiny");
+            insert_bytes(&mut bf, "small2.txt", b"tiny");
+            insert_bytes(&mut bf, "b.bin", &vendored);
+            insert_bytes(&mut bf, "c.bin", b"something else entirely");
+            bf.finish().unwrap();
+        }
+
+        let data_end = {
+            let bf = BoxFileReader::open(filename).unwrap();
+            let files = bf.meta.inodes.iter().filter_map(Record::as_file);
+            files.map(|x| x.data.get() + x.length).max().unwrap()
+        };
+
+        // Digests of files already in the archive are matched when appending, and the data of
+        // a duplicate, written before it is known to be one, is written over by what follows
+        let other = vec![7u8; 8192];
+        {
+            let mut bf = BoxFileWriter::open(filename).unwrap();
+            bf.set_deduplicate(true).unwrap();
+            insert_bytes(&mut bf, "d.bin", &vendored);
+            insert_bytes(&mut bf, "e.bin", &other);
+            bf.finish().unwrap();
+        }
+        assert!(std::fs::metadata(filename).unwrap().len() < 3 * vendored.len() as u64);
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        let a = file_record(&bf, "a.bin");
+        for name in ["b.bin", "d.bin"].iter() {
+            let record = file_record(&bf, name);
+            assert_eq!((record.data, record.length), (a.data, a.length));
+            assert_eq!(bf.decompress_value::<Vec<u8>>(record).unwrap(), vendored);
+        }
+        assert_ne!(file_record(&bf, "c.bin").data, a.data);
+        let e = file_record(&bf, "e.bin");
+        assert_eq!(e.data.get(), data_end);
+        assert_eq!(bf.decompress_value::<Vec<u8>>(e).unwrap(), other);
+
+        let small = file_record(&bf, "small2.txt");
+        assert_eq!(small.data, file_record(&bf, "small1.txt").data);
+        assert_eq!(bf.decompress_value::<Vec<u8>>(small).unwrap(), b"tiny");
+    }
+
+    #[test]
+    fn content_defined_chunks() {
+        let filename = &test_box("content_defined_chunks");
+
+        // Incompressible data, so that only deduplicated chunks save space
+        let mut state = 1u32;
+        let v1 = (0..200_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect::<Vec<_>>();
+        let mut v2 = v1.clone();
+        v2.splice(100_000..100_000, b"a few more bytes".iter().copied());
+
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            let chunk_size = std::num::NonZeroU32::new(4096);
+            let err = bf.set_chunk_size(chunk_size).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+            bf.set_hash_algorithm(HashAlgorithm::Sha256).unwrap();
+            bf.set_password("hunter2").unwrap();
+            bf.set_encrypted_metadata(true);
+            bf.set_chunk_size(chunk_size).unwrap();
+            insert_bytes(&mut bf, "v1.bin", &v1);
+            insert_bytes(&mut bf, "v2.bin", &v2);
+            bf.finish().unwrap();
+        }
+        let size = std::fs::metadata(filename).unwrap().len();
+        assert!(size < (v1.len() + v2.len()) as u64 * 3 / 4);
+
+        // Chunks already in the archive are matched when appending
+        {
+            let mut bf = BoxFileWriter::open_with_password(filename, "hunter2").unwrap();
+            bf.set_chunk_size(std::num::NonZeroU32::new(4096)).unwrap();
+            insert_bytes(&mut bf, "v3.bin", &v2);
+            bf.finish().unwrap();
+        }
+        assert!(std::fs::metadata(filename).unwrap().len() < size + 8192);
+
+        let bf = BoxFileReader::open_with_password(filename, "hunter2").unwrap();
+        for (name, data) in [("v1.bin", &v1), ("v2.bin", &v2), ("v3.bin", &v2)].iter() {
+            let record = file_record(&bf, name);
+            assert!(record.attr(bf.metadata(), CHUNKS_ATTR).is_some());
+            assert_eq!(&bf.decompress_value::<Vec<u8>>(record).unwrap(), *data);
+            bf.verify(record).unwrap();
+            assert_eq!(
+                bf.read_at(record, 99_000, 3000).unwrap(),
+                &data[99_000..102_000]
+            );
+        }
+
+        // Chunk digests are keyed, as they would otherwise identify the plaintext
+        let mut offset = 0;
+        for chunk in bf.chunks.iter() {
+            let end = offset + chunk.decompressed_length as usize;
+            if end > v1.len() {
+                break;
+            }
+            let mut hasher = HashAlgorithm::Sha256.hasher().unwrap();
+            hasher.update(&v1[offset..end]);
+            assert_ne!(chunk.digest, hasher.finalize());
+            offset = end;
+        }
+        assert_eq!(offset, v1.len());
+    }
+
+    #[test]
+    fn hard_links() {
+        let filename = &test_box("hard_links");
+
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("original.txt").unwrap(),
+                &mut std::io::Cursor::new(b"shared".to_vec()),
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.mkdir(BoxPath::new("dir").unwrap(), HashMap::new())
+                .unwrap();
+            bf.hard_link(
+                BoxPath::new("dir/linked.txt").unwrap(),
+                &BoxPath::new("original.txt").unwrap(),
+                HashMap::new(),
+            )
+            .unwrap();
+
+            let err = bf
+                .hard_link(
+                    BoxPath::new("bad.txt").unwrap(),
+                    &BoxPath::new("dir").unwrap(),
+                    HashMap::new(),
+                )
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        let link = bf
+            .metadata()
+            .inode(&BoxPath::new("dir/linked.txt").unwrap())
+            .and_then(|x| bf.metadata().record(x))
+            .and_then(Record::as_hard_link)
+            .unwrap();
+        let target = bf.resolve_hard_link(link).unwrap();
+        assert_eq!(target.path, BoxPath::new("original.txt").unwrap());
+        assert!(target.record.as_file().is_some());
+        assert_eq!(bf.link_targets.len(), 1);
+
+        let output = test_dir("hard_links_out");
+        bf.extract_all(&output).unwrap();
+        assert_eq!(
+            std::fs::read(output.join("dir").join("linked.txt")).unwrap(),
+            b"shared"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let original = std::fs::metadata(output.join("original.txt")).unwrap();
+            assert_eq!(original.nlink(), 2);
+        }
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn special_files() {
+        let filename = &test_box("special_files");
+
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            let mut attrs = HashMap::new();
+            attrs.insert(MODE_ATTR.to_string(), 0o10600u32.to_le_bytes().to_vec());
+            bf.mknod(
+                BoxPath::new("pipe").unwrap(),
+                SpecialFileType::Fifo,
+                0,
+                0,
+                attrs,
+            )
+            .unwrap();
+            bf.mknod(
+                BoxPath::new("null").unwrap(),
+                SpecialFileType::CharDevice,
+                1,
+                3,
+                HashMap::new(),
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        let record = |name: &str| {
+            bf.metadata()
+                .inode(&BoxPath::new(name).unwrap())
+                .and_then(|x| bf.metadata().record(x))
+                .and_then(Record::as_special)
+                .unwrap()
+        };
+        assert_eq!(record("pipe").file_type, SpecialFileType::Fifo);
+        assert_eq!(record("pipe").mode(bf.metadata()), Some(0o10600));
+        let null = record("null");
+        assert_eq!(null.file_type, SpecialFileType::CharDevice);
+        assert_eq!((null.major, null.minor), (1, 3));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+            let output = test_dir("special_files_out");
+            bf.extract(&BoxPath::new("pipe").unwrap(), &output).unwrap();
+            let meta = std::fs::symlink_metadata(output.join("pipe")).unwrap();
+            assert!(meta.file_type().is_fifo());
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+            std::fs::remove_dir_all(&output).unwrap();
+        }
+    }
+
+    #[test]
+    fn sparse_files() {
+        let filename = &test_box("sparse_files");
+
+        let dir = test_dir("sparse_files_files");
+
+        // A hole, data, and another hole to the end of the file
+        let source = dir.join("source.img");
+        let mut expected = vec![0u8; 8 << 20];
+        {
+            let mut file = std::fs::File::create(&source).unwrap();
+            file.set_len(expected.len() as u64).unwrap();
+            file.seek(std::io::SeekFrom::Start(4 << 20)).unwrap();
+            file.write_all(&[7u8; 10_000]).unwrap();
+        }
+        expected[4 << 20..(4 << 20) + 10_000].copy_from_slice(&[7u8; 10_000]);
+        let dense = dir.join("dense.txt");
+        std::fs::write(&dense, b"no holes").unwrap();
+
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            for (name, path) in [("disk.img", &source), ("dense.txt", &dense)].iter() {
+                let mut file = std::fs::File::open(path).unwrap();
+                bf.insert_sparse(
+                    Compression::Stored,
+                    BoxPath::new(name).unwrap(),
+                    &mut file,
+                    HashMap::new(),
+                )
+                .unwrap();
+            }
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        let record = file_record(&bf, "disk.img");
+        #[cfg(target_os = "linux")]
+        {
+            assert!(record.attr(bf.metadata(), SPARSE_ATTR).is_some());
+            assert!(record.decompressed_length < expected.len() as u64);
+        }
+        assert_eq!(bf.file_length(record).unwrap(), expected.len() as u64);
+        assert_eq!(bf.decompress_value::<Vec<u8>>(record).unwrap(), expected);
+        let start = (4 << 20) - 100;
+        assert_eq!(
+            bf.read_at(record, start as u64, 200).unwrap(),
+            &expected[start..start + 200]
+        );
+
+        let dense = file_record(&bf, "dense.txt");
+        assert!(dense.attr(bf.metadata(), SPARSE_ATTR).is_none());
+        assert_eq!(bf.decompress_value::<Vec<u8>>(dense).unwrap(), b"no holes");
+
+        let output = dir.join("out");
+        std::fs::create_dir_all(&output).unwrap();
+        bf.extract_all(&output).unwrap();
+        let extracted = output.join("disk.img");
+        assert_eq!(std::fs::read(&extracted).unwrap(), expected);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let meta = std::fs::metadata(&extracted).unwrap();
+            assert!(meta.blocks() * 512 < meta.len());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn xattrs() {
+        let filename = &test_box("xattrs");
+
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            let mut attrs = HashMap::new();
+            attrs.insert("xattr.user.comment".to_string(), b"hello".to_vec());
+            attrs.insert("xattr.user.empty".to_string(), vec![]);
+            attrs.insert("unix.mode".to_string(), 0o644u32.to_le_bytes().to_vec());
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("file.txt").unwrap(),
+                &mut std::io::Cursor::new(b"data".to_vec()),
+                attrs,
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        let record = bf
+            .metadata()
+            .inode(&BoxPath::new("file.txt").unwrap())
+            .and_then(|x| bf.metadata().record(x))
+            .unwrap();
+        assert_eq!(
+            record.xattrs(bf.metadata()),
+            vec![("user.comment", &b"hello"[..]), ("user.empty", &b""[..])]
+        );
+
+        #[cfg(target_os = "linux")]
+        {
+            let output = test_dir("xattrs_out");
+            bf.extract_all(&output).unwrap();
+            let xattrs = read_xattrs(output.join("file.txt")).unwrap();
+            assert_eq!(xattrs.get("xattr.user.comment").unwrap(), b"hello");
+            assert_eq!(xattrs.get("xattr.user.empty").unwrap(), b"");
+            std::fs::remove_dir_all(&output).unwrap();
+        }
+    }
+
+    #[test]
+    fn acls() {
+        let filename = &test_box("acls");
+
+        // user::rw-, user:1000:r--, group::r--, mask::r--, other::r--
+        let acl = [
+            [1u8, 6, 0, 0, 0, 0],
+            [2, 4, 0xe8, 0x03, 0, 0],
+            [3, 4, 0, 0, 0, 0],
+            [5, 4, 0, 0, 0, 0],
+            [6, 4, 0, 0, 0, 0],
+        ]
+        .concat();
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            let mut attrs = HashMap::new();
+            attrs.insert(ACL_ATTR.to_string(), acl.clone());
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("file.txt").unwrap(),
+                &mut std::io::Cursor::new(b"data".to_vec()),
+                attrs,
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert_eq!(
+            bf.metadata()
+                .attr(&BoxPath::new("file.txt").unwrap(), ACL_ATTR)
+                .unwrap(),
+            &acl[..]
+        );
+
+        #[cfg(target_os = "linux")]
+        {
+            let output = test_dir("acls_out");
+            bf.extract_all(&output).unwrap();
+            let acls = read_acls(output.join("file.txt")).unwrap();
+            assert_eq!(acls.get(ACL_ATTR).unwrap(), &acl);
+            assert!(!acls.contains_key(DEFAULT_ACL_ATTR));
+            assert!(read_xattrs(output.join("file.txt")).unwrap().is_empty());
+            std::fs::remove_dir_all(&output).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_metadata() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let filename = &test_box("restore_metadata");
+
+        let attrs = |mode: u32, modified: i64| {
+            let mut attrs = HashMap::new();
+            attrs.insert("unix.mode".to_string(), mode.to_le_bytes().to_vec());
+            attrs.insert("modified".to_string(), modified.to_le_bytes().to_vec());
+            attrs
+        };
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            bf.mkdir(BoxPath::new("dir").unwrap(), attrs(0o40750, 1_000_000_000))
+                .unwrap();
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("dir/run.sh").unwrap(),
+                &mut std::io::Cursor::new(b"#!/bin/sh\n".to_vec()),
+                attrs(0o100755, 1_500_000_000),
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let output = test_dir("restore_metadata_out");
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        bf.extract_all(&output).unwrap();
+
+        let file = std::fs::metadata(output.join("dir/run.sh")).unwrap();
+        assert_eq!(file.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(file.mtime(), 1_500_000_000);
+
+        // Writing the file into the directory must not have changed its time
+        let dir = std::fs::metadata(output.join("dir")).unwrap();
+        assert_eq!(dir.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(dir.mtime(), 1_000_000_000);
+
+        std::fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn timestamps() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let time = Timestamp::new(1_600_000_000, 123_456_789);
+        assert_eq!(time.to_bytes().len(), 12);
+        assert_eq!(Timestamp::from_bytes(&time.to_bytes()), Some(time));
+
+        // Seconds alone, as archives made before nanoseconds were recorded have them
+        assert_eq!(
+            Timestamp::from_bytes(&1_600_000_000i64.to_le_bytes()),
+            Some(Timestamp::new(1_600_000_000, 0))
+        );
+        assert_eq!(Timestamp::from_bytes(&[0; 4]), None);
+        assert_eq!(
+            Timestamp::from_bytes(&Timestamp::new(0, 0).to_bytes()[..8]),
+            Some(Timestamp::new(0, 0))
+        );
+
+        let before = UNIX_EPOCH - Duration::new(1, 250_000_000);
+        assert_eq!(Timestamp::from(before), Timestamp::new(-2, 750_000_000));
+        assert_eq!(Timestamp::from(before).to_system_time(), Some(before));
+        assert_eq!(
+            time.to_system_time(),
+            Some(UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789))
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let filename = &test_box("timestamps");
+            {
+                let mut bf = BoxFileWriter::create(filename).unwrap();
+                let mut attrs = HashMap::new();
+                attrs.insert(MODIFIED_ATTR.to_string(), time.to_bytes());
+                bf.insert(
+                    Compression::Stored,
+                    BoxPath::new("file.txt").unwrap(),
+                    &mut std::io::Cursor::new(b"data".to_vec()),
+                    attrs,
+                )
+                .unwrap();
+                bf.finish().unwrap();
+            }
+
+            let output = test_dir("timestamps_out");
+            BoxFileReader::open(filename)
+                .unwrap()
+                .extract_all(&output)
+                .unwrap();
+
+            let meta = std::fs::metadata(output.join("file.txt")).unwrap();
+            assert_eq!(meta.mtime(), time.secs);
+            assert_eq!(meta.mtime_nsec(), time.nanos as i64);
+            std::fs::remove_dir_all(&output).unwrap();
+        }
+    }
+
+    #[test]
+    fn attr_values() {
+        assert_eq!(AttrType::of_key(MODE_ATTR), AttrType::U32);
+        assert_eq!(AttrType::of_key(MODIFIED_ATTR), AttrType::Timestamp);
+        assert_eq!(AttrType::of_key(USER_ATTR), AttrType::String);
+        assert_eq!(AttrType::of_key("xattr.user.comment"), AttrType::Bytes);
+
+        for value in &[
+            AttrValue::U32(0o100644),
+            AttrValue::U64(u64::MAX),
+            AttrValue::I64(-1),
+            AttrValue::Timestamp(Timestamp::new(1_600_000_000, 5)),
+            AttrValue::String("root".into()),
+            AttrValue::Bytes(vec![0, 1, 2]),
+        ] {
+            assert_eq!(
+                AttrValue::from_bytes(value.attr_type(), &value.to_bytes()).as_ref(),
+                Some(value)
+            );
+        }
+
+        assert_eq!(
+            AttrValue::from_bytes(AttrType::U32, &0o644u16.to_le_bytes()),
+            None
+        );
+        assert_eq!(AttrValue::from_bytes(AttrType::U64, &[0; 4]), None);
+        assert_eq!(AttrValue::from_bytes(AttrType::String, &[0xff]), None);
+
+        let filename = &test_box("attr_values");
+        {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            let mut attrs = HashMap::new();
+            attrs.insert(MODE_ATTR.to_string(), 0o100755u32.to_le_bytes().to_vec());
+            attrs.insert(
+                MODIFIED_ATTR.to_string(),
+                Timestamp::new(1_600_000_000, 5).to_bytes(),
+            );
+            attrs.insert(USER_ATTR.to_string(), b"root".to_vec());
+            // Too short to be a uid
+            attrs.insert(UID_ATTR.to_string(), vec![0; 2]);
+            bf.insert(
+                Compression::Stored,
+                BoxPath::new("file.txt").unwrap(),
+                &mut std::io::Cursor::new(b"data".to_vec()),
+                attrs,
+            )
+            .unwrap();
+            bf.finish().unwrap();
+        }
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        let meta = bf.metadata();
+        let record = meta
+            .record(meta.inode(&BoxPath::new("file.txt").unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(record.mode(meta), Some(0o100755));
+        assert_eq!(
+            record.modified(meta),
+            Some(Timestamp::new(1_600_000_000, 5))
+        );
+        assert_eq!(record.user(meta), Some("root"));
+        assert_eq!(record.uid(meta), None);
+        assert_eq!(record.accessed(meta), None);
+        assert_eq!(
+            record.attr_value(meta, USER_ATTR),
+            Some(AttrValue::String("root".into()))
+        );
+    }
+
+    #[test]
+    fn attr_sets() {
+        let dir = test_dir("attr_sets");
+        let shared = || {
+            let mut attrs = HashMap::new();
+            attrs.insert(MODE_ATTR.to_string(), 0o100644u32.to_le_bytes().to_vec());
+            attrs.insert(UID_ATTR.to_string(), 1000u32.to_le_bytes().to_vec());
+            attrs.insert(GID_ATTR.to_string(), 1000u32.to_le_bytes().to_vec());
+            attrs.insert(USER_ATTR.to_string(), b"user".to_vec());
+            attrs.insert(GROUP_ATTR.to_string(), b"users".to_vec());
+            attrs
+        };
+        let create = |filename: &Path, with_attrs: bool| {
+            let mut bf = BoxFileWriter::create(filename).unwrap();
+            for i in 0..200u64 {
+                let mut attrs = if with_attrs { shared() } else { HashMap::new() };
+                if i == 0 {
+                    attrs.insert(MODE_ATTR.to_string(), 0o100755u32.to_le_bytes().to_vec());
+                }
+                attrs.insert(
+                    MODIFIED_ATTR.to_string(),
+                    Timestamp::new(i as i64, 0).to_bytes(),
+                );
+                bf.insert(
+                    Compression::Stored,
+                    BoxPath::new(format!("{}.txt", i)).unwrap(),
+                    &mut std::io::Cursor::new(vec![]),
+                    attrs,
+                )
+                .unwrap();
+            }
+            bf.finish().unwrap()
+        };
+
+        // Inline, the shared attributes would take at least 6 bytes each per record
+        let with = create(&dir.join("attr_sets.box"), true);
+        let without = create(&dir.join("attr_sets_none.box"), false);
+        assert!(with - without < 200 * 8, "{} - {}", with, without);
+
+        let bf = BoxFileReader::open(dir.join("attr_sets.box")).unwrap();
+        let meta = bf.metadata();
+        assert_eq!(meta.file_attr(ATTR_SETS_ATTR), None);
+        for i in 0..200u64 {
+            let path = BoxPath::new(format!("{}.txt", i)).unwrap();
+            let record = meta.record(meta.inode(&path).unwrap()).unwrap();
+            let mode = if i == 0 { 0o100755 } else { 0o100644 };
+            assert_eq!(record.mode(meta), Some(mode));
+            assert_eq!(record.user(meta), Some("user"));
+            assert_eq!(record.gid(meta), Some(1000));
+            assert_eq!(record.modified(meta), Some(Timestamp::new(i as i64, 0)));
+            assert_eq!(meta.attr(&path, ATTR_SET_ATTR), None);
+        }
+    }
+
+    #[test]
+    fn verify_strong_hash() {
+        let filename = &test_box("verify_strong_hash");
+
+        for algorithm in &[HashAlgorithm::Blake3, HashAlgorithm::Sha256] {
+            let _ = std::fs::remove_file(filename);
+            {
+                let mut bf = BoxFileWriter::create(filename).unwrap();
+                bf.set_hash_algorithm(*algorithm).unwrap();
+                bf.insert(
+                    Compression::Stored,
+                    BoxPath::new("hello.txt").unwrap(),
+                    &mut std::io::Cursor::new(b"hello\0\0\0".to_vec()),
+                    HashMap::new(),
+                )
+                .unwrap();
+                bf.finish().unwrap();
+            }
+
+            let bf = BoxFileReader::open(filename).unwrap();
+            assert_eq!(bf.metadata().hash_algorithm().unwrap(), Some(*algorithm));
+            let record = bf.meta.inodes[0].as_file().unwrap();
+            assert_eq!(record.attr(bf.metadata(), HASH_ATTR).unwrap().len(), 32);
+            bf.verify(record).unwrap();
+
+            // A record whose digest does not match its data must fail verification
+            let mut bf = bf;
+            let key = bf.meta.attr_key(HASH_ATTR).unwrap();
+            bf.meta.inodes[0]
+                .as_file_mut()
+                .unwrap()
+                .attrs
+                .insert(key, vec![0; 32]);
+            let record = bf.meta.inodes[0].as_file().unwrap();
+            assert!(bf.verify(record).is_err());
+        }
+    }
+
+    /// Writes a single file as version 0 of the format did, with a 24-byte header and records
+    /// that are not framed with their length. Its CRC32 is wrong, as it was then.
+    fn create_v0_box<F: AsRef<Path>>(filename: F, name: &str, data: &[u8]) {
+        use crate::ser::Serialize;
+        use byteorder::{LittleEndian, WriteBytesExt};
+        use fastvlq::WriteVu64Ext;
+
+        let mut meta = BoxMetadata::default();
+        let mut attrs = HashMap::new();
+        attrs.insert(meta.attr_key_or_create(CRC32_ATTR), vec![1, 2, 3, 4]);
+        let record = FileRecord {
+            compression: Compression::Stored,
+            length: data.len() as u64,
+            decompressed_length: data.len() as u64,
+            data: std::num::NonZeroU64::new(24).unwrap(),
+            name: name.to_string(),
+            attrs,
+        };
+
+        let mut out = Cursor::new(vec![]);
+        out.write_all(b"\xffBOX").unwrap();
+        out.write_u32::<LittleEndian>(0).unwrap();
+        out.write_u64::<LittleEndian>(0).unwrap();
+        out.write_u64::<LittleEndian>(24 + data.len() as u64)
+            .unwrap();
+        out.write_all(data).unwrap();
+
+        vec![Inode::new(1).unwrap()].write(&mut out).unwrap();
+        out.write_vu64(1).unwrap();
+        out.write_u8(0x0).unwrap();
+        record.write(&mut out).unwrap();
+        meta.attr_keys.write(&mut out).unwrap();
+        meta.attrs.write(&mut out).unwrap();
+        let mut index = pathtrie::PathTrie::new();
+        index.insert(BoxPath::new(name).unwrap(), 1);
+        index.write_fst(&mut out).unwrap();
+
+        std::fs::write(filename, out.into_inner()).unwrap();
+    }
+
+    #[test]
+    fn read_v0_archive() {
+        let filename = &test_box("read_v0_archive");
+        create_v0_box(filename, "hello.txt", b"hello");
+
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert_eq!(bf.version(), 0);
+        assert_eq!(bf.header.flags, 0);
+        assert_eq!(bf.header.trailer_checksum, None);
+        let record = file_record(&bf, "hello.txt");
+        assert_eq!(bf.decompress_value::<Vec<u8>>(record).unwrap(), b"hello");
+        bf.verify(record).unwrap();
+
+        // The header cannot grow without overwriting the data after it
+        assert!(BoxFileWriter::open(filename).is_err());
+
+        // Newer versions are refused rather than misread
+        let mut bytes = std::fs::read(filename).unwrap();
+        bytes[4] = 2;
+        std::fs::write(filename, bytes).unwrap();
+        let err = BoxFileReader::open(filename).unwrap_err();
+        assert!(err.to_string().contains("unsupported archive version"));
+
+        create_test_box(filename);
+        let bf = BoxFileReader::open(filename).unwrap();
+        assert_eq!(bf.version(), 1);
+    }
+
     #[test]
     fn read_index() {
         insert_impl("./read_index.box", |n| BoxFileWriter::create(n).unwrap());
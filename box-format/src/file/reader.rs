@@ -1,19 +1,111 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::fs::{self, OpenOptions};
 use std::io::{self, prelude::*, BufReader, BufWriter, SeekFrom};
 use std::num::NonZeroU64;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use comde::Decompress;
 use memmap::MmapOptions;
 
-use super::{meta::RecordsItem, BoxMetadata};
+use super::{meta::RecordsItem, BoxMetadata, Inode};
+#[cfg(feature = "encryption")]
+use crate::encryption::{ContentKey, DecryptReader, Identity, NONCE_PREFIX_LEN};
+#[cfg(feature = "signature")]
+use crate::signature::{self, Keypair, PublicKey, Signature, SIGNATURE_ATTR};
 use crate::{
+    acl::{self, ACL_ATTR, DEFAULT_ACL_ATTR},
+    blocks::{BlockTable, BLOCKS_ATTR},
+    checksum::{self, ChecksumWriter, Checksums, Hasher, KEYED_HASH_ATTR},
+    chunks::{read_chunk_list, read_chunk_table, Chunk, CHUNKS_ATTR},
+    compression::Compression,
     de::DeserializeOwned,
+    encryption::{self, ENCRYPTION_ATTR, ENCRYPTION_NONCE_ATTR},
     header::BoxHeader,
+    inline::INLINE_ATTR,
     path::BoxPath,
-    record::{FileRecord, LinkRecord, Record},
+    record::{FileRecord, HardLinkRecord, LinkRecord, Record, SpecialRecord},
+    solid::{BlockCache, SOLID_ATTR},
+    sparse::{self, SparseMap, SparseWriter, SPARSE_ATTR},
+    xattr,
 };
+#[cfg(unix)]
+use crate::{owner, time::Timestamp};
+
+/// Keeps the bytes written to it that fall within a range of a decompressed file, given the
+/// offset in the file of the next byte written.
+struct RangeWriter {
+    range: Range<u64>,
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl RangeWriter {
+    fn new(range: Range<u64>) -> RangeWriter {
+        RangeWriter {
+            pos: 0,
+            buf: Vec::with_capacity((range.end - range.start) as usize),
+            range,
+        }
+    }
+}
+
+impl Write for RangeWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let start = self.pos;
+        let end = start + data.len() as u64;
+        let (from, to) = (self.range.start.max(start), self.range.end.min(end));
+        if from < to {
+            self.buf
+                .extend_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+        self.pos = end;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The largest trailer and index, once decompressed or decrypted, that will be loaded, so
+/// that a small compressed trailer cannot exhaust memory.
+pub(crate) const MAX_TRAILER_SIZE: u64 = 1 << 30;
+
+/// Keeps the bytes written to it, failing once more than `limit` bytes have been written.
+pub(crate) struct LimitWriter {
+    limit: u64,
+    pub(crate) buf: Vec<u8>,
+}
+
+impl LimitWriter {
+    pub(crate) fn new(limit: u64) -> LimitWriter {
+        LimitWriter { limit, buf: vec![] }
+    }
+}
+
+impl Write for LimitWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if (self.buf.len() + data.len()) as u64 > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("metadata larger than {} bytes", self.limit),
+            ));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A decompressed solid block and the range of one file within it.
+type SolidData = (Arc<Vec<u8>>, Range<usize>);
 
 #[derive(Debug)]
 pub struct BoxFileReader {
@@ -22,6 +114,15 @@ pub struct BoxFileReader {
     pub(crate) header: BoxHeader,
     pub(crate) meta: BoxMetadata,
     pub(crate) offset: u64,
+    pub(crate) verify_checksums: bool,
+    pub(crate) same_owner: bool,
+    pub(crate) numeric_owner: bool,
+    pub(crate) solid_cache: Mutex<BlockCache>,
+    pub(crate) chunks: Vec<Chunk>,
+    /// The path of each file that a hard link points to.
+    pub(crate) link_targets: HashMap<Inode, BoxPath>,
+    #[cfg(feature = "encryption")]
+    pub(crate) content_key: Option<ContentKey>,
 }
 
 #[inline(always)]
@@ -30,19 +131,55 @@ pub(super) fn read_header<R: Read + Seek>(file: &mut R, offset: u64) -> io::Resu
     BoxHeader::deserialize_owned(file)
 }
 
+/// Maps the serialized trailer and index, which run to the end of the file.
+#[inline(always)]
+pub(super) unsafe fn map_trailer(
+    file: &File,
+    ptr: NonZeroU64,
+    offset: u64,
+) -> io::Result<memmap::Mmap> {
+    MmapOptions::new().offset(offset + ptr.get()).map(file)
+}
+
 #[inline(always)]
 pub(super) fn read_trailer<R: Read + Seek, P: AsRef<Path>>(
     reader: &mut R,
+    header: &BoxHeader,
     ptr: NonZeroU64,
     path: P,
     offset: u64,
 ) -> io::Result<BoxMetadata> {
+    // Check the trailer and index against the header before trying to make sense of them,
+    // which version 0 archives have no checksum for
+    let file = File::open(path.as_ref())?;
+    let trailer_mmap = unsafe { map_trailer(&file, ptr, offset)? };
+    if let Some(expected) = header.trailer_checksum {
+        let checksum = crc32fast::hash(&trailer_mmap);
+        if checksum != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "metadata corrupted: trailer checksum mismatch (expected {:08x}, got {:08x})",
+                    expected, checksum
+                ),
+            ));
+        }
+    }
+
+    if header.has_encrypted_metadata() {
+        // No path is visible until the archive is unlocked with the content key.
+        return encryption::read_metadata_envelope(&mut &*trailer_mmap);
+    }
+
+    if header.has_compressed_trailer() {
+        return load_trailer(&decompress_trailer(&trailer_mmap)?);
+    }
+
     reader.seek(SeekFrom::Start(offset + ptr.get()))?;
     let mut meta = BoxMetadata::deserialize_owned(reader)?;
 
     // Load index if exists
     let offset = reader.seek(SeekFrom::Current(0))?;
-    let file = File::open(path.as_ref())?;
     let fst_mmap = unsafe { memmap::MmapOptions::new().offset(offset).map(&file)? };
     let index = pathtrie::fst::Fst::new(fst_mmap).ok();
     meta.index = index;
@@ -50,6 +187,43 @@ pub(super) fn read_trailer<R: Read + Seek, P: AsRef<Path>>(
     Ok(meta)
 }
 
+/// Decompresses a compressed trailer, which starts with the compression used, up to
+/// `MAX_TRAILER_SIZE` bytes.
+pub(super) fn decompress_trailer(compressed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader = compressed;
+    let compression = Compression::deserialize_owned(&mut reader)?;
+    let mut trailer = LimitWriter::new(MAX_TRAILER_SIZE);
+    compression.decompress_write(reader, &mut trailer)?;
+    Ok(trailer.buf)
+}
+
+/// Decrypts a sealed trailer and loads it along with its index.
+#[cfg(feature = "encryption")]
+pub(super) fn read_sealed_trailer(sealed: &[u8], key: &ContentKey) -> io::Result<BoxMetadata> {
+    load_trailer(&encryption::open_metadata(sealed, key)?)
+}
+
+/// Loads a trailer along with its index from memory, as it is once decrypted or decompressed.
+fn load_trailer(trailer: &[u8]) -> io::Result<BoxMetadata> {
+    let mut cursor = io::Cursor::new(trailer);
+    let mut meta = BoxMetadata::deserialize_owned(&mut cursor)?;
+
+    let index = &trailer[cursor.position() as usize..];
+    if !index.is_empty() {
+        meta.index = pathtrie::fst::Fst::new(map_anon(index)?).ok();
+    }
+
+    Ok(meta)
+}
+
+/// Copies decrypted, decompressed or inline bytes into an anonymous map, for use where the file
+/// would be mapped.
+pub(super) fn map_anon(bytes: &[u8]) -> io::Result<memmap::Mmap> {
+    let mut map = MmapOptions::new().len(bytes.len()).map_anon()?;
+    map.copy_from_slice(bytes);
+    map.make_read_only()
+}
+
 impl BoxFileReader {
     /// This will open an existing `.box` file for reading and writing, and error if the file is not valid.
     pub fn open_at_offset<P: AsRef<Path>>(path: P, offset: u64) -> io::Result<BoxFileReader> {
@@ -65,29 +239,85 @@ impl BoxFileReader {
                     let ptr = header
                         .trailer
                         .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no trailer found"))?;
-                    let meta = read_trailer(&mut reader, ptr, path.as_ref(), offset)?;
+                    let meta = read_trailer(&mut reader, &header, ptr, path.as_ref(), offset)?;
 
                     (header, meta)
                 };
 
-                let f = BoxFileReader {
-                    file: BufReader::new(file),
-                    path: path.as_ref().to_path_buf().canonicalize()?,
-                    header,
-                    meta,
-                    offset,
-                };
-
-                Ok(f)
+                Self::from_parts(file, path.as_ref(), header, meta, offset)
             })?
     }
 
+    /// Makes a reader of an archive from its header and as much of its metadata as is loaded.
+    pub(super) fn from_parts(
+        file: File,
+        path: &Path,
+        header: BoxHeader,
+        meta: BoxMetadata,
+        offset: u64,
+    ) -> io::Result<BoxFileReader> {
+        let chunks = read_chunk_table(&meta)?;
+        let link_targets = meta.hard_link_targets();
+
+        Ok(BoxFileReader {
+            file: BufReader::new(file),
+            path: path.to_path_buf().canonicalize()?,
+            header,
+            meta,
+            offset,
+            verify_checksums: true,
+            same_owner: true,
+            numeric_owner: false,
+            solid_cache: Mutex::new(BlockCache::default()),
+            chunks,
+            link_targets,
+            #[cfg(feature = "encryption")]
+            content_key: None,
+        })
+    }
+
     /// This will open an existing `.box` file for reading and writing, and error if the file is not valid.
     #[inline]
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<BoxFileReader> {
         Self::open_at_offset(path, 0)
     }
 
+    /// This will open an existing encrypted `.box` file for reading, and error if `password`
+    /// does not unlock it.
+    #[cfg(feature = "encryption")]
+    pub fn open_with_password<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+    ) -> io::Result<BoxFileReader> {
+        let mut bf = Self::open(path)?;
+        bf.unlock_with_password(password)?;
+        Ok(bf)
+    }
+
+    /// This will open an existing encrypted `.box` file for reading, and error if `identity`
+    /// is not one of its recipients.
+    #[cfg(feature = "encryption")]
+    pub fn open_with_identity<P: AsRef<Path>>(
+        path: P,
+        identity: &Identity,
+    ) -> io::Result<BoxFileReader> {
+        let mut bf = Self::open(path)?;
+        bf.unlock_with_identity(identity)?;
+        Ok(bf)
+    }
+
+    /// This will open an existing `.box` file for reading, and error unless it carries a valid
+    /// embedded signature made with the secret key belonging to `public_key`.
+    #[cfg(feature = "signature")]
+    pub fn open_signed<P: AsRef<Path>>(
+        path: P,
+        public_key: &PublicKey,
+    ) -> io::Result<BoxFileReader> {
+        let bf = Self::open(path)?;
+        bf.verify_signature(public_key)?;
+        Ok(bf)
+    }
+
     #[inline(always)]
     pub fn path(&self) -> &Path {
         &self.path
@@ -108,18 +338,490 @@ impl BoxFileReader {
         &self.meta
     }
 
+    /// Whether the archive contains encrypted file data.
+    #[inline(always)]
+    pub fn is_encrypted(&self) -> bool {
+        self.meta.file_attr(ENCRYPTION_ATTR).is_some()
+    }
+
+    /// Whether the trailer and index of the archive are encrypted. Until such an archive is
+    /// unlocked, its metadata holds no records and only the attributes that lock it.
+    #[inline(always)]
+    pub fn has_encrypted_metadata(&self) -> bool {
+        self.header.has_encrypted_metadata()
+    }
+
+    /// Recovers the key of an encrypted archive from its password, so that encrypted files
+    /// and metadata can be read. Returns a `PermissionDenied` error if the password is incorrect.
+    #[cfg(feature = "encryption")]
+    pub fn unlock_with_password(&mut self, password: &str) -> io::Result<()> {
+        let key = encryption::unlock_with_password(&self.meta, password)?;
+        self.unlock(key)
+    }
+
+    /// Recovers the key of an encrypted archive from the identity of one of its recipients,
+    /// so that encrypted files and metadata can be read. Returns a `PermissionDenied` error if
+    /// `identity` is not a recipient.
+    #[cfg(feature = "encryption")]
+    pub fn unlock_with_identity(&mut self, identity: &Identity) -> io::Result<()> {
+        let key = encryption::unlock_with_identity(&self.meta, identity)?;
+        self.unlock(key)
+    }
+
+    #[cfg(feature = "encryption")]
+    fn unlock(&mut self, key: ContentKey) -> io::Result<()> {
+        if self.header.has_encrypted_metadata() && self.content_key.is_none() {
+            let sealed = self.map_raw_trailer()?;
+            self.meta = read_sealed_trailer(&sealed, &key)?;
+            self.chunks = read_chunk_table(&self.meta)?;
+            self.link_targets = self.meta.hard_link_targets();
+        }
+
+        self.content_key = Some(key);
+        Ok(())
+    }
+
+    /// Whether decompressed data is checked against the recorded CRC32, length and, if the
+    /// archive records a hash algorithm, digest of each file.
+    #[inline(always)]
+    pub fn verify_checksums(&self) -> bool {
+        self.verify_checksums
+    }
+
+    /// Enables or disables checking decompressed data against the recorded CRC32, length and
+    /// digest of each file. This is enabled by default.
+    #[inline(always)]
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+    }
+
+    /// Whether extracted files are given the owner and group they were recorded with, which is
+    /// only done when running as root. This is enabled by default.
+    #[inline(always)]
+    pub fn set_same_owner(&mut self, same_owner: bool) {
+        self.same_owner = same_owner;
+    }
+
+    /// Whether extracted files are given the recorded uid and gid as they are, rather than
+    /// those of the users and groups with the recorded names on this system. This is disabled
+    /// by default, and ids are still used for names that are not found.
+    #[inline(always)]
+    pub fn set_numeric_owner(&mut self, numeric_owner: bool) {
+        self.numeric_owner = numeric_owner;
+    }
+
     #[inline(always)]
     pub fn decompress_value<V: Decompress>(&self, record: &FileRecord) -> io::Result<V> {
-        let mmap = unsafe { self.memory_map(record)? };
-        record.compression.decompress(io::Cursor::new(mmap))
+        // The recorded length is not to be trusted before the data has been checked against it
+        let mut buf = Vec::with_capacity(record.decompressed_length.min(1 << 20) as usize);
+        self.decompress(record, &mut buf)?;
+        Compression::Stored.decompress(io::Cursor::new(buf))
     }
 
+    /// Decompresses the data of the given record into `dest`, filling the holes of a sparse
+    /// file with zeros. When checksum verification is enabled, an `InvalidData` error is
+    /// returned after writing if the output does not match the recorded CRC32 or length.
     #[inline(always)]
     pub fn decompress<W: Write>(&self, record: &FileRecord, dest: W) -> io::Result<()> {
-        let mmap = unsafe { self.memory_map(record)? };
+        match self.sparse_map(record)? {
+            Some(map) => {
+                let mut writer = SparseWriter::new(dest, map, sparse::write_zeros);
+                self.decompress_stored(record, &mut writer)?;
+                writer.finish().map(|_| ())
+            }
+            None => self.decompress_stored(record, dest),
+        }
+    }
+
+    /// The length of the file a record stands for, which is more than its decompressed length
+    /// if it is a sparse file.
+    pub fn file_length(&self, record: &FileRecord) -> io::Result<u64> {
+        Ok(self
+            .sparse_map(record)?
+            .map(|map| map.length)
+            .unwrap_or(record.decompressed_length))
+    }
+
+    /// A hasher for the digest of a record, if the archive records a hash algorithm. The digest
+    /// of an encrypted file is keyed, so the archive must be unlocked to check it.
+    fn hasher(&self, record: &FileRecord) -> io::Result<Option<Hasher>> {
+        let algorithm = match self.meta.hash_algorithm()? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if record.attr(&self.meta, KEYED_HASH_ATTR).is_none() {
+            return algorithm.hasher().map(Some);
+        }
+
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(key) = self.content_key.as_ref() {
+                return algorithm.keyed_hasher(&key.hash_key()).map(Some);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("`{}` is encrypted and the archive is locked", record.name),
+        ))
+    }
+
+    /// Checks the checksums of decompressed data against those recorded for the file. Version 0
+    /// archives recorded a CRC32 of the wrong bytes, so theirs is only checked for length.
+    fn check_checksums(&self, record: &FileRecord, checksums: &Checksums) -> io::Result<()> {
+        checksum::verify(&self.meta, record, checksums, self.header.version > 0)
+    }
+
+    /// Decompresses the stored data of a record, which leaves out the holes of a sparse file.
+    fn decompress_stored<W: Write>(&self, record: &FileRecord, dest: W) -> io::Result<()> {
+        if !self.verify_checksums {
+            return self.decompress_unchecked(record, dest);
+        }
+
+        let hasher = self.hasher(record)?;
+        let mut writer = ChecksumWriter::new(dest, hasher);
+        self.decompress_unchecked(record, &mut writer)?;
+        self.check_checksums(record, &writer.finalize())
+    }
+
+    /// Whether `read_at` can read any range of a record without decompressing everything
+    /// before it, which is the case for a record stored in blocks, chunks or a solid block,
+    /// or kept in its record.
+    pub fn is_random_access(&self, record: &FileRecord) -> bool {
+        [BLOCKS_ATTR, CHUNKS_ATTR, SOLID_ATTR, INLINE_ATTR]
+            .iter()
+            .any(|key| record.attr(&self.meta, key).is_some())
+    }
+
+    /// Reads up to `len` decompressed bytes of the given record from `offset`, returning fewer
+    /// at the end of the file. Only the blocks or chunks holding the range are decompressed for
+    /// a record stored in them; otherwise everything before `offset` is decompressed and skipped.
+    /// The bytes read are not checked against the checksums of the record.
+    pub fn read_at(&self, record: &FileRecord, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let map = match self.sparse_map(record)? {
+            Some(v) => v,
+            None => return self.read_stored_at(record, offset, len),
+        };
+
+        let end = offset.saturating_add(len as u64).min(map.length);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        // Holes read as zeros, and each extent in the range is read from the stored data.
+        let mut buf = vec![0u8; (end - offset) as usize];
+        let mut stored = 0;
+        for (extent, length) in map.extents {
+            let (from, to) = (offset.max(extent), end.min(extent + length));
+            if from < to {
+                let data =
+                    self.read_stored_at(record, stored + (from - extent), (to - from) as usize)?;
+                let start = (from - offset) as usize;
+                buf[start..start + data.len()].copy_from_slice(&data);
+            }
+            stored += length;
+        }
+
+        Ok(buf)
+    }
+
+    fn read_stored_at(&self, record: &FileRecord, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset
+            .saturating_add(len as u64)
+            .min(record.decompressed_length);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        if let Some((block, range)) = self.solid_data(record)? {
+            let start = range.start + offset as usize;
+            return Ok(block[start..range.start + end as usize].to_vec());
+        }
+
+        let mut writer = RangeWriter::new(offset..end);
+
+        if let Some(data) = self.inline_data(record) {
+            record.compression.decompress_write(data, &mut writer)?;
+            return Ok(writer.buf);
+        }
+
+        if let Some(chunks) = self.record_chunks(record)? {
+            for chunk in chunks {
+                let next = writer.pos + chunk.decompressed_length;
+                if next > offset {
+                    self.decompress_chunk(record, chunk, &mut writer)?;
+                }
+                writer.pos = next;
+                if next >= end {
+                    break;
+                }
+            }
+            return Ok(writer.buf);
+        }
+
+        let data = unsafe { self.memory_map(record)? };
+
+        let blocks = match self.block_table(record)? {
+            Some(v) => v,
+            None => {
+                let reader = self.data_reader(record, &data, record.data.get(), None)?;
+                record.compression.decompress_write(reader, &mut writer)?;
+                return Ok(writer.buf);
+            }
+        };
+
+        let (first, last) = (blocks.block_index(offset), blocks.block_index(end - 1));
+        if last >= blocks.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("block table of `{}` does not cover its data", record.name),
+            ));
+        }
+
+        for index in first..=last {
+            writer.pos = blocks.decompressed_offset(index);
+            let range = blocks.compressed_range(index);
+            let offset = record.data.get() + range.start as u64;
+            let reader = self.data_reader(record, &data[range], offset, Some(index))?;
+            record.compression.decompress_write(reader, &mut writer)?;
+        }
+
+        Ok(writer.buf)
+    }
+
+    /// Decompresses the data of the given record and checks it against its recorded length,
+    /// CRC32 and, if the archive records a hash algorithm, its cryptographic digest.
+    /// This does not depend on whether checksum verification is enabled for `decompress`.
+    pub fn verify(&self, record: &FileRecord) -> io::Result<()> {
+        let hasher = self.hasher(record)?;
+
+        let mut writer = ChecksumWriter::new(io::sink(), hasher);
+        self.decompress_unchecked(record, &mut writer)?;
+        self.check_checksums(record, &writer.finalize())
+    }
+
+    /// Decompresses the data of a record into `dest` without checking it, block by block if
+    /// the record is stored in blocks.
+    fn decompress_unchecked<W: Write>(&self, record: &FileRecord, mut dest: W) -> io::Result<()> {
+        if let Some((block, range)) = self.solid_data(record)? {
+            return dest.write_all(&block[range]);
+        }
+
+        if let Some(data) = self.inline_data(record) {
+            return record.compression.decompress_write(data, dest);
+        }
+
+        if let Some(chunks) = self.record_chunks(record)? {
+            for chunk in chunks {
+                self.decompress_chunk(record, chunk, &mut dest)?;
+            }
+            return Ok(());
+        }
+
+        let blocks = self.block_table(record)?;
+        if blocks.as_ref().map(|x| x.len() == 0).unwrap_or(false) {
+            return Ok(());
+        }
+
+        let data = unsafe { self.memory_map(record)? };
+        match blocks {
+            None => record.compression.decompress_write(
+                self.data_reader(record, &data, record.data.get(), None)?,
+                dest,
+            ),
+            Some(blocks) => {
+                for index in 0..blocks.len() {
+                    let range = blocks.compressed_range(index);
+                    let offset = record.data.get() + range.start as u64;
+                    let reader = self.data_reader(record, &data[range], offset, Some(index))?;
+                    record.compression.decompress_write(reader, &mut dest)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The decompressed solid block of a record and the range of the record within it, if the
+    /// record is stored in a solid block.
+    fn solid_data(&self, record: &FileRecord) -> io::Result<Option<SolidData>> {
+        let offset = match record.attr(&self.meta, SOLID_ATTR) {
+            Some(bytes) => <[u8; 8]>::try_from(bytes)
+                .map(u64::from_le_bytes)
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "malformed solid block offset")
+                })?,
+            None => return Ok(None),
+        };
+
+        let cached = self.solid_cache.lock().unwrap().get(record.data.get());
+        let block = match cached {
+            Some(v) => v,
+            None => {
+                let data = unsafe { self.memory_map(record)? };
+                let mut block = Vec::new();
+                record.compression.decompress_write(
+                    self.data_reader(record, &data, record.data.get(), None)?,
+                    &mut block,
+                )?;
+                let block = Arc::new(block);
+                self.solid_cache
+                    .lock()
+                    .unwrap()
+                    .insert(record.data.get(), block.clone());
+                block
+            }
+        };
+
+        let end = offset
+            .checked_add(record.decompressed_length)
+            .filter(|x| *x <= block.len() as u64)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("solid block of `{}` is too short", record.name),
+                )
+            })?;
+
+        Ok(Some((block, offset as usize..end as usize)))
+    }
+
+    /// The stored data of a record, if it is kept in the record itself.
+    #[inline(always)]
+    fn inline_data<'a>(&self, record: &'a FileRecord) -> Option<&'a [u8]> {
+        record.attr(&self.meta, INLINE_ATTR)
+    }
+
+    /// Where the data of a record lies in the file it stands for, if it is a sparse file.
+    fn sparse_map(&self, record: &FileRecord) -> io::Result<Option<SparseMap>> {
+        record
+            .attr(&self.meta, SPARSE_ATTR)
+            .map(SparseMap::from_bytes)
+            .transpose()
+    }
+
+    /// The chunks of a record in order, if its data is stored as content-defined chunks.
+    fn record_chunks(&self, record: &FileRecord) -> io::Result<Option<Vec<&Chunk>>> {
+        let indices = match record.attr(&self.meta, CHUNKS_ATTR) {
+            Some(bytes) => read_chunk_list(bytes)?,
+            None => return Ok(None),
+        };
+
+        indices
+            .into_iter()
+            .map(|index| {
+                self.chunks.get(index as usize).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("`{}` refers to missing chunk {}", record.name, index),
+                    )
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    fn decompress_chunk<W: Write>(
+        &self,
+        record: &FileRecord,
+        chunk: &Chunk,
+        dest: W,
+    ) -> io::Result<()> {
+        let data = unsafe { self.map_data(chunk.data, chunk.length)? };
+        let reader = self.chunk_reader(record, chunk, &data)?;
+        chunk.compression.decompress_write(reader, dest)
+    }
+
+    /// The block table of a record, if its data is stored in independently compressed blocks.
+    #[inline(always)]
+    fn block_table(&self, record: &FileRecord) -> io::Result<Option<BlockTable>> {
         record
-            .compression
-            .decompress_write(io::Cursor::new(mmap), dest)
+            .attr(&self.meta, BLOCKS_ATTR)
+            .map(|x| BlockTable::from_bytes(x, record.length))
+            .transpose()
+    }
+
+    /// The ed25519 signature embedded in the archive, if it has been signed.
+    #[cfg(feature = "signature")]
+    pub fn signature(&self) -> io::Result<Option<Signature>> {
+        match self.meta.file_attr(SIGNATURE_ATTR) {
+            Some(bytes) => Signature::try_from(&bytes[..]).map(Some).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed signature attribute")
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Creates a detached signature over the trailer of the archive, which covers every record
+    /// and, with it, every recorded checksum and digest. Every file must have a digest, as it is
+    /// only through its digest that the data of a file is covered.
+    #[cfg(feature = "signature")]
+    pub fn sign(&self, keypair: &Keypair) -> io::Result<Signature> {
+        signature::check_digests(&self.meta)?;
+        let trailer = self.map_trailer()?;
+        signature::sign(keypair, &trailer)
+    }
+
+    /// Checks the embedded signature against `public_key`. Returns an `InvalidData` error if the
+    /// archive is unsigned, was not signed with the matching secret key, or has a file without a
+    /// digest. The data of each file is checked against its digest when it is decompressed.
+    #[cfg(feature = "signature")]
+    pub fn verify_signature(&self, public_key: &PublicKey) -> io::Result<()> {
+        let signature = self
+            .signature()?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "archive is not signed"))?;
+        self.verify_detached_signature(public_key, &signature)
+    }
+
+    /// Checks a detached signature of the archive against `public_key`, as for
+    /// `verify_signature`.
+    #[cfg(feature = "signature")]
+    pub fn verify_detached_signature(
+        &self,
+        public_key: &PublicKey,
+        signature: &Signature,
+    ) -> io::Result<()> {
+        let trailer = self.map_trailer()?;
+        signature::verify(public_key, signature, &trailer)?;
+        signature::check_digests(&self.meta)
+    }
+
+    /// Maps the trailer and index as they were serialized, before any compression or
+    /// encryption.
+    #[cfg(feature = "signature")]
+    fn map_trailer(&self) -> io::Result<memmap::Mmap> {
+        let trailer = self.map_raw_trailer()?;
+        if self.header.has_compressed_trailer() {
+            return map_anon(&decompress_trailer(&trailer)?);
+        }
+        if !self.header.has_encrypted_metadata() {
+            return Ok(trailer);
+        }
+
+        #[cfg(feature = "encryption")]
+        {
+            let key = self.content_key.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "archive metadata is encrypted and the archive is locked",
+                )
+            })?;
+            map_anon(&encryption::open_metadata(&trailer, key)?)
+        }
+        #[cfg(not(feature = "encryption"))]
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Compiled without support for encryption",
+        ))
+    }
+
+    #[cfg(any(feature = "signature", feature = "encryption"))]
+    #[inline(always)]
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn map_raw_trailer(&self) -> io::Result<memmap::Mmap> {
+        let ptr = self
+            .header
+            .trailer
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no trailer found"))?;
+        unsafe { map_trailer(self.file.get_ref(), ptr, self.offset) }
     }
 
     #[inline(always)]
@@ -141,10 +843,42 @@ impl BoxFileReader {
     #[inline(always)]
     pub fn extract_all<P: AsRef<Path>>(&self, output_path: P) -> io::Result<()> {
         let output_path = output_path.as_ref().canonicalize()?;
+        let mut directories = vec![];
         self.meta
             .iter()
-            .map(|RecordsItem { path, record, .. }| self.extract_inner(&path, record, &output_path))
-            .collect()
+            .filter(|item| item.record.as_hard_link().is_none())
+            .try_for_each(|RecordsItem { path, record, .. }| match record {
+                Record::Directory(_) => {
+                    self.extract_record(&path, record, &output_path)?;
+                    directories.push((path, record));
+                    Ok(())
+                }
+                _ => self.extract_inner(&path, record, &output_path),
+            })?;
+
+        // Hard links go last, so that the files they name have been extracted already.
+        self.meta
+            .iter()
+            .filter_map(|item| item.record.as_hard_link().map(|link| (item.path, link)))
+            .try_for_each(|(path, link)| {
+                let target = self.resolve_hard_link(link)?;
+                let original = output_path.join(target.path.to_path_buf());
+                let link_path = output_path.join(path.to_path_buf());
+
+                if fs::hard_link(&original, &link_path).is_ok() {
+                    return Ok(());
+                }
+
+                // Fall back to a copy, such as when the file system has no hard links.
+                self.extract_inner(&path, target.record, &output_path)
+            })?;
+
+        // Directories are restored last, deepest first, so that writing into them neither
+        // changes their times nor is prevented by their mode.
+        for (path, record) in directories.iter().rev() {
+            self.restore_metadata(record, &output_path.join(path.to_path_buf()));
+        }
+        Ok(())
     }
 
     #[inline(always)]
@@ -162,14 +896,124 @@ impl BoxFileReader {
         }
     }
 
+    /// Finds the file record that a hard link is another name for, and the path it was
+    /// inserted at.
+    pub fn resolve_hard_link(&self, link: &HardLinkRecord) -> io::Result<RecordsItem<'_>> {
+        let record = self.meta.record(link.target);
+        match (record, self.link_targets.get(&link.target)) {
+            (Some(record), Some(path)) if record.as_file().is_some() => Ok(RecordsItem {
+                inode: link.target,
+                path: path.clone(),
+                record,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No file for hard link target: {:?}", link.target),
+            )),
+        }
+    }
+
+    /// Reads the stored data of a record, as `memory_map` maps it.
     #[inline(always)]
-    pub fn read_bytes(&self, record: &FileRecord) -> io::Result<io::Take<File>> {
+    pub fn read_bytes<'a>(&self, record: &'a FileRecord) -> io::Result<Box<dyn Read + 'a>> {
+        if let Some(data) = self.inline_data(record) {
+            return Ok(Box::new(data));
+        }
+
         let mut file = OpenOptions::new().read(true).open(&self.path)?;
 
         file.seek(io::SeekFrom::Start(self.offset + record.data.get()))?;
-        Ok(file.take(record.length))
+        Ok(Box::new(file.take(record.length)))
+    }
+
+    /// Reads stored data of a record, which is either all of it or the block at `block`,
+    /// decrypting it if the record is encrypted. `offset` is where `data` lies in the archive.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    #[inline(always)]
+    fn data_reader<'a>(
+        &self,
+        record: &FileRecord,
+        data: &'a [u8],
+        offset: u64,
+        block: Option<usize>,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        match record.attr(&self.meta, ENCRYPTION_NONCE_ATTR) {
+            None => Ok(Box::new(data)),
+            #[cfg(feature = "encryption")]
+            Some(nonce) => {
+                // Every block is encrypted with its own nonce prefix.
+                let nonce = match block {
+                    None => Some(nonce),
+                    Some(index) => {
+                        nonce.get(index * NONCE_PREFIX_LEN..(index + 1) * NONCE_PREFIX_LEN)
+                    }
+                }
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "malformed encryption nonce")
+                })?;
+                self.decrypt_reader(record, data, offset, nonce)
+            }
+            #[cfg(not(feature = "encryption"))]
+            Some(nonce) => self.decrypt_reader(record, data, offset, nonce),
+        }
+    }
+
+    /// Reads a stored chunk of a record, decrypting it if the chunk is encrypted.
+    #[inline(always)]
+    fn chunk_reader<'a>(
+        &self,
+        record: &FileRecord,
+        chunk: &Chunk,
+        data: &'a [u8],
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        match chunk.nonce.as_slice() {
+            [] => Ok(Box::new(data)),
+            nonce => self.decrypt_reader(record, data, chunk.data, nonce),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decrypt_reader<'a>(
+        &self,
+        record: &FileRecord,
+        data: &'a [u8],
+        offset: u64,
+        nonce: &[u8],
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        let key = self.content_key.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("`{}` is encrypted and the archive is locked", record.name),
+            )
+        })?;
+        Ok(Box::new(DecryptReader::new(
+            data,
+            key,
+            nonce,
+            data.len() as u64,
+            &offset.to_le_bytes(),
+        )?))
     }
 
+    #[cfg(not(feature = "encryption"))]
+    fn decrypt_reader<'a>(
+        &self,
+        _record: &FileRecord,
+        _data: &'a [u8],
+        _offset: u64,
+        _nonce: &[u8],
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Compiled without support for encryption",
+        ))
+    }
+
+    /// Maps the stored data of a record, which is still compressed and, if the record is
+    /// encrypted, still encrypted. A record stored in blocks has its blocks one after another,
+    /// a record stored in a solid block maps the whole block, a record stored as chunks has no
+    /// data of its own, and the data of an inline record is copied into an anonymous map.
+    ///
     /// # Safety
     ///
     /// Use of memory maps is unsafe as modifications to the file could affect the operation
@@ -177,22 +1021,209 @@ impl BoxFileReader {
     /// map is in use.
     #[inline(always)]
     pub unsafe fn memory_map(&self, record: &FileRecord) -> io::Result<memmap::Mmap> {
+        match self.inline_data(record) {
+            Some(data) if !data.is_empty() => map_anon(data),
+            _ => self.map_data(record.data.get(), record.length),
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn map_data(&self, data: u64, length: u64) -> io::Result<memmap::Mmap> {
+        // Empty maps are refused, other than at an offset within a page, as the map then
+        // starts at the beginning of the page. Any such offset will do for no data at all.
+        if length == 0 {
+            return MmapOptions::new().offset(1).len(0).map(self.file.get_ref());
+        }
+
         MmapOptions::new()
-            .offset(self.offset + record.data.get())
-            .len(record.length as usize)
+            .offset(self.offset + data)
+            .len(length as usize)
             .map(self.file.get_ref())
     }
 
+    /// Creates a FIFO, device node or socket. Device nodes can only be made by root, so they
+    /// are skipped otherwise.
+    #[cfg(unix)]
+    fn extract_special(&self, special: &SpecialRecord, path: &Path) -> io::Result<()> {
+        use crate::record::SpecialFileType::*;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mode = special.mode(&self.meta).map_or(0o644, |x| x & 0o7777) as libc::mode_t;
+        let (kind, dev) = match special.file_type {
+            Fifo => (libc::S_IFIFO, 0),
+            Socket => (libc::S_IFSOCK, 0),
+            CharDevice | BlockDevice if !owner::is_root() => {
+                log::warn!("Skipping device node, as not running as root: {:?}", path);
+                return Ok(());
+            }
+            CharDevice => (
+                libc::S_IFCHR,
+                libc::makedev(special.major as _, special.minor as _),
+            ),
+            BlockDevice => (
+                libc::S_IFBLK,
+                libc::makedev(special.major as _, special.minor as _),
+            ),
+        };
+
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let result = if special.file_type == Fifo {
+            unsafe { libc::mkfifo(c_path.as_ptr(), mode) }
+        } else {
+            unsafe { libc::mknod(c_path.as_ptr(), kind | mode, dev) }
+        };
+
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn extract_special(&self, special: &SpecialRecord, path: &Path) -> io::Result<()> {
+        log::warn!(
+            "Skipping {:?}, which cannot be created on this platform: {:?}",
+            special.file_type,
+            path
+        );
+        Ok(())
+    }
+
     #[inline(always)]
     fn extract_inner(&self, path: &BoxPath, record: &Record, output_path: &Path) -> io::Result<()> {
+        self.extract_record(path, record, output_path)?;
+        self.restore_metadata(record, &output_path.join(path.to_path_buf()));
+        Ok(())
+    }
+
+    /// Applies the recorded owner, mode, extended attributes, ACLs and times of a record to
+    /// the file extracted for it, in that order, as changing the owner clears file
+    /// capabilities and changing the mode changes the ACL mask. As with tar, what cannot be
+    /// applied is warned about and skipped rather than failing the extraction.
+    fn restore_metadata(&self, record: &Record, path: &Path) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if self.same_owner && owner::is_root() {
+                if let Err(e) = self.restore_owner(record, path) {
+                    log::warn!("Cannot set owner of {:?}: {}", path, e);
+                }
+            }
+
+            // The mode of a symbolic link is that of what it points to
+            if let (Some(mode), None) = (record.mode(&self.meta), record.as_link()) {
+                let permissions = fs::Permissions::from_mode(mode & 0o7777);
+                if let Err(e) = fs::set_permissions(path, permissions) {
+                    log::warn!("Cannot set mode {:o} of {:?}: {}", mode, path, e);
+                }
+            }
+        }
+
+        self.restore_xattrs(record, path);
+        self.restore_acls(record, path);
+
+        #[cfg(unix)]
+        {
+            let accessed = record.accessed(&self.meta);
+            let modified = record.modified(&self.meta);
+            if let Err(e) = set_times(path, accessed, modified) {
+                log::warn!("Cannot set times of {:?}: {}", path, e);
+            }
+        }
+    }
+
+    /// Gives an extracted record the owner and group it was recorded with, found by name
+    /// unless numeric ids are asked for.
+    #[cfg(unix)]
+    fn restore_owner(&self, record: &Record, path: &Path) -> io::Result<()> {
+        let by_name = !self.numeric_owner;
+        let uid = record
+            .user(&self.meta)
+            .filter(|_| by_name)
+            .and_then(owner::user_id)
+            .or_else(|| record.uid(&self.meta));
+        let gid = record
+            .group(&self.meta)
+            .filter(|_| by_name)
+            .and_then(owner::group_id)
+            .or_else(|| record.gid(&self.meta));
+
+        if uid.is_none() && gid.is_none() {
+            return Ok(());
+        }
+        owner::set_owner(path, uid, gid)
+    }
+
+    /// Sets the extended attributes of an extracted record. Those that cannot be set, such as
+    /// ones that need privileges or that the file system does not support, are skipped.
+    fn restore_xattrs(&self, record: &Record, path: &Path) {
+        for (name, value) in record.xattrs(&self.meta) {
+            if let Err(e) = xattr::write_xattr(path, name, value) {
+                log::warn!(
+                    "Cannot set extended attribute {} on {:?}: {}",
+                    name,
+                    path,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Sets the access and default ACLs of an extracted record, skipping those that cannot be
+    /// set, as for extended attributes.
+    fn restore_acls(&self, record: &Record, path: &Path) {
+        for key in [ACL_ATTR, DEFAULT_ACL_ATTR].iter() {
+            if let Some(value) = record.attr(&self.meta, key) {
+                if let Err(e) = acl::write_acl(path, key, value) {
+                    log::warn!("Cannot set ACL {} on {:?}: {}", key, path, e);
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn extract_record(
+        &self,
+        path: &BoxPath,
+        record: &Record,
+        output_path: &Path,
+    ) -> io::Result<()> {
         println!("{} -> {}: {:?}", path, output_path.display(), record);
         match record {
             Record::File(file) => {
                 let out_file = File::create(output_path.join(path.to_path_buf())).unwrap();
                 let out_file = BufWriter::new(out_file);
-                self.decompress(&file, out_file)
+                match self.sparse_map(file)? {
+                    // Holes are seeked over, so that the file is sparse again
+                    Some(map) => {
+                        let length = map.length;
+                        let mut writer = SparseWriter::new(out_file, map, sparse::seek_hole);
+                        self.decompress_stored(file, &mut writer)?;
+                        let out_file = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+                        out_file.set_len(length)
+                    }
+                    None => self.decompress(file, out_file),
+                }
             }
             Record::Directory(_dir) => fs::create_dir_all(output_path.join(path.to_path_buf())),
+            Record::HardLink(link) => {
+                let target = self.resolve_hard_link(link)?;
+                self.extract_inner(path, target.record, output_path)
+            }
+            Record::Special(special) => {
+                self.extract_special(special, &output_path.join(path.to_path_buf()))
+            }
+            Record::Unknown(unknown) => {
+                log::warn!(
+                    "Skipping record of unknown kind {}: {:?}",
+                    unknown.kind,
+                    path
+                );
+                Ok(())
+            }
             #[cfg(unix)]
             Record::Link(link) => {
                 let link_target = self.resolve_link(link)?;
@@ -218,3 +1249,44 @@ impl BoxFileReader {
         }
     }
 }
+
+/// Sets the access and modification times of a file without following symbolic links,
+/// leaving either as it is if not given.
+#[cfg(unix)]
+fn set_times(
+    path: &Path,
+    accessed: Option<Timestamp>,
+    modified: Option<Timestamp>,
+) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if accessed.is_none() && modified.is_none() {
+        return Ok(());
+    }
+
+    let time = |time: Option<Timestamp>| match time {
+        Some(time) => libc::timespec {
+            tv_sec: time.secs as libc::time_t,
+            tv_nsec: time.nanos as libc::c_long,
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    };
+    let times = [time(accessed), time(modified)];
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    match unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -3,22 +3,47 @@ use std::default::Default;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{prelude::*, BufReader, BufWriter, Result, SeekFrom};
-use std::num::NonZeroU64;
+use std::num::{NonZeroU32, NonZeroU64};
 use std::path::{Path, PathBuf};
 
 use memmap::MmapOptions;
 
+#[cfg(feature = "signature")]
+use crate::signature::{self, Keypair};
 use crate::{
+    attr_set::{ATTR_SETS_ATTR, ATTR_SET_ATTR, SHARED_ATTR_KEYS},
+    blocks::{BlockTable, BLOCKS_ATTR},
+    checksum::{
+        ChecksumReader, HashAlgorithm, Hasher, CRC32_ATTR, HASH_ALGORITHM_ATTR, HASH_ATTR,
+        KEYED_HASH_ATTR,
+    },
+    chunks::{chunk_list_bytes, read_chunk_table, Chunk, Chunker, CHUNKS_ATTR, CHUNK_TABLE_ATTR},
     compression::Compression,
-    header::BoxHeader,
+    de::DeserializeOwned,
+    encryption::ENCRYPTION_NONCE_ATTR,
+    header::{BoxHeader, FLAG_ATTR_SETS, FLAG_COMPRESSED_TRAILER, FLAG_RECORD_INDEX, HEADER_SIZE},
+    inline::INLINE_ATTR,
     path::BoxPath,
-    record::{DirectoryRecord, FileRecord, LinkRecord, Record},
+    record::{
+        DirectoryRecord, FileRecord, HardLinkRecord, LinkRecord, Record, SpecialFileType,
+        SpecialRecord,
+    },
     ser::Serialize,
+    signature::SIGNATURE_ATTR,
+    solid::{SolidBlock, SOLID_ATTR},
+    sparse::{ExtentReader, SparseMap, SPARSE_ATTR},
+};
+#[cfg(feature = "encryption")]
+use crate::{
+    encryption::{self, ContentKey, EncryptWriter, Identity, Recipient, ENCRYPTION_ATTR},
+    header::FLAG_ENCRYPTED_METADATA,
 };
 
+#[cfg(feature = "encryption")]
+use super::reader::{map_trailer, read_sealed_trailer};
 use super::{
-    reader::{read_header, read_trailer},
-    BoxMetadata,
+    reader::{map_anon, read_header, read_trailer},
+    BoxMetadata, Inode,
 };
 
 pub struct BoxFileWriter {
@@ -26,6 +51,30 @@ pub struct BoxFileWriter {
     pub(crate) path: PathBuf,
     pub(crate) header: BoxHeader,
     pub(crate) meta: BoxMetadata,
+    pub(crate) trailer_compression: Option<Compression>,
+    pub(crate) record_index: bool,
+    pub(crate) block_size: Option<NonZeroU32>,
+    pub(crate) inline_size: Option<NonZeroU32>,
+    pub(crate) solid_size: Option<NonZeroU32>,
+    pub(crate) solid: Option<SolidBlock>,
+    pub(crate) dedup: Option<HashMap<Vec<u8>, Inode>>,
+    pub(crate) chunk_size: Option<NonZeroU32>,
+    pub(crate) chunks: Vec<Chunk>,
+    pub(crate) chunk_digests: HashMap<Vec<u8>, u64>,
+    pub(crate) data_end: u64,
+    #[cfg(feature = "signature")]
+    pub(crate) signing_key: Option<Keypair>,
+    #[cfg(feature = "encryption")]
+    pub(crate) content_key: Option<ContentKey>,
+}
+
+/// The result of writing the data of a file.
+struct DataWritten {
+    length: u64,
+    decompressed_length: u64,
+    nonce: Option<Vec<u8>>,
+    blocks: Option<BlockTable>,
+    chunks: Option<Vec<u64>>,
 }
 
 impl Drop for BoxFileWriter {
@@ -43,11 +92,32 @@ impl BoxFileWriter {
 
     #[inline(always)]
     fn finish_inner(&mut self) -> std::io::Result<u64> {
-        let pos = self.next_write_addr().get();
+        self.flush_solid()?;
+
+        // The trailer and index are serialized up front so the header can carry their checksum.
+        let (trailer, offsets) = self.serialize_trailer()?;
+        let trailer = self.compress_trailer(trailer)?;
+        let trailer = self.seal_trailer(trailer)?;
+
+        // Offsets into a sealed trailer would give away the size of each record.
+        let record_index = self.record_index && !self.header.has_encrypted_metadata();
+        self.header.set_flag(FLAG_RECORD_INDEX, record_index);
+
+        let mut pos = self.next_write_addr().get();
+        self.file.seek(SeekFrom::Start(pos))?;
+        if record_index {
+            for offset in offsets.iter() {
+                self.file.write_all(&offset.to_le_bytes())?;
+            }
+            self.file.write_all(&(offsets.len() as u64).to_le_bytes())?;
+            pos += (offsets.len() as u64 + 1) * 8;
+        }
+
         self.header.trailer = NonZeroU64::new(pos);
+        self.header.trailer_checksum = Some(crc32fast::hash(&trailer));
         self.write_header()?;
         self.file.seek(SeekFrom::Start(pos))?;
-        self.meta.write(&mut self.file)?;
+        self.file.write_all(&trailer)?;
 
         let new_pos = self.file.seek(SeekFrom::Current(0))?;
         let file = self.file.get_mut();
@@ -55,21 +125,100 @@ impl BoxFileWriter {
         Ok(new_pos)
     }
 
+    /// Serializes the trailer and index, along with the offsets of a record index into them.
+    fn serialize_trailer(&mut self) -> std::io::Result<(Vec<u8>, Vec<u64>)> {
+        if !self.chunks.is_empty() {
+            let mut table = std::io::Cursor::new(vec![]);
+            self.chunks.write(&mut table)?;
+            self.set_file_attr(CHUNK_TABLE_ATTR, table.into_inner())?;
+        }
+
+        // Attributes that records tend to have in common are kept once, in sets they refer to.
+        // The keys must be interned before signing, as the key list is part of what is signed.
+        if SHARED_ATTR_KEYS
+            .iter()
+            .any(|x| self.meta.attr_key(x).is_some())
+        {
+            self.meta.attr_key_or_create(ATTR_SET_ATTR);
+            self.meta.attr_key_or_create(ATTR_SETS_ATTR);
+            self.header.set_flag(FLAG_ATTR_SETS, true);
+        }
+
+        // Any existing signature was made over the archive as it was before this writer changed it.
+        if let Some(key) = self.meta.attr_key(SIGNATURE_ATTR) {
+            self.meta.attrs.remove(&key);
+        }
+
+        #[cfg(feature = "signature")]
+        {
+            if let Some(keypair) = self.signing_key.as_ref() {
+                signature::check_digests(&self.meta)?;
+                let key = self.meta.attr_key_or_create(SIGNATURE_ATTR);
+                let mut unsigned = std::io::Cursor::new(vec![]);
+                self.meta.write(&mut unsigned)?;
+
+                let signature = signature::sign(keypair, unsigned.get_ref())?;
+                self.meta.attrs.insert(key, signature.to_bytes().to_vec());
+            }
+        }
+
+        let mut trailer = std::io::Cursor::new(vec![]);
+        let offsets = self.meta.write_trailer(&mut trailer)?;
+        Ok((trailer.into_inner(), offsets))
+    }
+
+    /// Compresses the trailer and index with the trailer compression, if there is one, after
+    /// the compression used. Sealed trailers are compressed as they are sealed instead.
+    fn compress_trailer(&mut self, trailer: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let compression = match self.trailer_compression {
+            Some(v) if !self.header.has_encrypted_metadata() => v,
+            _ => {
+                self.header.set_flag(FLAG_COMPRESSED_TRAILER, false);
+                return Ok(trailer);
+            }
+        };
+
+        let mut out = std::io::Cursor::new(vec![]);
+        compression.write(&mut out)?;
+        compression.compress(&mut out, &mut &*trailer)?;
+        self.header.set_flag(FLAG_COMPRESSED_TRAILER, true);
+        Ok(out.into_inner())
+    }
+
+    #[cfg(feature = "encryption")]
+    fn seal_trailer(&self, trailer: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        if !self.header.has_encrypted_metadata() {
+            return Ok(trailer);
+        }
+
+        let key = self.content_key.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "encrypted metadata requires a password or recipient",
+            )
+        })?;
+        encryption::seal_metadata(&self.meta, key, &trailer, self.trailer_compression)
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn seal_trailer(&self, trailer: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        if !self.header.has_encrypted_metadata() {
+            return Ok(trailer);
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Compiled without support for encryption",
+        ))
+    }
+
     pub fn finish(mut self) -> std::io::Result<u64> {
         self.finish_inner()
     }
 
     #[inline(always)]
     fn next_write_addr(&self) -> NonZeroU64 {
-        // TODO: this is probably slow as hell
-        let offset = self
-            .meta
-            .inodes
-            .iter()
-            .rev()
-            .find_map(|r| r.as_file())
-            .map(|r| r.data.get() + r.length)
-            .unwrap_or(std::mem::size_of::<BoxHeader>() as u64);
+        let offset = self.data_end;
 
         let v = match self.header.alignment {
             0 => offset,
@@ -87,7 +236,44 @@ impl BoxFileWriter {
     }
 
     /// This will open an existing `.box` file for writing, and error if the file is not valid.
+    /// An archive with encrypted metadata must be opened with its password or an identity.
     pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<BoxFileWriter> {
+        let bf = Self::open_inner(path)?;
+        if bf.header.has_encrypted_metadata() {
+            // Dropping a writer finishes it, which fails before writing while it is locked.
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "archive metadata is encrypted; open it with a password or identity",
+            ));
+        }
+        Ok(bf)
+    }
+
+    /// This will open an existing encrypted `.box` file for writing, and error if `password`
+    /// does not unlock it.
+    #[cfg(feature = "encryption")]
+    pub fn open_with_password<P: AsRef<Path>>(
+        path: P,
+        password: &str,
+    ) -> std::io::Result<BoxFileWriter> {
+        let mut bf = Self::open_inner(path)?;
+        bf.unlock_with_password(password)?;
+        Ok(bf)
+    }
+
+    /// This will open an existing encrypted `.box` file for writing, and error if `identity`
+    /// is not one of its recipients.
+    #[cfg(feature = "encryption")]
+    pub fn open_with_identity<P: AsRef<Path>>(
+        path: P,
+        identity: &Identity,
+    ) -> std::io::Result<BoxFileWriter> {
+        let mut bf = Self::open_inner(path)?;
+        bf.unlock_with_identity(identity)?;
+        Ok(bf)
+    }
+
+    fn open_inner<P: AsRef<Path>>(path: P) -> std::io::Result<BoxFileWriter> {
         OpenOptions::new()
             .read(true)
             .write(true)
@@ -98,24 +284,80 @@ impl BoxFileWriter {
                 let (header, meta) = {
                     let mut reader = BufReader::new(&mut file);
                     let header = read_header(&mut reader, 0)?;
+                    // Rewriting a version 0 header would overwrite the data after it
+                    if header.version == 0 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "archives of version 0 cannot be appended to",
+                        ));
+                    }
                     let ptr = header.trailer.ok_or_else(|| {
                         std::io::Error::new(std::io::ErrorKind::Other, "no trailer found")
                     })?;
-                    let meta = read_trailer(&mut reader, ptr, path.as_ref(), 0)?;
+                    let meta = read_trailer(&mut reader, &header, ptr, path.as_ref(), 0)?;
                     (header, meta)
                 };
 
-                let f = BoxFileWriter {
+                // The trailer is compressed as it was when the archive is finished again.
+                let trailer_compression = if header.has_compressed_trailer() {
+                    let ptr = header.trailer.map(|x| x.get()).unwrap_or(0);
+                    file.seek(SeekFrom::Start(ptr))?;
+                    Some(Compression::deserialize_owned(&mut file)?)
+                } else {
+                    None
+                };
+                let record_index = header.has_record_index();
+
+                let mut f = BoxFileWriter {
                     file: BufWriter::new(file),
                     path: path.as_ref().to_path_buf().canonicalize()?,
                     header,
                     meta,
+                    trailer_compression,
+                    record_index,
+                    block_size: None,
+                    inline_size: None,
+                    solid_size: None,
+                    solid: None,
+                    dedup: None,
+                    chunk_size: None,
+                    chunks: vec![],
+                    chunk_digests: HashMap::new(),
+                    data_end: HEADER_SIZE,
+                    #[cfg(feature = "signature")]
+                    signing_key: None,
+                    #[cfg(feature = "encryption")]
+                    content_key: None,
                 };
+                f.load_meta()?;
 
                 Ok(f)
             })?
     }
 
+    /// Loads the chunk table of an archive and finds where its data ends, once its metadata
+    /// has been read.
+    fn load_meta(&mut self) -> std::io::Result<()> {
+        self.chunks = read_chunk_table(&self.meta)?;
+        self.chunk_digests = self
+            .chunks
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (x.digest.clone(), i as u64))
+            .collect();
+
+        // Deduplicated records point back at earlier data, so the last may not end last.
+        let records = self
+            .meta
+            .inodes
+            .iter()
+            .filter_map(|r| r.as_file())
+            .map(|r| r.data.get() + r.length);
+        let chunks = self.chunks.iter().map(|x| x.data + x.length);
+        self.data_end = records.chain(chunks).max().unwrap_or(HEADER_SIZE);
+        Ok(())
+    }
+
     /// This will create a new `.box` file for writing, and error if the file already exists.
     pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<BoxFileWriter> {
         let mut boxfile = OpenOptions::new()
@@ -129,6 +371,21 @@ impl BoxFileWriter {
                     path: path.as_ref().to_path_buf().canonicalize()?,
                     header: BoxHeader::default(),
                     meta: BoxMetadata::default(),
+                    trailer_compression: None,
+                    record_index: false,
+                    block_size: None,
+                    inline_size: None,
+                    solid_size: None,
+                    solid: None,
+                    dedup: None,
+                    chunk_size: None,
+                    chunks: vec![],
+                    chunk_digests: HashMap::new(),
+                    data_end: HEADER_SIZE,
+                    #[cfg(feature = "signature")]
+                    signing_key: None,
+                    #[cfg(feature = "encryption")]
+                    content_key: None,
                 })
             })?;
 
@@ -154,6 +411,21 @@ impl BoxFileWriter {
                     path: path.as_ref().to_path_buf().canonicalize()?,
                     header: BoxHeader::with_alignment(alignment),
                     meta: BoxMetadata::default(),
+                    trailer_compression: None,
+                    record_index: false,
+                    block_size: None,
+                    inline_size: None,
+                    solid_size: None,
+                    solid: None,
+                    dedup: None,
+                    chunk_size: None,
+                    chunks: vec![],
+                    chunk_digests: HashMap::new(),
+                    data_end: HEADER_SIZE,
+                    #[cfg(feature = "signature")]
+                    signing_key: None,
+                    #[cfg(feature = "encryption")]
+                    content_key: None,
                 })
             })?;
 
@@ -275,16 +547,56 @@ impl BoxFileWriter {
         })
     }
 
-    pub fn insert<R: Read>(
+    /// Adds a FIFO, device node or socket, which has no data of its own. `major` and `minor`
+    /// are the device numbers of a device node, and are zero otherwise.
+    pub fn mknod(
         &mut self,
-        compression: Compression,
         path: BoxPath,
-        value: &mut R,
+        file_type: SpecialFileType,
+        major: u32,
+        minor: u32,
         attrs: HashMap<String, Vec<u8>>,
-    ) -> std::io::Result<&FileRecord> {
+    ) -> std::io::Result<()> {
+        self.insert_inner(path, move |this, path| {
+            let attrs = attrs
+                .into_iter()
+                .map(|(k, v)| {
+                    let k = this.meta.attr_key_or_create(&k);
+                    (k, v)
+                })
+                .collect::<HashMap<_, _>>();
+
+            let special_record = SpecialRecord {
+                name: path.filename(),
+                file_type,
+                major,
+                minor,
+                attrs,
+            };
+
+            Ok(special_record.upcast())
+        })
+    }
+
+    /// Adds `path` as another name for the file already inserted at `target`, sharing its data
+    /// and attributes.
+    pub fn hard_link(
+        &mut self,
+        path: BoxPath,
+        target: &BoxPath,
+        attrs: HashMap<String, Vec<u8>>,
+    ) -> std::io::Result<()> {
+        let target = match self.meta.inode(target) {
+            Some(inode) if self.meta.record(inode).and_then(|x| x.as_file()).is_some() => inode,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("No file found for hard link target: {}", target),
+                ))
+            }
+        };
+
         self.insert_inner(path, move |this, path| {
-            let next_addr = this.next_write_addr();
-            let byte_count = this.write_data::<R>(compression, next_addr.get(), value)?;
             let attrs = attrs
                 .into_iter()
                 .map(|(k, v)| {
@@ -293,10 +605,116 @@ impl BoxFileWriter {
                 })
                 .collect::<HashMap<_, _>>();
 
+            let link_record = HardLinkRecord {
+                name: path.filename(),
+                target,
+                attrs,
+            };
+
+            Ok(link_record.upcast())
+        })
+    }
+
+    /// Inserts a file read from `value`. A file smaller than the inline size is kept in its
+    /// record. When solid blocks are enabled, a file smaller than the solid size is held back
+    /// to be written with the files after it, and the `length` of its record is zero until its
+    /// block is written.
+    pub fn insert<R: Read>(
+        &mut self,
+        compression: Compression,
+        path: BoxPath,
+        value: &mut R,
+        attrs: HashMap<String, Vec<u8>>,
+    ) -> std::io::Result<&FileRecord> {
+        let inline_size = match self.inline_size {
+            Some(v) if self.can_inline() => v.get() as usize,
+            _ => 0,
+        };
+        let solid_size = self.solid_size.map(|x| x.get() as usize).unwrap_or(0);
+        if inline_size == 0 && solid_size == 0 {
+            return self.insert_stream(compression, path, value, attrs);
+        }
+
+        let mut head = Vec::new();
+        value
+            .by_ref()
+            .take(inline_size.max(solid_size) as u64)
+            .read_to_end(&mut head)?;
+
+        if head.len() < inline_size {
+            self.insert_inline(path, head, attrs)
+        } else if head.len() < solid_size {
+            self.insert_solid(compression, path, head, attrs)
+        } else {
+            self.insert_stream(compression, path, &mut (&*head).chain(value), attrs)
+        }
+    }
+
+    /// Inserts a file from the file system, storing only its extents holding data if it has
+    /// holes, as found with `SEEK_DATA` and `SEEK_HOLE`. A file without holes, or on a platform
+    /// where they cannot be found, is inserted as with `insert`.
+    pub fn insert_sparse(
+        &mut self,
+        compression: Compression,
+        path: BoxPath,
+        file: &mut File,
+        mut attrs: HashMap<String, Vec<u8>>,
+    ) -> std::io::Result<&FileRecord> {
+        let map = match SparseMap::of_file(file)? {
+            Some(v) => v,
+            None => return self.insert(compression, path, &mut BufReader::new(file), attrs),
+        };
+
+        attrs.insert(SPARSE_ATTR.into(), map.to_bytes());
+        let mut file = BufReader::new(file);
+        let mut reader = ExtentReader::new(&mut file, &map);
+        self.insert(compression, path, &mut reader, attrs)
+    }
+
+    fn insert_stream<R: Read>(
+        &mut self,
+        compression: Compression,
+        path: BoxPath,
+        value: &mut R,
+        attrs: HashMap<String, Vec<u8>>,
+    ) -> std::io::Result<&FileRecord> {
+        // A pending solid block must be written first, as it is placed at the next address.
+        self.flush_solid()?;
+
+        self.insert_inner(path, move |this, path| {
+            let next_addr = this.next_write_addr();
+            let hasher = this.hasher()?;
+            let mut value = ChecksumReader::new(value, hasher);
+            let written = this.write_data(compression, next_addr.get(), &mut value)?;
+            let (crc32, digest) = value.finalize();
+            if let Some(original) = this.duplicate_of(digest.as_deref()) {
+                // The data just written is left past `data_end`, which only `write_data` moves
+                // and only for chunks, so nothing refers to it and the next data written goes
+                // over it. Chunks are stored once each already.
+                return this.duplicate_record(original, path, attrs, crc32, digest);
+            }
+
+            // Chunks are placed as they are written, and a file stored as chunks has no length.
+            this.data_end = this.data_end.max(next_addr.get() + written.length);
+            let mut attrs = this.file_attrs(attrs, crc32, digest);
+
+            if let Some(nonce) = written.nonce {
+                let nonce_key = this.meta.attr_key_or_create(ENCRYPTION_NONCE_ATTR);
+                attrs.insert(nonce_key, nonce);
+            }
+            if let Some(blocks) = written.blocks {
+                let blocks_key = this.meta.attr_key_or_create(BLOCKS_ATTR);
+                attrs.insert(blocks_key, blocks.to_bytes());
+            }
+            if let Some(chunks) = written.chunks {
+                let chunks_key = this.meta.attr_key_or_create(CHUNKS_ATTR);
+                attrs.insert(chunks_key, chunk_list_bytes(&chunks));
+            }
+
             let record = FileRecord {
                 compression,
-                length: byte_count.write,
-                decompressed_length: byte_count.read,
+                length: written.length,
+                decompressed_length: written.decompressed_length,
                 name: path.filename(),
                 data: next_addr,
                 attrs,
@@ -305,14 +723,316 @@ impl BoxFileWriter {
             Ok(record.upcast())
         })?;
 
+        self.index_digest(Inode::new(self.meta.inodes.len() as u64)?);
         Ok(&self.meta.inodes.last().unwrap().as_file().unwrap())
     }
 
+    /// Inserts a file with its data kept in its record, uncompressed.
+    fn insert_inline(
+        &mut self,
+        path: BoxPath,
+        data: Vec<u8>,
+        attrs: HashMap<String, Vec<u8>>,
+    ) -> std::io::Result<&FileRecord> {
+        let hasher = self.hasher()?;
+        let mut value = ChecksumReader::new(&*data, hasher);
+        std::io::copy(&mut value, &mut std::io::sink())?;
+        let (crc32, digest) = value.finalize();
+
+        self.insert_inner(path, |this, path| {
+            let next_addr = this.next_write_addr();
+            let mut attrs = this.file_attrs(attrs, crc32, digest);
+
+            let inline_key = this.meta.attr_key_or_create(INLINE_ATTR);
+            let decompressed_length = data.len() as u64;
+            attrs.insert(inline_key, data);
+
+            let record = FileRecord {
+                compression: Compression::Stored,
+                length: 0,
+                decompressed_length,
+                name: path.filename(),
+                data: next_addr,
+                attrs,
+            };
+
+            Ok(record.upcast())
+        })?;
+
+        Ok(self.meta.inodes.last().unwrap().as_file().unwrap())
+    }
+
+    /// Whether files can be kept in their records. The data of files in an encrypted archive
+    /// is only kept in the trailer if the trailer is encrypted too.
+    #[cfg(feature = "encryption")]
+    fn can_inline(&self) -> bool {
+        self.content_key.is_none() || self.header.has_encrypted_metadata()
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn can_inline(&self) -> bool {
+        true
+    }
+
+    /// Whether the data of files inserted now is encrypted.
+    #[cfg(feature = "encryption")]
+    fn is_encrypting(&self) -> bool {
+        self.content_key.is_some()
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn is_encrypting(&self) -> bool {
+        false
+    }
+
+    /// A hasher for the digest of a new file, if the archive records a hash algorithm. The
+    /// digest of an encrypted file is keyed, so that it reveals nothing of the data.
+    fn hasher(&self) -> std::io::Result<Option<Hasher>> {
+        match self.meta.hash_algorithm()? {
+            Some(algorithm) => self.hasher_for(algorithm).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// A hasher for `algorithm`, keyed with the content key when encrypting.
+    fn hasher_for(&self, algorithm: HashAlgorithm) -> std::io::Result<Hasher> {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(key) = self.content_key.as_ref() {
+                return algorithm.keyed_hasher(&key.hash_key());
+            }
+        }
+        algorithm.hasher()
+    }
+
+    /// The attribute the digest of a new file is kept under.
+    fn digest_attr(&self) -> &'static str {
+        if self.is_encrypting() {
+            KEYED_HASH_ATTR
+        } else {
+            HASH_ATTR
+        }
+    }
+
+    /// Adds a file to the pending solid block, first writing out the block if the file does
+    /// not fit in it or uses another compression.
+    fn insert_solid(
+        &mut self,
+        compression: Compression,
+        path: BoxPath,
+        data: Vec<u8>,
+        attrs: HashMap<String, Vec<u8>>,
+    ) -> std::io::Result<&FileRecord> {
+        let hasher = self.hasher()?;
+        let mut value = ChecksumReader::new(&*data, hasher);
+        std::io::copy(&mut value, &mut std::io::sink())?;
+        let (crc32, digest) = value.finalize();
+
+        if let Some(original) = self.duplicate_of(digest.as_deref()) {
+            self.insert_inner(path, |this, path| {
+                this.duplicate_record(original, path, attrs, crc32, digest)
+            })?;
+
+            // A duplicate of a file in the pending block is pointed at the block along with it.
+            let inode = Inode::new(self.meta.inodes.len() as u64)?;
+            if let Some(block) = self.solid.as_mut() {
+                if block.inodes.contains(&original) {
+                    block.inodes.push(inode);
+                }
+            }
+            return Ok(self.meta.inodes.last().unwrap().as_file().unwrap());
+        }
+
+        let solid_size = self.solid_size.map(|x| x.get() as usize).unwrap_or(0);
+        let fits = match self.solid.as_ref() {
+            Some(block) => {
+                block.compression == compression && block.data.len() + data.len() <= solid_size
+            }
+            None => true,
+        };
+        if !fits {
+            self.flush_solid()?;
+        }
+
+        let offset = self.solid.as_ref().map(|x| x.data.len()).unwrap_or(0) as u64;
+
+        self.insert_inner(path, |this, path| {
+            // Records of the pending block have no length yet, so this is where it will go.
+            let next_addr = this.next_write_addr();
+            let mut attrs = this.file_attrs(attrs, crc32, digest);
+
+            let solid_key = this.meta.attr_key_or_create(SOLID_ATTR);
+            attrs.insert(solid_key, offset.to_le_bytes().to_vec());
+
+            let record = FileRecord {
+                compression,
+                length: 0,
+                decompressed_length: data.len() as u64,
+                name: path.filename(),
+                data: next_addr,
+                attrs,
+            };
+
+            Ok(record.upcast())
+        })?;
+
+        let inode = Inode::new(self.meta.inodes.len() as u64)?;
+        let block = self
+            .solid
+            .get_or_insert_with(|| SolidBlock::new(compression));
+        block.data.extend_from_slice(&data);
+        block.inodes.push(inode);
+        self.index_digest(inode);
+
+        Ok(self.meta.inodes.last().unwrap().as_file().unwrap())
+    }
+
+    /// Writes out the pending solid block, if there is one, and points its records at it.
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn flush_solid(&mut self) -> std::io::Result<()> {
+        let block = match self.solid.take() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let pos = self.next_write_addr().get();
+        self.file.seek(SeekFrom::Start(pos))?;
+        let written = self.write_stream(block.compression, &mut &*block.data)?;
+        self.data_end = pos + written.length;
+        let nonce_key = written
+            .nonce
+            .as_ref()
+            .map(|_| self.meta.attr_key_or_create(ENCRYPTION_NONCE_ATTR));
+
+        for inode in block.inodes {
+            let record = self
+                .meta
+                .record_mut(inode)
+                .and_then(Record::as_file_mut)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("No file record for inode {}", inode.get()),
+                    )
+                })?;
+            record.length = written.length;
+            if let (Some(key), Some(nonce)) = (nonce_key, written.nonce.as_ref()) {
+                record.attrs.insert(key, nonce.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The file already holding data with the given digest, if deduplicating.
+    #[inline(always)]
+    fn duplicate_of(&self, digest: Option<&[u8]>) -> Option<Inode> {
+        self.dedup.as_ref()?.get(digest?).copied()
+    }
+
+    /// Records the digest of a newly inserted file, so that later duplicates can point at it.
+    fn index_digest(&mut self, inode: Inode) {
+        if self.dedup.is_none() {
+            return;
+        }
+
+        let digest = self.meta.attr_key(self.digest_attr()).and_then(|key| {
+            self.meta
+                .record(inode)
+                .and_then(|x| x.attrs().get(&key))
+                .cloned()
+        });
+        if let (Some(index), Some(digest)) = (self.dedup.as_mut(), digest) {
+            index.entry(digest).or_insert(inode);
+        }
+    }
+
+    /// A record for a new file with the same content as `original`, pointing at its stored data.
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn duplicate_record(
+        &mut self,
+        original: Inode,
+        path: &BoxPath,
+        attrs: HashMap<String, Vec<u8>>,
+        crc32: u32,
+        digest: Option<Vec<u8>>,
+    ) -> std::io::Result<Record> {
+        let mut attrs = self.file_attrs(attrs, crc32, digest);
+        let original = self
+            .meta
+            .record(original)
+            .and_then(Record::as_file)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("No file record for inode {}", original.get()),
+                )
+            })?;
+
+        // How the data is stored is shared along with it.
+        for key in [
+            ENCRYPTION_NONCE_ATTR,
+            BLOCKS_ATTR,
+            SOLID_ATTR,
+            CHUNKS_ATTR,
+            INLINE_ATTR,
+        ]
+        .iter()
+        {
+            if let Some(key) = self.meta.attr_key(key) {
+                if let Some(value) = original.attrs.get(&key) {
+                    attrs.insert(key, value.clone());
+                }
+            }
+        }
+
+        let record = FileRecord {
+            compression: original.compression,
+            length: original.length,
+            decompressed_length: original.decompressed_length,
+            name: path.filename(),
+            data: original.data,
+            attrs,
+        };
+
+        Ok(record.upcast())
+    }
+
+    /// Interns the keys of the attributes given for a file, adding its checksums. Encrypted
+    /// files have no CRC32, which would reveal something of their data.
+    fn file_attrs(
+        &mut self,
+        attrs: HashMap<String, Vec<u8>>,
+        crc32: u32,
+        digest: Option<Vec<u8>>,
+    ) -> HashMap<usize, Vec<u8>> {
+        let mut attrs = attrs
+            .into_iter()
+            .map(|(k, v)| {
+                let k = self.meta.attr_key_or_create(&k);
+                (k, v)
+            })
+            .collect::<HashMap<_, _>>();
+
+        if !self.is_encrypting() {
+            let crc32_key = self.meta.attr_key_or_create(CRC32_ATTR);
+            attrs.insert(crc32_key, crc32.to_le_bytes().to_vec());
+        }
+        if let Some(digest) = digest {
+            let hash_key = self.meta.attr_key_or_create(self.digest_attr());
+            attrs.insert(hash_key, digest);
+        }
+        attrs
+    }
+
     /// # Safety
     ///
     /// Use of memory maps is unsafe as modifications to the file could affect the operation
     /// of the application. Ensure that the Box being operated on is not mutated while a memory
     /// map is in use.
+    ///
+    /// The data of an inline record is copied into an anonymous map. A record stored in a
+    /// solid block or as chunks has no data of its own to map, and is refused.
     pub unsafe fn data(&self, record: &FileRecord) -> std::io::Result<memmap::Mmap> {
         self.read_data(record)
     }
@@ -323,9 +1043,160 @@ impl BoxFileWriter {
         compression: Compression,
         pos: u64,
         reader: &mut R,
-    ) -> std::io::Result<comde::com::ByteCount> {
+    ) -> std::io::Result<DataWritten> {
+        self.file.seek(SeekFrom::Start(pos))?;
+
+        match (self.chunk_size, self.block_size) {
+            (Some(chunk_size), _) => self.write_chunks(compression, chunk_size.get(), reader),
+            (None, Some(block_size)) => self.write_blocks(compression, block_size.get(), reader),
+            (None, None) => self.write_stream(compression, reader),
+        }
+    }
+
+    /// Splits the data read into content-defined chunks, writing only the chunks that are not
+    /// already in the archive.
+    fn write_chunks<R: Read>(
+        &mut self,
+        compression: Compression,
+        chunk_size: u32,
+        reader: &mut R,
+    ) -> std::io::Result<DataWritten> {
+        let algorithm = self.meta.hash_algorithm()?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "chunking requires a hash algorithm",
+            )
+        })?;
+
+        let mut chunker = Chunker::new(reader, chunk_size);
+        let mut chunks = vec![];
+        let mut decompressed_length = 0;
+        while let Some(chunk) = chunker.next_chunk()? {
+            decompressed_length += chunk.len() as u64;
+            chunks.push(self.write_chunk(compression, algorithm, &chunk)?);
+        }
+
+        Ok(DataWritten {
+            length: 0,
+            decompressed_length,
+            nonce: None,
+            blocks: None,
+            chunks: Some(chunks),
+        })
+    }
+
+    /// Writes a chunk at the end of the archive data unless an identical one is already stored,
+    /// returning its index in the chunk table.
+    fn write_chunk(
+        &mut self,
+        compression: Compression,
+        algorithm: HashAlgorithm,
+        chunk: &[u8],
+    ) -> std::io::Result<u64> {
+        let mut hasher = self.hasher_for(algorithm)?;
+        hasher.update(chunk);
+        let digest = hasher.finalize();
+        if let Some(index) = self.chunk_digests.get(&digest) {
+            return Ok(*index);
+        }
+
+        let pos = self.next_write_addr().get();
         self.file.seek(SeekFrom::Start(pos))?;
-        compression.compress(&mut self.file, reader)
+        let written = self.write_stream(compression, &mut &*chunk)?;
+        self.data_end = pos + written.length;
+
+        let index = self.chunks.len() as u64;
+        self.chunks.push(Chunk {
+            data: pos,
+            length: written.length,
+            decompressed_length: written.decompressed_length,
+            compression,
+            nonce: written.nonce.unwrap_or_default(),
+            digest: digest.clone(),
+        });
+        self.chunk_digests.insert(digest, index);
+        Ok(index)
+    }
+
+    /// Compresses every `block_size` bytes read as its own stream. Encrypted blocks each have
+    /// their own nonce prefix, and the prefixes are stored one after another.
+    fn write_blocks<R: Read>(
+        &mut self,
+        compression: Compression,
+        block_size: u32,
+        reader: &mut R,
+    ) -> std::io::Result<DataWritten> {
+        let mut blocks = BlockTable::new(block_size);
+        let mut nonces: Option<Vec<u8>> = None;
+        let mut length = 0;
+        let mut decompressed_length = 0;
+        let mut buf = Vec::with_capacity(block_size as usize);
+
+        loop {
+            buf.clear();
+            reader
+                .by_ref()
+                .take(block_size as u64)
+                .read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                break;
+            }
+
+            let written = self.write_stream(compression, &mut &*buf)?;
+            length += written.length;
+            decompressed_length += written.decompressed_length;
+            blocks.ends.push(length);
+            if let Some(mut nonce) = written.nonce {
+                nonces.get_or_insert_with(Vec::new).append(&mut nonce);
+            }
+
+            if buf.len() < block_size as usize {
+                break;
+            }
+        }
+
+        Ok(DataWritten {
+            length,
+            decompressed_length,
+            nonce: nonces,
+            blocks: Some(blocks),
+            chunks: None,
+        })
+    }
+
+    fn write_stream<R: Read>(
+        &mut self,
+        compression: Compression,
+        reader: &mut R,
+    ) -> std::io::Result<DataWritten> {
+        #[cfg(feature = "encryption")]
+        {
+            if let Some(key) = self.content_key.as_ref() {
+                // The stream is bound to where it is written.
+                let offset = self.file.stream_position()?;
+                let nonce = encryption::generate_nonce_prefix()?;
+                let mut writer =
+                    EncryptWriter::new(&mut self.file, key, nonce, &offset.to_le_bytes());
+                let byte_count = compression.compress(&mut writer, reader)?;
+
+                return Ok(DataWritten {
+                    length: writer.finish()?,
+                    decompressed_length: byte_count.read,
+                    nonce: Some(nonce.to_vec()),
+                    blocks: None,
+                    chunks: None,
+                });
+            }
+        }
+
+        let byte_count = compression.compress(&mut self.file, reader)?;
+        Ok(DataWritten {
+            length: byte_count.write,
+            decompressed_length: byte_count.read,
+            nonce: None,
+            blocks: None,
+            chunks: None,
+        })
     }
 
     pub fn set_attr<S: AsRef<str>>(
@@ -346,6 +1217,188 @@ impl BoxFileWriter {
         Ok(())
     }
 
+    /// Computes a digest with the given algorithm for every file inserted from now on,
+    /// recording the algorithm in the archive attributes.
+    pub fn set_hash_algorithm(&mut self, algorithm: HashAlgorithm) -> Result<()> {
+        // Fail early if support for the algorithm was not compiled in.
+        algorithm.hasher()?;
+        if self.meta.hash_algorithm()? != Some(algorithm) {
+            // Digests made with another algorithm cannot be matched.
+            if let Some(index) = self.dedup.as_mut() {
+                index.clear();
+            }
+        }
+        self.set_file_attr(HASH_ALGORITHM_ATTR, algorithm.name().as_bytes().to_vec())
+    }
+
+    /// Encrypts the data of every file inserted from now on with a key protected by `password`,
+    /// replacing any password the archive already had.
+    #[cfg(feature = "encryption")]
+    pub fn set_password(&mut self, password: &str) -> Result<()> {
+        let key = self.content_key()?;
+        encryption::lock_with_password(&mut self.meta, &key, password)
+    }
+
+    /// Encrypts the data of every file inserted from now on so that `recipient` can read it,
+    /// in addition to any password or recipients the archive already has.
+    #[cfg(feature = "encryption")]
+    pub fn add_recipient(&mut self, recipient: &Recipient) -> Result<()> {
+        let key = self.content_key()?;
+        encryption::add_recipient(&mut self.meta, &key, recipient)
+    }
+
+    /// Recovers the key of an encrypted archive from its password, so that files can be added
+    /// to it. Returns a `PermissionDenied` error if the password is incorrect.
+    #[cfg(feature = "encryption")]
+    pub fn unlock_with_password(&mut self, password: &str) -> Result<()> {
+        let key = encryption::unlock_with_password(&self.meta, password)?;
+        self.unlock(key)
+    }
+
+    /// Recovers the key of an encrypted archive from the identity of one of its recipients,
+    /// so that files can be added to it.
+    #[cfg(feature = "encryption")]
+    pub fn unlock_with_identity(&mut self, identity: &Identity) -> Result<()> {
+        let key = encryption::unlock_with_identity(&self.meta, identity)?;
+        self.unlock(key)
+    }
+
+    #[cfg(feature = "encryption")]
+    #[allow(unknown_lints, clippy::io_other_error)]
+    fn unlock(&mut self, key: ContentKey) -> Result<()> {
+        if self.header.has_encrypted_metadata() && self.content_key.is_none() {
+            let ptr = self.header.trailer.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::Other, "no trailer found")
+            })?;
+            let sealed = unsafe { map_trailer(self.file.get_ref(), ptr, 0)? };
+            self.meta = read_sealed_trailer(&sealed, &key)?;
+            self.load_meta()?;
+        }
+
+        self.content_key = Some(key);
+        Ok(())
+    }
+
+    /// Stores the data of every file inserted from now on as independently compressed blocks
+    /// of `block_size` decompressed bytes, so that it can be read from any offset without
+    /// decompressing everything before it. `None` stores each file as a single stream.
+    pub fn set_block_size(&mut self, block_size: Option<NonZeroU32>) {
+        self.block_size = block_size;
+    }
+
+    /// Stores every file inserted from now on whose digest matches that of a file already in
+    /// the archive as a record pointing at the existing data, rather than keeping the data
+    /// again. Digests come from the hash algorithm of the archive, which must be set first.
+    /// As data is read only once, a duplicate is still compressed, encrypted and written before
+    /// it is found to be one, so this saves space but not time.
+    pub fn set_deduplicate(&mut self, enabled: bool) -> Result<()> {
+        if !enabled {
+            self.dedup = None;
+            return Ok(());
+        }
+
+        if self.meta.hash_algorithm()?.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "deduplication requires a hash algorithm",
+            ));
+        }
+
+        self.dedup = Some(HashMap::new());
+        for index in 1..=self.meta.inodes.len() as u64 {
+            self.index_digest(Inode::new(index)?);
+        }
+        Ok(())
+    }
+
+    /// Splits the data of every file inserted from now on into content-defined chunks of about
+    /// `chunk_size` bytes, storing each distinct chunk once, so that files sharing content with
+    /// files already in the archive only add what differs. Chunks are told apart by digests
+    /// from the hash algorithm of the archive, which must be set first. `None` stores each
+    /// file as a whole.
+    pub fn set_chunk_size(&mut self, chunk_size: Option<NonZeroU32>) -> Result<()> {
+        if chunk_size.is_some() && self.meta.hash_algorithm()?.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "chunking requires a hash algorithm",
+            ));
+        }
+
+        self.chunk_size = chunk_size;
+        Ok(())
+    }
+
+    /// Keeps the data of every file smaller than `inline_size` bytes inserted from now on in
+    /// its record in the trailer, uncompressed, rather than in a data region of its own.
+    /// `None` stores every file in the data of the archive. Files of an encrypted archive are
+    /// only kept in their records if its metadata is encrypted.
+    pub fn set_inline_size(&mut self, inline_size: Option<NonZeroU32>) {
+        self.inline_size = inline_size;
+    }
+
+    /// Gathers every file smaller than `solid_size` bytes inserted from now on into solid
+    /// blocks of up to `solid_size` bytes, compressing consecutive small files as one stream.
+    /// `None` compresses each file on its own. Any pending block is written out first.
+    pub fn set_solid_size(&mut self, solid_size: Option<NonZeroU32>) -> Result<()> {
+        self.flush_solid()?;
+        self.solid_size = solid_size;
+        Ok(())
+    }
+
+    /// Compresses the trailer and index with `compression` when the archive is finished, which
+    /// for archives with many files can be most of their size. `None` leaves them uncompressed,
+    /// unless the metadata is encrypted, in which case they are compressed as they are sealed.
+    pub fn set_trailer_compression(&mut self, compression: Option<Compression>) {
+        self.trailer_compression = compression;
+    }
+
+    /// Writes a record index before the trailer when the archive is finished, so that a
+    /// `LazyBoxFileReader` can read any record without reading those before it. Archives with
+    /// encrypted metadata never have one.
+    pub fn set_record_index(&mut self, enabled: bool) {
+        self.record_index = enabled;
+    }
+
+    /// Encrypts the trailer and index when the archive is finished, so that paths, sizes and
+    /// attributes cannot be read without the content key. The archive must also be given a
+    /// password or recipient.
+    #[cfg(feature = "encryption")]
+    pub fn set_encrypted_metadata(&mut self, enabled: bool) {
+        self.header.set_flag(FLAG_ENCRYPTED_METADATA, enabled);
+    }
+
+    /// The key for newly encrypted data. Every file of an archive shares one key, so an
+    /// archive that is already encrypted must be unlocked first.
+    #[cfg(feature = "encryption")]
+    fn content_key(&mut self) -> Result<ContentKey> {
+        if let Some(key) = self.content_key.as_ref() {
+            return Ok(key.clone());
+        }
+
+        if self.meta.file_attr(ENCRYPTION_ATTR).is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "archive is encrypted and must be unlocked first",
+            ));
+        }
+
+        let key = ContentKey::generate()?;
+        self.content_key = Some(key.clone());
+        Ok(key)
+    }
+
+    /// Signs the trailer with the given key when the archive is finished, embedding the signature
+    /// in the archive attributes. The data of files is only covered through their digests, so
+    /// a hash algorithm is set if there is none, and every file must have a digest by then.
+    #[cfg(feature = "signature")]
+    pub fn set_signing_key(&mut self, keypair: Keypair) -> Result<()> {
+        if self.meta.hash_algorithm()?.is_none() {
+            self.set_hash_algorithm(HashAlgorithm::default())?;
+        }
+        self.signing_key = Some(keypair);
+        Ok(())
+    }
+
     pub fn set_file_attr<S: AsRef<str>>(&mut self, key: S, value: Vec<u8>) -> Result<()> {
         let key = self.meta.attr_key_or_create(key.as_ref());
 
@@ -356,9 +1409,30 @@ impl BoxFileWriter {
 
     #[inline(always)]
     unsafe fn read_data(&self, header: &FileRecord) -> std::io::Result<memmap::Mmap> {
+        match header.attr(&self.meta, INLINE_ATTR) {
+            Some(data) if !data.is_empty() => return map_anon(data),
+            _ => {}
+        }
+        if header.attr(&self.meta, SOLID_ATTR).is_some()
+            || header.attr(&self.meta, CHUNKS_ATTR).is_some()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "data of `{}` is shared with other records and cannot be mapped on its own",
+                    header.name
+                ),
+            ));
+        }
+
+        // Empty maps are refused, other than at an offset within a page.
+        if header.length == 0 {
+            return MmapOptions::new().offset(1).len(0).map(self.file.get_ref());
+        }
+
         MmapOptions::new()
             .offset(header.data.get())
             .len(header.length as usize)
-            .map(&self.file.get_ref())
+            .map(self.file.get_ref())
     }
 }
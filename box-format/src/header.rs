@@ -6,22 +6,83 @@ pub(crate) struct BoxHeader {
     pub(crate) version: u32,
     pub(crate) alignment: u64,
     pub(crate) trailer: Option<NonZeroU64>,
+    pub(crate) trailer_checksum: Option<u32>,
+    pub(crate) flags: u32,
 }
 
 // Make some attempt to not accidentally load plain text files,
 // and also make it break almost immediately in any UTF-8 compliant text parser.
 pub(crate) const MAGIC_BYTES: &[u8; 4] = b"\xffBOX";
 
+/// The version of the format written. Version 0 archives have a 24-byte header, without the
+/// trailer checksum and flags, and their records are not framed with their length.
+pub(crate) const VERSION: u32 = 1;
+
+/// The size of the serialized header, which is where the first record's data may begin.
+pub(crate) const HEADER_SIZE: u64 = 32;
+
+/// The trailer and index are sealed with the content key; only the attributes needed to
+/// recover the key are readable.
+pub(crate) const FLAG_ENCRYPTED_METADATA: u32 = 0x1;
+
+/// Records take some of their attributes from sets kept with the archive attributes, which
+/// readers that do not know of them would miss.
+pub(crate) const FLAG_ATTR_SETS: u32 = 0x2;
+
+/// The trailer and index are compressed, and start with the compression used. Sealed trailers
+/// are always compressed along with their encryption, so they never have this flag.
+pub(crate) const FLAG_COMPRESSED_TRAILER: u32 = 0x4;
+
+/// The trailer is preceded by a record index, so that records can be read without reading
+/// those before them. It holds the offset of the attribute keys and then of each record from
+/// the start of the trailer, once decompressed, followed by the number of offsets, all as 8 LE
+/// bytes. Sealed trailers have none.
+pub(crate) const FLAG_RECORD_INDEX: u32 = 0x8;
+
+const KNOWN_FLAGS: u32 =
+    FLAG_ENCRYPTED_METADATA | FLAG_ATTR_SETS | FLAG_COMPRESSED_TRAILER | FLAG_RECORD_INDEX;
+
 impl BoxHeader {
     pub(crate) fn new(trailer: Option<NonZeroU64>) -> BoxHeader {
         BoxHeader {
             magic_bytes: *MAGIC_BYTES,
-            version: 0x0,
+            version: VERSION,
             alignment: 0,
             trailer,
+            trailer_checksum: None,
+            flags: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_encrypted_metadata(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED_METADATA != 0
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_compressed_trailer(&self) -> bool {
+        self.flags & FLAG_COMPRESSED_TRAILER != 0
+    }
+
+    #[inline(always)]
+    pub(crate) fn has_record_index(&self) -> bool {
+        self.flags & FLAG_RECORD_INDEX != 0
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_flag(&mut self, flag: u32, enabled: bool) {
+        if enabled {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
         }
     }
 
+    /// Any flag not known to this implementation may change how the archive must be read.
+    pub(crate) fn unknown_flags(&self) -> u32 {
+        self.flags & !KNOWN_FLAGS
+    }
+
     pub(crate) fn with_alignment(alignment: u64) -> BoxHeader {
         let mut header = BoxHeader::default();
         header.alignment = alignment;
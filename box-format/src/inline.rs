@@ -0,0 +1,7 @@
+//! Inline files, whose data is small enough to be kept in the trailer with their record rather
+//! than in a data region of its own.
+
+/// The attribute key holding the stored data of an inline file. The record has a `length` of
+/// zero, as nothing is stored outside the trailer, and its `data` is only a placeholder. Files
+/// are inlined without compression, so the value is the file itself.
+pub const INLINE_ATTR: &str = "inline";
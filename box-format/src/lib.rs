@@ -1,26 +1,69 @@
+mod acl;
+mod attr;
+mod attr_set;
+mod blocks;
+mod checksum;
+mod chunks;
 mod compression;
 #[cfg(feature = "reader")]
 mod de;
+mod encryption;
 mod file;
 mod header;
+mod inline;
+mod owner;
 pub mod path;
 mod record;
 #[cfg(feature = "writer")]
 mod ser;
+mod signature;
+mod solid;
+mod sparse;
+mod time;
+mod xattr;
 
 #[cfg(feature = "ffi")]
 pub mod ffi;
 
 pub use self::file::Inode;
+#[cfg(feature = "writer")]
+pub use acl::read_acls;
+pub use acl::{ACL_ATTR, DEFAULT_ACL_ATTR};
+pub use attr::{AttrType, AttrValue, MODE_ATTR, WELL_KNOWN_ATTRS};
+pub use attr_set::{ATTR_SETS_ATTR, ATTR_SET_ATTR};
+pub use blocks::BLOCKS_ATTR;
+pub use checksum::{HashAlgorithm, CRC32_ATTR, HASH_ALGORITHM_ATTR, HASH_ATTR, KEYED_HASH_ATTR};
+pub use chunks::{CHUNKS_ATTR, CHUNK_TABLE_ATTR};
 pub use compression::Compression;
+#[cfg(feature = "encryption")]
+pub use encryption::{Identity, Recipient};
+pub use encryption::{ENCRYPTION_ATTR, ENCRYPTION_NONCE_ATTR};
+#[cfg(feature = "reader")]
+pub use file::lazy::LazyBoxFileReader;
 #[cfg(feature = "reader")]
 pub use file::reader::BoxFileReader;
 #[cfg(feature = "writer")]
 pub use file::writer::BoxFileWriter;
 pub use file::{AttrMap, BoxMetadata};
 use header::BoxHeader;
+pub use inline::INLINE_ATTR;
+#[cfg(all(feature = "writer", unix))]
+pub use owner::{group_name, user_name};
+pub use owner::{GID_ATTR, GROUP_ATTR, UID_ATTR, USER_ATTR};
 pub use path::BoxPath;
-pub use record::{DirectoryRecord, FileRecord, LinkRecord, Record};
+pub use record::{
+    DirectoryRecord, FileRecord, HardLinkRecord, LinkRecord, Record, SpecialFileType,
+    SpecialRecord, UnknownRecord,
+};
+pub use signature::SIGNATURE_ATTR;
+#[cfg(feature = "signature")]
+pub use signature::{Keypair, PublicKey, SecretKey, Signature};
+pub use solid::SOLID_ATTR;
+pub use sparse::SPARSE_ATTR;
+pub use time::{Timestamp, ACCESSED_ATTR, CHANGED_ATTR, CREATED_ATTR, MODIFIED_ATTR};
+#[cfg(feature = "writer")]
+pub use xattr::read_xattrs;
+pub use xattr::XATTR_PREFIX;
 
 #[doc(hidden)]
 pub use comde;
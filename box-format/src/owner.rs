@@ -0,0 +1,110 @@
+//! The users and groups that own files, which are recorded by name as well as by id so that
+//! extracted files can be given to the same users on a system where their ids differ.
+
+/// The attribute key under which the uid of the user owning a file is kept, as 4 LE bytes.
+pub const UID_ATTR: &str = "unix.uid";
+
+/// The attribute key under which the gid of the group owning a file is kept, as 4 LE bytes.
+pub const GID_ATTR: &str = "unix.gid";
+
+/// The attribute key under which the name of the user owning a file is kept, as UTF-8.
+pub const USER_ATTR: &str = "unix.user";
+
+/// The attribute key under which the name of the group owning a file is kept, as UTF-8.
+pub const GROUP_ATTR: &str = "unix.group";
+
+/// Calls one of the reentrant `getpw*_r` or `getgr*_r` functions, growing its buffer as asked,
+/// and reads what is needed from the entry found before the buffer is dropped.
+#[cfg(all(feature = "reader", unix))]
+fn lookup<T, R, F, G>(f: F, read: G) -> Option<R>
+where
+    F: Fn(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+    G: FnOnce(&T) -> Option<R>,
+{
+    let mut buf = vec![0 as libc::c_char; 1024];
+    let mut entry = std::mem::MaybeUninit::<T>::uninit();
+    let mut result = std::ptr::null_mut();
+
+    loop {
+        match f(entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) {
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            0 if !result.is_null() => return read(unsafe { &*result }),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(all(feature = "writer", unix))]
+fn c_name(ptr: *const libc::c_char) -> Option<String> {
+    unsafe { std::ffi::CStr::from_ptr(ptr) }
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+/// The name of the user with the given uid, if there is one.
+#[cfg(all(feature = "writer", unix))]
+pub fn user_name(uid: u32) -> Option<String> {
+    lookup(
+        |entry, buf, len, result| unsafe { libc::getpwuid_r(uid, entry, buf, len, result) },
+        |entry: &libc::passwd| c_name(entry.pw_name),
+    )
+}
+
+/// The name of the group with the given gid, if there is one.
+#[cfg(all(feature = "writer", unix))]
+pub fn group_name(gid: u32) -> Option<String> {
+    lookup(
+        |entry, buf, len, result| unsafe { libc::getgrgid_r(gid, entry, buf, len, result) },
+        |entry: &libc::group| c_name(entry.gr_name),
+    )
+}
+
+#[cfg(all(feature = "reader", unix))]
+pub(crate) fn user_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    lookup(
+        |entry, buf, len, result| unsafe {
+            libc::getpwnam_r(name.as_ptr(), entry, buf, len, result)
+        },
+        |entry: &libc::passwd| Some(entry.pw_uid),
+    )
+}
+
+#[cfg(all(feature = "reader", unix))]
+pub(crate) fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    lookup(
+        |entry, buf, len, result| unsafe {
+            libc::getgrnam_r(name.as_ptr(), entry, buf, len, result)
+        },
+        |entry: &libc::group| Some(entry.gr_gid),
+    )
+}
+
+#[cfg(all(feature = "reader", unix))]
+pub(crate) fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Sets the owner and group of a file without following symbolic links, leaving either as
+/// it is if not given.
+#[cfg(all(feature = "reader", unix))]
+pub(crate) fn set_owner(
+    path: &std::path::Path,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // An id of -1 leaves it unchanged
+    let uid = uid.unwrap_or(u32::MAX) as libc::uid_t;
+    let gid = gid.unwrap_or(u32::MAX) as libc::gid_t;
+
+    match unsafe { libc::lchown(path.as_ptr(), uid, gid) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
@@ -1,13 +1,44 @@
-use crate::{compression::Compression, path::BoxPath, AttrMap};
+use crate::{
+    attr::{AttrValue, MODE_ATTR},
+    compression::Compression,
+    owner::{GID_ATTR, GROUP_ATTR, UID_ATTR, USER_ATTR},
+    path::BoxPath,
+    time::{Timestamp, ACCESSED_ATTR, CHANGED_ATTR, CREATED_ATTR, MODIFIED_ATTR},
+    xattr::XATTR_PREFIX,
+    AttrMap,
+};
 
 use crate::file::{BoxMetadata, Inode};
 use std::num::NonZeroU64;
 
+/// The kinds of record, as stored in the low bits of the first byte of each record.
+pub(crate) const RECORD_FILE: u8 = 0x0;
+pub(crate) const RECORD_DIRECTORY: u8 = 0x1;
+pub(crate) const RECORD_LINK: u8 = 0x2;
+pub(crate) const RECORD_HARD_LINK: u8 = 0x3;
+pub(crate) const RECORD_SPECIAL: u8 = 0x4;
+
+/// The bits of the first byte of a record that hold its kind.
+pub(crate) const RECORD_KIND_MASK: u8 = 0x3f;
+
+/// Set in the first byte of records followed by the length of the rest of them as a vu64,
+/// which is every record written since lengths were added. Records without it are read as
+/// they were before, and cannot be skipped.
+pub(crate) const RECORD_FRAMED: u8 = 0x80;
+
+/// Set in the first byte of framed records that readers may skip if they do not know their
+/// kind, rather than failing to read the archive.
+pub(crate) const RECORD_OPTIONAL: u8 = 0x40;
+
 #[derive(Debug)]
 pub enum Record {
     File(FileRecord),
     Directory(DirectoryRecord),
     Link(LinkRecord),
+    HardLink(HardLinkRecord),
+    Special(SpecialRecord),
+    /// A record of an optional kind that this version does not know.
+    Unknown(UnknownRecord),
 }
 
 impl Record {
@@ -59,12 +90,40 @@ impl Record {
         }
     }
 
+    #[inline(always)]
+    pub fn as_hard_link(&self) -> Option<&HardLinkRecord> {
+        match self {
+            Record::HardLink(link) => Some(link),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_special(&self) -> Option<&SpecialRecord> {
+        match self {
+            Record::Special(special) => Some(special),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_unknown(&self) -> Option<&UnknownRecord> {
+        match self {
+            Record::Unknown(unknown) => Some(unknown),
+            _ => None,
+        }
+    }
+
+    /// The name of the record, which is empty for records of an unknown kind.
     #[inline(always)]
     pub fn name(&self) -> &str {
         match self {
             Record::File(file) => &*file.name,
             Record::Directory(dir) => &*dir.name,
             Record::Link(link) => &*link.name,
+            Record::HardLink(link) => &link.name,
+            Record::Special(special) => &special.name,
+            Record::Unknown(_) => "",
         }
     }
 
@@ -74,12 +133,75 @@ impl Record {
         self.attrs().get(&key).map(|x| &**x)
     }
 
+    /// The extended attributes of the record by name, in order of name.
+    pub fn xattrs<'a>(&'a self, metadata: &'a BoxMetadata) -> Vec<(&'a str, &'a [u8])> {
+        let mut xattrs = self
+            .attrs()
+            .iter()
+            .filter_map(|(key, value)| {
+                let name = metadata.attr_keys.get(*key)?.strip_prefix(XATTR_PREFIX)?;
+                Some((name, &**value))
+            })
+            .collect::<Vec<_>>();
+        xattrs.sort_unstable_by_key(|(name, _)| *name);
+        xattrs
+    }
+
+    /// The value of an attribute, decoded as the type its key is known to have, which is
+    /// `None` if it is missing or malformed.
+    pub fn attr_value<S: AsRef<str>>(&self, metadata: &BoxMetadata, key: S) -> Option<AttrValue> {
+        let key = key.as_ref();
+        AttrValue::from_key(key, self.attr(metadata, key)?)
+    }
+
+    /// The Unix mode of the record, with both its type and permission bits.
+    pub fn mode(&self, metadata: &BoxMetadata) -> Option<u32> {
+        self.attr_value(metadata, MODE_ATTR)?.as_u32()
+    }
+
+    pub fn uid(&self, metadata: &BoxMetadata) -> Option<u32> {
+        self.attr_value(metadata, UID_ATTR)?.as_u32()
+    }
+
+    pub fn gid(&self, metadata: &BoxMetadata) -> Option<u32> {
+        self.attr_value(metadata, GID_ATTR)?.as_u32()
+    }
+
+    /// The name of the user owning the record.
+    pub fn user<'a>(&'a self, metadata: &'a BoxMetadata) -> Option<&'a str> {
+        std::str::from_utf8(self.attr(metadata, USER_ATTR)?).ok()
+    }
+
+    /// The name of the group owning the record.
+    pub fn group<'a>(&'a self, metadata: &'a BoxMetadata) -> Option<&'a str> {
+        std::str::from_utf8(self.attr(metadata, GROUP_ATTR)?).ok()
+    }
+
+    pub fn created(&self, metadata: &BoxMetadata) -> Option<Timestamp> {
+        self.attr_value(metadata, CREATED_ATTR)?.as_timestamp()
+    }
+
+    pub fn modified(&self, metadata: &BoxMetadata) -> Option<Timestamp> {
+        self.attr_value(metadata, MODIFIED_ATTR)?.as_timestamp()
+    }
+
+    pub fn accessed(&self, metadata: &BoxMetadata) -> Option<Timestamp> {
+        self.attr_value(metadata, ACCESSED_ATTR)?.as_timestamp()
+    }
+
+    pub fn changed(&self, metadata: &BoxMetadata) -> Option<Timestamp> {
+        self.attr_value(metadata, CHANGED_ATTR)?.as_timestamp()
+    }
+
     #[inline(always)]
     pub(crate) fn attrs(&self) -> &AttrMap {
         match self {
             Record::Directory(dir) => &dir.attrs,
             Record::File(file) => &file.attrs,
             Record::Link(link) => &link.attrs,
+            Record::HardLink(link) => &link.attrs,
+            Record::Special(special) => &special.attrs,
+            Record::Unknown(unknown) => &unknown.attrs,
         }
     }
 
@@ -89,6 +211,9 @@ impl Record {
             Record::Directory(dir) => &mut dir.attrs,
             Record::File(file) => &mut file.attrs,
             Record::Link(link) => &mut link.attrs,
+            Record::HardLink(link) => &mut link.attrs,
+            Record::Special(special) => &mut special.attrs,
+            Record::Unknown(unknown) => &mut unknown.attrs,
         }
     }
 }
@@ -119,6 +244,97 @@ impl LinkRecord {
     }
 }
 
+/// Another name for a file, which shares its data and attributes with the file record it
+/// refers to.
+#[derive(Debug)]
+pub struct HardLinkRecord {
+    pub name: String,
+
+    /// The inode of the file record that this is another name for.
+    pub target: Inode,
+
+    /// Optional attributes for the given paths, such as Windows or Unix ACLs, last accessed time, etc.
+    pub attrs: AttrMap,
+}
+
+impl HardLinkRecord {
+    #[inline(always)]
+    pub fn attr<S: AsRef<str>>(&self, metadata: &BoxMetadata, key: S) -> Option<&[u8]> {
+        let key = metadata.attr_key(key.as_ref())?;
+        self.attrs.get(&key).map(|x| &**x)
+    }
+
+    #[inline(always)]
+    pub fn upcast(self) -> Record {
+        Record::HardLink(self)
+    }
+}
+
+/// The kind of file a special file record stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFileType {
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Socket,
+}
+
+/// A file with no data of its own, such as a FIFO, a device node or a socket.
+#[derive(Debug)]
+pub struct SpecialRecord {
+    pub name: String,
+
+    pub file_type: SpecialFileType,
+
+    /// The major and minor device numbers of a device node, which are zero for other files.
+    pub major: u32,
+    pub minor: u32,
+
+    /// Optional attributes for the given paths, such as Windows or Unix ACLs, last accessed time, etc.
+    pub attrs: AttrMap,
+}
+
+impl SpecialRecord {
+    #[inline(always)]
+    pub fn attr<S: AsRef<str>>(&self, metadata: &BoxMetadata, key: S) -> Option<&[u8]> {
+        let key = metadata.attr_key(key.as_ref())?;
+        self.attrs.get(&key).map(|x| &**x)
+    }
+
+    /// The Unix mode of the special file, with both its type and permission bits.
+    pub fn mode(&self, metadata: &BoxMetadata) -> Option<u32> {
+        AttrValue::from_key(MODE_ATTR, self.attr(metadata, MODE_ATTR)?)?.as_u32()
+    }
+
+    #[inline(always)]
+    pub fn upcast(self) -> Record {
+        Record::Special(self)
+    }
+}
+
+/// A record of a kind that this version does not know, which was marked as optional so that
+/// it could be skipped. It is kept as it was read, and written back unchanged when the archive
+/// is appended to. Attribute keys are only ever added to and attribute sets keep their indices
+/// while an archive has such records, so that any it refers to stay as they were.
+#[derive(Debug)]
+pub struct UnknownRecord {
+    /// The kind of the record, without the bits marking it as framed and optional.
+    pub kind: u8,
+
+    /// The rest of the record, as stored after its length.
+    pub data: Vec<u8>,
+
+    /// Always empty, as the attributes of a record of an unknown kind cannot be found.
+    pub attrs: AttrMap,
+}
+
+impl UnknownRecord {
+    #[inline(always)]
+    pub fn upcast(self) -> Record {
+        Record::Unknown(self)
+    }
+}
+
 #[derive(Debug)]
 pub struct DirectoryRecord {
     /// The name of the directory
@@ -160,7 +376,7 @@ pub struct FileRecord {
     /// The exact length of the data as written, ignoring any padding.
     pub length: u64,
 
-    /// A hint for the size of the content when decompressed. Do not trust in absolute terms.
+    /// The length of the content when decompressed, checked against the output when the reader verifies checksums.
     pub decompressed_length: u64,
 
     /// The position of the data in the file
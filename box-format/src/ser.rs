@@ -4,8 +4,16 @@ use byteorder::{LittleEndian, WriteBytesExt};
 use fastvlq::WriteVu64Ext;
 
 use crate::{
-    file::Inode, AttrMap, BoxHeader, BoxMetadata, BoxPath, Compression, DirectoryRecord,
-    FileRecord, LinkRecord, Record,
+    attr_set::{AttrSets, ATTR_SETS_ATTR},
+    chunks::Chunk,
+    file::Inode,
+    AttrMap, BoxHeader, BoxMetadata, BoxPath, Compression, DirectoryRecord, FileRecord,
+    HardLinkRecord, LinkRecord, Record, SpecialFileType, SpecialRecord,
+};
+
+use crate::record::{
+    RECORD_DIRECTORY, RECORD_FILE, RECORD_FRAMED, RECORD_HARD_LINK, RECORD_LINK, RECORD_OPTIONAL,
+    RECORD_SPECIAL,
 };
 
 pub(crate) trait Serialize {
@@ -42,17 +50,20 @@ impl Serialize for AttrMap {
         // Write the length in bytes so implementations can skip the entire map if they so choose.
 
         // Write it as u64::MAX, then seek back
-        let size_index = writer.seek(SeekFrom::Current(0))?;
+        let size_index = writer.stream_position()?;
         writer.write_u64::<LittleEndian>(std::u64::MAX)?;
         writer.write_vu64(self.len() as u64)?;
 
-        for (key, value) in self.iter() {
+        // In order of key, so that the same attributes are always written the same way
+        let mut attrs = self.iter().collect::<Vec<_>>();
+        attrs.sort_unstable_by_key(|(key, _)| **key);
+        for (key, value) in attrs {
             writer.write_vu64(*key as u64)?;
             value.write(writer)?;
         }
 
         // Go back and write size
-        let cur_index = writer.seek(SeekFrom::Current(0))?;
+        let cur_index = writer.stream_position()?;
         writer.seek(SeekFrom::Start(size_index))?;
         writer.write_u64::<LittleEndian>(cur_index - size_index)?;
         writer.seek(SeekFrom::Start(cur_index))?;
@@ -75,48 +86,157 @@ impl Serialize for Inode {
 
 impl Serialize for FileRecord {
     fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
-        // Record id - 0 for file
-        writer.write_u8(0x0)?;
+        self.write_with_attrs(&self.attrs, writer)
+    }
+}
 
+impl FileRecord {
+    fn write_with_attrs<W: Write + Seek>(
+        &self,
+        attrs: &AttrMap,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
         writer.write_u8(self.compression.id())?;
         writer.write_u64::<LittleEndian>(self.length)?;
         writer.write_u64::<LittleEndian>(self.decompressed_length)?;
         writer.write_u64::<LittleEndian>(self.data.get())?;
 
         self.name.write(writer)?;
-        self.attrs.write(writer)
+        attrs.write(writer)
     }
 }
 
 impl Serialize for DirectoryRecord {
     fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
-        // Record id - 1 for directory
-        writer.write_u8(0x1)?;
+        self.write_with_attrs(&self.attrs, writer)
+    }
+}
 
+impl DirectoryRecord {
+    fn write_with_attrs<W: Write + Seek>(
+        &self,
+        attrs: &AttrMap,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
         self.name.write(writer)?;
         self.inodes.write(writer)?;
-        self.attrs.write(writer)
+        attrs.write(writer)
     }
 }
 
 impl Serialize for LinkRecord {
     fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
-        // Record id - 2 for symlink
-        writer.write_u8(0x2)?;
+        self.write_with_attrs(&self.attrs, writer)
+    }
+}
 
+impl LinkRecord {
+    fn write_with_attrs<W: Write + Seek>(
+        &self,
+        attrs: &AttrMap,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
         self.name.write(writer)?;
         self.target.write(writer)?;
-        self.attrs.write(writer)
+        attrs.write(writer)
+    }
+}
+
+impl Serialize for HardLinkRecord {
+    fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.write_with_attrs(&self.attrs, writer)
+    }
+}
+
+impl HardLinkRecord {
+    fn write_with_attrs<W: Write + Seek>(
+        &self,
+        attrs: &AttrMap,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        self.name.write(writer)?;
+        self.target.write(writer)?;
+        attrs.write(writer)
+    }
+}
+
+impl Serialize for SpecialFileType {
+    fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_u8(match self {
+            SpecialFileType::Fifo => 0x0,
+            SpecialFileType::CharDevice => 0x1,
+            SpecialFileType::BlockDevice => 0x2,
+            SpecialFileType::Socket => 0x3,
+        })
+    }
+}
+
+impl Serialize for SpecialRecord {
+    fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.write_with_attrs(&self.attrs, writer)
+    }
+}
+
+impl SpecialRecord {
+    fn write_with_attrs<W: Write + Seek>(
+        &self,
+        attrs: &AttrMap,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        self.name.write(writer)?;
+        self.file_type.write(writer)?;
+        writer.write_u32::<LittleEndian>(self.major)?;
+        writer.write_u32::<LittleEndian>(self.minor)?;
+        attrs.write(writer)
     }
 }
 
 impl Serialize for Record {
     fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
-        match self {
-            Record::File(file) => file.write(writer),
-            Record::Directory(directory) => directory.write(writer),
-            Record::Link(link) => link.write(writer),
-        }
+        self.write_with_attrs(self.attrs(), writer)
+    }
+}
+
+impl Record {
+    /// Writes the record with the given attributes in place of its own.
+    fn write_with_attrs<W: Write + Seek>(
+        &self,
+        attrs: &AttrMap,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        // The record is written out first so that its length can be written before it.
+        let mut body = std::io::Cursor::new(vec![]);
+        let kind = match self {
+            Record::File(file) => {
+                file.write_with_attrs(attrs, &mut body)?;
+                RECORD_FILE
+            }
+            Record::Directory(directory) => {
+                directory.write_with_attrs(attrs, &mut body)?;
+                RECORD_DIRECTORY
+            }
+            Record::Link(link) => {
+                link.write_with_attrs(attrs, &mut body)?;
+                RECORD_LINK
+            }
+            Record::HardLink(link) => {
+                link.write_with_attrs(attrs, &mut body)?;
+                RECORD_HARD_LINK
+            }
+            Record::Special(special) => {
+                special.write_with_attrs(attrs, &mut body)?;
+                RECORD_SPECIAL
+            }
+            Record::Unknown(unknown) => {
+                body.write_all(&unknown.data)?;
+                unknown.kind | RECORD_OPTIONAL
+            }
+        };
+
+        let body = body.into_inner();
+        writer.write_u8(kind | RECORD_FRAMED)?;
+        writer.write_vu64(body.len() as u64)?;
+        writer.write_all(&body)
     }
 }
 
@@ -125,16 +245,61 @@ impl Serialize for BoxHeader {
         writer.write_all(&self.magic_bytes)?;
         writer.write_u32::<LittleEndian>(self.version)?;
         writer.write_u64::<LittleEndian>(self.alignment)?;
-        writer.write_u64::<LittleEndian>(self.trailer.map(|x| x.get()).unwrap_or(0))
+        writer.write_u64::<LittleEndian>(self.trailer.map(|x| x.get()).unwrap_or(0))?;
+        writer.write_u32::<LittleEndian>(self.trailer_checksum.unwrap_or(0))?;
+        writer.write_u32::<LittleEndian>(self.flags)
     }
 }
 
 impl Serialize for BoxMetadata {
     fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.write_trailer(writer).map(|_| ())
+    }
+}
+
+impl BoxMetadata {
+    /// Writes the trailer and index, returning the offsets of the attribute keys and then of
+    /// each record from the start of the trailer, as kept in a record index.
+    pub(crate) fn write_trailer<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+    ) -> std::io::Result<Vec<u64>> {
+        let start = writer.stream_position()?;
+        let mut offsets = Vec::with_capacity(self.inodes.len() + 1);
+        offsets.push(0);
+
         self.root.write(writer)?;
-        self.inodes.write(writer)?;
+
+        // Records refer to the sets of attributes they share with others, which are kept with
+        // the attributes of the archive.
+        let sets = AttrSets::new(self);
+        writer.write_vu64(self.inodes.len() as u64)?;
+        for (index, record) in self.inodes.iter().enumerate() {
+            offsets.push(writer.stream_position()? - start);
+            match sets
+                .as_ref()
+                .and_then(|x| x.record_attrs(index, record.attrs()))
+            {
+                Some(attrs) => record.write_with_attrs(&attrs, writer)?,
+                None => record.write(writer)?,
+            }
+        }
+        offsets[0] = writer.stream_position()? - start;
         self.attr_keys.write(writer)?;
-        self.attrs.write(writer)?;
+
+        match sets.filter(|x| !x.sets.is_empty()) {
+            Some(sets) => {
+                let mut value = std::io::Cursor::new(vec![]);
+                sets.sets.write(&mut value)?;
+                let mut attrs = self.attrs.clone();
+                attrs.insert(
+                    self.attr_key(ATTR_SETS_ATTR).expect("interned"),
+                    value.into_inner(),
+                );
+                attrs.write(writer)?;
+            }
+            None => self.attrs.write(writer)?,
+        }
 
         // Write the index
         let mut builder = pathtrie::PathTrie::new();
@@ -143,7 +308,19 @@ impl Serialize for BoxMetadata {
             builder.insert(x.path, x.inode.get())
         }
 
-        builder.write_fst(writer)
+        builder.write_fst(writer)?;
+        Ok(offsets)
+    }
+}
+
+impl Serialize for Chunk {
+    fn write<W: Write + Seek>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.data)?;
+        writer.write_u64::<LittleEndian>(self.length)?;
+        writer.write_u64::<LittleEndian>(self.decompressed_length)?;
+        self.compression.write(writer)?;
+        self.nonce.write(writer)?;
+        self.digest.write(writer)
     }
 }
 
@@ -0,0 +1,111 @@
+#[cfg(feature = "signature")]
+use std::io::{self, Cursor};
+
+#[cfg(feature = "signature")]
+pub use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
+#[cfg(feature = "signature")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "signature")]
+use crate::{
+    checksum::{HASH_ATTR, KEYED_HASH_ATTR},
+    de::DeserializeOwned,
+    file::Inode,
+    inline::INLINE_ATTR,
+    AttrMap, BoxMetadata, Record,
+};
+
+/// The archive attribute key under which an embedded ed25519 signature is stored, as 64 bytes.
+pub const SIGNATURE_ATTR: &str = "signature";
+
+#[cfg(feature = "signature")]
+/// Computes the SHA-256 digest that is signed for an archive from its serialized trailer and index.
+///
+/// Archive attributes are hashed in order of their key names, leaving out the signature itself,
+/// so that a signature can be embedded in the trailer it covers.
+pub(crate) fn trailer_digest(trailer: &[u8]) -> io::Result<[u8; 32]> {
+    let mut cursor = Cursor::new(trailer);
+    Vec::<Inode>::deserialize_owned(&mut cursor)?;
+    Vec::<Record>::deserialize_owned(&mut cursor)?;
+    let attr_keys = Vec::<String>::deserialize_owned(&mut cursor)?;
+    let attrs_start = cursor.position() as usize;
+    let attrs = AttrMap::deserialize_owned(&mut cursor)?;
+    let attrs_end = cursor.position() as usize;
+
+    let mut named_attrs = Vec::with_capacity(attrs.len());
+    for (key, value) in attrs.iter() {
+        let name = attr_keys.get(*key).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown attribute key: {}", key),
+            )
+        })?;
+
+        if name != SIGNATURE_ATTR {
+            named_attrs.push((name, value));
+        }
+    }
+    named_attrs.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(&trailer[..attrs_start]);
+    for (name, value) in named_attrs {
+        hasher.update((name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    }
+    hasher.update(&trailer[attrs_end..]);
+
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&hasher.finalize());
+    Ok(digest)
+}
+
+#[cfg(feature = "signature")]
+pub(crate) fn sign(keypair: &Keypair, trailer: &[u8]) -> io::Result<Signature> {
+    use ed25519_dalek::Signer;
+
+    let digest = trailer_digest(trailer)?;
+    Ok(keypair.sign(&digest))
+}
+
+#[cfg(feature = "signature")]
+pub(crate) fn verify(
+    public_key: &PublicKey,
+    signature: &Signature,
+    trailer: &[u8],
+) -> io::Result<()> {
+    let digest = trailer_digest(trailer)?;
+    public_key
+        .verify_strict(&digest, signature)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "signature verification failed"))
+}
+
+/// Checks that every file has a digest made with the recorded hash algorithm, which is what
+/// ties its data to a signature over the trailer. Inline files are in the trailer themselves.
+#[cfg(feature = "signature")]
+pub(crate) fn check_digests(meta: &BoxMetadata) -> io::Result<()> {
+    if meta.hash_algorithm()?.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "archive records no hash algorithm, so its file data cannot be signed",
+        ));
+    }
+
+    let unhashed = meta.inodes.iter().filter_map(Record::as_file).find(|x| {
+        [HASH_ATTR, KEYED_HASH_ATTR, INLINE_ATTR]
+            .iter()
+            .all(|key| x.attr(meta, key).is_none())
+    });
+    match unhashed {
+        Some(file) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "no digest recorded for `{}`, so its data cannot be signed",
+                file.name
+            ),
+        )),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,71 @@
+//! Solid blocks, in which consecutive small files share one compressed stream so that they
+//! compress as well as they would in a single file.
+
+#[cfg(feature = "reader")]
+use std::collections::VecDeque;
+#[cfg(feature = "reader")]
+use std::sync::Arc;
+
+#[cfg(feature = "writer")]
+use crate::{compression::Compression, file::Inode};
+
+/// The attribute key marking a file stored in a solid block: the offset of the file within
+/// the decompressed block, as 8 LE bytes. Every file in a block shares its `data`, `length`,
+/// compression and encryption nonce, and `decompressed_length` is the size of the file alone.
+pub const SOLID_ATTR: &str = "solid";
+
+/// The most decompressed solid blocks a reader keeps at once.
+#[cfg(feature = "reader")]
+const BLOCK_CACHE_LEN: usize = 4;
+
+/// Files gathered by a writer for a solid block that has not been written yet.
+#[cfg(feature = "writer")]
+pub(crate) struct SolidBlock {
+    pub(crate) compression: Compression,
+    pub(crate) data: Vec<u8>,
+    pub(crate) inodes: Vec<Inode>,
+}
+
+#[cfg(feature = "writer")]
+impl SolidBlock {
+    pub(crate) fn new(compression: Compression) -> SolidBlock {
+        SolidBlock {
+            compression,
+            data: vec![],
+            inodes: vec![],
+        }
+    }
+}
+
+/// Recently decompressed solid blocks, keyed by the offset of their data and most recently
+/// used first, so that reading the files of a block in turn decompresses it only once.
+#[cfg(feature = "reader")]
+#[derive(Default)]
+pub(crate) struct BlockCache {
+    blocks: VecDeque<(u64, Arc<Vec<u8>>)>,
+}
+
+#[cfg(feature = "reader")]
+impl BlockCache {
+    pub(crate) fn get(&mut self, data: u64) -> Option<Arc<Vec<u8>>> {
+        let index = self.blocks.iter().position(|(x, _)| *x == data)?;
+        let entry = self.blocks.remove(index)?;
+        let block = entry.1.clone();
+        self.blocks.push_front(entry);
+        Some(block)
+    }
+
+    pub(crate) fn insert(&mut self, data: u64, block: Arc<Vec<u8>>) {
+        self.blocks.push_front((data, block));
+        self.blocks.truncate(BLOCK_CACHE_LEN);
+    }
+}
+
+#[cfg(feature = "reader")]
+impl std::fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.blocks.iter().map(|(data, _)| data))
+            .finish()
+    }
+}
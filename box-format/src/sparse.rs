@@ -0,0 +1,245 @@
+//! Sparse files, of which only the extents holding data are stored, so that large holes take
+//! no space in an archive and are recreated as holes on extraction.
+
+use std::io;
+#[cfg(feature = "writer")]
+use std::io::prelude::*;
+
+/// The attribute key marking a sparse file: the length of the file as 8 LE bytes, then the
+/// offset and length of each extent holding data as 8 LE bytes each. Only those extents are
+/// stored, one after another, so `decompressed_length` is their total length.
+pub const SPARSE_ATTR: &str = "sparse";
+
+/// Where the data of a sparse file lies, as `(offset, length)` pairs in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SparseMap {
+    pub(crate) length: u64,
+    pub(crate) extents: Vec<(u64, u64)>,
+}
+
+impl SparseMap {
+    #[cfg(feature = "writer")]
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.length.to_le_bytes().to_vec();
+        for (offset, length) in self.extents.iter() {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&length.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[cfg(feature = "reader")]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<SparseMap> {
+        if bytes.len() % 16 != 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed sparse map",
+            ));
+        }
+
+        let values = bytes
+            .chunks(8)
+            .map(|x| u64::from_le_bytes([x[0], x[1], x[2], x[3], x[4], x[5], x[6], x[7]]))
+            .collect::<Vec<_>>();
+
+        let map = SparseMap {
+            length: values[0],
+            extents: values[1..].chunks(2).map(|x| (x[0], x[1])).collect(),
+        };
+
+        let mut end = 0;
+        for (offset, length) in map.extents.iter() {
+            if *offset < end || offset.checked_add(*length).map(|x| x > map.length) != Some(false) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "sparse map extents are out of order or past the end of the file",
+                ));
+            }
+            end = offset + length;
+        }
+
+        Ok(map)
+    }
+
+    /// Finds the extents holding data in a file, or `None` if it has no holes. The position of
+    /// the file is left as it was.
+    #[cfg(all(feature = "writer", target_os = "linux"))]
+    pub(crate) fn of_file(file: &std::fs::File) -> io::Result<Option<SparseMap>> {
+        use std::os::unix::io::AsRawFd;
+
+        let length = file.metadata()?.len();
+        // Seeking for data and holes moves the position of the file, which is put back after
+        let position = (&mut &*file).stream_position()?;
+        let fd = file.as_raw_fd();
+        let seek = |offset: u64, whence| -> io::Result<Option<u64>> {
+            match unsafe { libc::lseek(fd, offset as libc::off_t, whence) } {
+                -1 => match io::Error::last_os_error() {
+                    // No data after the offset, so the rest of the file is a hole.
+                    e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+                    e => Err(e),
+                },
+                x => Ok(Some(x as u64)),
+            }
+        };
+
+        let mut extents = vec![];
+        let mut offset = 0;
+        while offset < length {
+            let start = match seek(offset, libc::SEEK_DATA)? {
+                Some(v) => v,
+                None => break,
+            };
+            let end = seek(start, libc::SEEK_HOLE)?.unwrap_or(length).min(length);
+            extents.push((start, end - start));
+            offset = end;
+        }
+        (&mut &*file).seek(io::SeekFrom::Start(position))?;
+
+        if extents == [(0, length)] || length == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(SparseMap { length, extents }))
+    }
+
+    #[cfg(all(feature = "writer", not(target_os = "linux")))]
+    pub(crate) fn of_file(_file: &std::fs::File) -> io::Result<Option<SparseMap>> {
+        Ok(None)
+    }
+}
+
+/// Reads the extents of a sparse file one after another, skipping its holes.
+#[cfg(feature = "writer")]
+pub(crate) struct ExtentReader<'a, R> {
+    inner: &'a mut R,
+    extents: std::slice::Iter<'a, (u64, u64)>,
+    remaining: u64,
+}
+
+#[cfg(feature = "writer")]
+impl<'a, R: Read + Seek> ExtentReader<'a, R> {
+    pub(crate) fn new(inner: &'a mut R, map: &'a SparseMap) -> ExtentReader<'a, R> {
+        ExtentReader {
+            inner,
+            extents: map.extents.iter(),
+            remaining: 0,
+        }
+    }
+}
+
+#[cfg(feature = "writer")]
+impl<R: Read + Seek> Read for ExtentReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            match self.extents.next() {
+                Some((offset, length)) => {
+                    self.inner.seek(io::SeekFrom::Start(*offset))?;
+                    self.remaining = *length;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sparse file is shorter than its extents",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes the stored extents of a sparse file where they belong, calling `hole` to move past
+/// each hole, which either writes zeros or seeks over them.
+#[cfg(feature = "reader")]
+pub(crate) struct SparseWriter<W> {
+    inner: W,
+    map: SparseMap,
+    extent: usize,
+    pos: u64,
+    hole: fn(&mut W, u64) -> io::Result<()>,
+}
+
+#[cfg(feature = "reader")]
+impl<W: io::Write> SparseWriter<W> {
+    pub(crate) fn new(
+        inner: W,
+        map: SparseMap,
+        hole: fn(&mut W, u64) -> io::Result<()>,
+    ) -> SparseWriter<W> {
+        SparseWriter {
+            inner,
+            map,
+            extent: 0,
+            pos: 0,
+            hole,
+        }
+    }
+
+    /// Moves past the hole at the end of the file, if any.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if self.map.extents[self.extent.min(self.map.extents.len())..]
+            .iter()
+            .any(|(_, length)| *length > 0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sparse file data is shorter than its extents",
+            ));
+        }
+        (self.hole)(&mut self.inner, self.map.length - self.pos)?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(feature = "reader")]
+impl<W: io::Write> io::Write for SparseWriter<W> {
+    fn write(&mut self, mut data: &[u8]) -> io::Result<usize> {
+        let len = data.len();
+        while !data.is_empty() {
+            let (offset, length) = match self.map.extents.get(self.extent) {
+                Some(v) => *v,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "sparse file data is longer than its extents",
+                    ))
+                }
+            };
+
+            if self.pos < offset {
+                (self.hole)(&mut self.inner, offset - self.pos)?;
+                self.pos = offset;
+            }
+
+            let n = data.len().min((offset + length - self.pos) as usize);
+            self.inner.write_all(&data[..n])?;
+            self.pos += n as u64;
+            data = &data[n..];
+            if self.pos == offset + length {
+                self.extent += 1;
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Fills a hole with zeros, for writers that cannot seek.
+#[cfg(feature = "reader")]
+pub(crate) fn write_zeros<W: io::Write>(writer: &mut W, len: u64) -> io::Result<()> {
+    io::copy(&mut io::Read::take(io::repeat(0), len), writer).map(|_| ())
+}
+
+/// Seeks over a hole, which the file system leaves unallocated.
+#[cfg(feature = "reader")]
+pub(crate) fn seek_hole<W: io::Seek>(writer: &mut W, len: u64) -> io::Result<()> {
+    writer.seek(io::SeekFrom::Current(len as i64)).map(|_| ())
+}
@@ -0,0 +1,89 @@
+//! Timestamps, which are kept in attributes as seconds since the Unix epoch as 8 LE bytes
+//! followed by nanoseconds as 4 LE bytes. Archives made before nanoseconds were recorded have
+//! the seconds alone, which are read as whole seconds.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The attribute key under which the time a file was created is kept, when the platform and
+/// file system record it. Archives made before `CHANGED_ATTR` existed kept the inode change
+/// time of files on Unix here instead.
+pub const CREATED_ATTR: &str = "created";
+
+/// The attribute key under which the time the data of a file was last modified is kept.
+pub const MODIFIED_ATTR: &str = "modified";
+
+/// The attribute key under which the time a file was last accessed is kept.
+pub const ACCESSED_ATTR: &str = "accessed";
+
+/// The attribute key under which the time the inode of a file was last changed is kept, which
+/// cannot be restored.
+pub const CHANGED_ATTR: &str = "changed";
+
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+/// A point in time as seconds and nanoseconds since the Unix epoch, which may be before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    /// Whole seconds since the epoch, rounded down.
+    pub secs: i64,
+    /// Nanoseconds after `secs`, less than a second.
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    /// Creates a timestamp, carrying whole seconds of `nanos` into `secs`.
+    pub fn new(secs: i64, nanos: u32) -> Timestamp {
+        Timestamp {
+            secs: secs + (nanos / NANOS_PER_SEC) as i64,
+            nanos: nanos % NANOS_PER_SEC,
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.secs.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.nanos.to_le_bytes());
+        bytes
+    }
+
+    /// Reads a timestamp attribute, which is `None` if it is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Timestamp> {
+        let nanos = match bytes.len() {
+            8 => 0,
+            12 => u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            _ => return None,
+        };
+        let mut secs = [0u8; 8];
+        secs.copy_from_slice(&bytes[..8]);
+        let secs = i64::from_le_bytes(secs);
+
+        if nanos >= NANOS_PER_SEC {
+            return None;
+        }
+        Some(Timestamp { secs, nanos })
+    }
+
+    /// The timestamp as a `SystemTime`, which is `None` if it is out of its range.
+    pub fn to_system_time(self) -> Option<SystemTime> {
+        let time = if self.secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(self.secs as u64))?
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(self.secs.unsigned_abs()))?
+        };
+        time.checked_add(Duration::from_nanos(self.nanos as u64))
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => Timestamp::new(d.as_secs() as i64, d.subsec_nanos()),
+            Err(e) => {
+                let d = e.duration();
+                match d.subsec_nanos() {
+                    0 => Timestamp::new(-(d.as_secs() as i64), 0),
+                    x => Timestamp::new(-(d.as_secs() as i64) - 1, NANOS_PER_SEC - x),
+                }
+            }
+        }
+    }
+}
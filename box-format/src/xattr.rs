@@ -0,0 +1,129 @@
+//! Extended attributes, which are kept in the attributes of a record under their name with a
+//! prefix, and are only read and written on Linux.
+
+#[cfg(feature = "writer")]
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// The prefix of the attribute keys under which the extended attributes of a record are kept,
+/// followed by the name of the extended attribute, such as `xattr.user.comment`.
+pub const XATTR_PREFIX: &str = "xattr.";
+
+/// The prefix of the names of the extended attributes in which Linux keeps POSIX ACLs.
+#[cfg(all(feature = "writer", target_os = "linux"))]
+pub(crate) const POSIX_ACL_PREFIX: &str = "system.posix_acl_";
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Calls `f` with a buffer of the size it asks for when given none, as the `*xattr` calls
+/// do, trying again if the value grows in between.
+#[cfg(all(feature = "writer", target_os = "linux"))]
+fn read_sized<F: Fn(*mut libc::c_void, usize) -> libc::ssize_t>(f: F) -> io::Result<Vec<u8>> {
+    loop {
+        let size = match f(std::ptr::null_mut(), 0) {
+            -1 => return Err(io::Error::last_os_error()),
+            x => x as usize,
+        };
+
+        let mut buf = vec![0u8; size];
+        match f(buf.as_mut_ptr() as *mut libc::c_void, size) {
+            -1 => match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(libc::ERANGE) => continue,
+                e => return Err(e),
+            },
+            x => {
+                buf.truncate(x as usize);
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+/// Reads the extended attributes of a file, without following symbolic links, keyed by
+/// their attribute keys. Attributes with names that are not UTF-8 are left out, as are POSIX
+/// ACLs, which are kept in an encoding of their own by `read_acls`.
+#[cfg(all(feature = "writer", target_os = "linux"))]
+pub fn read_xattrs<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, Vec<u8>>> {
+    let path = path.as_ref();
+    let c_path = c_path(path)?;
+    let names = match read_sized(|buf, size| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf as *mut libc::c_char, size)
+    }) {
+        Ok(v) => v,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = HashMap::new();
+    for name in names.split(|x| *x == 0).filter(|x| !x.is_empty()) {
+        let name = match std::str::from_utf8(name) {
+            Ok(v) if !v.starts_with(POSIX_ACL_PREFIX) => v,
+            _ => continue,
+        };
+        if let Some(value) = read_xattr(path, name)? {
+            xattrs.insert(format!("{}{}", XATTR_PREFIX, name), value);
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Reads one extended attribute of a file without following symbolic links, which is `None`
+/// if the file does not have it.
+#[cfg(all(feature = "writer", target_os = "linux"))]
+pub(crate) fn read_xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let path = c_path(path)?;
+    let name =
+        std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    match read_sized(|buf, size| unsafe {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, size)
+    }) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if [libc::ENODATA, libc::ENOTSUP].contains(&e.raw_os_error().unwrap_or(0)) => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(all(feature = "writer", not(target_os = "linux")))]
+pub fn read_xattrs<P: AsRef<Path>>(_path: P) -> io::Result<HashMap<String, Vec<u8>>> {
+    Ok(HashMap::new())
+}
+
+/// Sets extended attributes on a file, without following symbolic links.
+#[cfg(all(feature = "reader", target_os = "linux"))]
+pub(crate) fn write_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = c_path(path)?;
+    let name =
+        std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    match unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(feature = "reader", not(target_os = "linux")))]
+pub(crate) fn write_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "extended attributes are not supported on this platform",
+    ))
+}
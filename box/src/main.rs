@@ -2,15 +2,20 @@
 // Licensed under the EUPL 1.2 or later. See LICENSE file.
 
 use std::collections::{HashMap, HashSet};
-use std::io::{BufReader, Read, BufWriter, Write};
-use std::num::NonZeroU64;
+use std::convert::TryFrom;
+use std::io::{BufReader, BufWriter, Write};
+use std::num::{NonZeroU32, NonZeroU64};
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use box_format::{
-    path::PATH_PLATFORM_SEP, BoxFileReader, BoxFileWriter, BoxPath, Compression, Record,
+    path::PATH_PLATFORM_SEP, BoxFileReader, BoxFileWriter, BoxPath, Compression, FileRecord,
+    HashAlgorithm, Identity, Inode, Keypair, PublicKey, Recipient, Record, SecretKey, Signature,
+    SpecialFileType, Timestamp, ACCESSED_ATTR, ACL_ATTR, CHANGED_ATTR, CHUNKS_ATTR, CRC32_ATTR,
+    CREATED_ATTR, DEFAULT_ACL_ATTR, GID_ATTR, GROUP_ATTR, HASH_ALGORITHM_ATTR, HASH_ATTR,
+    INLINE_ATTR, MODE_ATTR, MODIFIED_ATTR, SIGNATURE_ATTR, SOLID_ATTR, SPARSE_ATTR, UID_ATTR,
+    USER_ATTR,
 };
-use byteorder::{LittleEndian, ReadBytesExt};
 use jwalk::{ClientState, DirEntry};
 use structopt::{clap::AppSettings::*, StructOpt};
 
@@ -49,6 +54,12 @@ fn parse_compression(src: &str) -> std::result::Result<Compression, Error> {
     Ok(compression)
 }
 
+fn parse_hash_algorithm(src: &str) -> std::result::Result<HashAlgorithm, Error> {
+    HashAlgorithm::from_name(src).ok_or_else(|| Error::UnknownHashAlgorithm {
+        name: src.to_string(),
+    })
+}
+
 #[inline(always)]
 #[allow(dead_code)] // used in Commands
 fn stored() -> Compression {
@@ -63,6 +74,14 @@ enum Commands {
         about = "List files of an archive [aliases: list]"
     )]
     List {
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to an X25519 secret key to decrypt the archive with, instead of a password"
+        )]
+        identity: Option<PathBuf>,
+
         #[structopt(
             name = "boxfile",
             parse(from_os_str),
@@ -95,6 +114,89 @@ enum Commands {
         )]
         compression: Compression,
 
+        #[structopt(
+            long,
+            parse(try_from_str = parse_compression),
+            possible_values = Compression::available_variants(),
+            help = "Compression to be used for the file names and attributes of the archive [default: none]"
+        )]
+        metadata_compression: Option<Compression>,
+
+        #[structopt(
+            long,
+            help = "Index where each record lies in the metadata, so that files can be looked up without reading it all"
+        )]
+        record_index: bool,
+
+        #[structopt(
+            short = "B",
+            long,
+            help = "Compress files in blocks of this many bytes for random access [unsigned 32-bit int, default: none]"
+        )]
+        block_size: Option<NonZeroU32>,
+
+        #[structopt(
+            long = "solid",
+            help = "Compress files smaller than this together in solid blocks of up to this size [unsigned 32-bit int, default: none]"
+        )]
+        solid_size: Option<NonZeroU32>,
+
+        #[structopt(
+            long = "inline",
+            help = "Store files smaller than this uncompressed in the archive metadata [unsigned 32-bit int, default: none]"
+        )]
+        inline_size: Option<NonZeroU32>,
+
+        #[structopt(
+            long = "hash",
+            parse(try_from_str = parse_hash_algorithm),
+            possible_values = HashAlgorithm::available_variants(),
+            help = "Cryptographic hash to record for each file [default: none]"
+        )]
+        hash_algorithm: Option<HashAlgorithm>,
+
+        #[structopt(
+            short = "D",
+            long = "dedup",
+            requires = "hash-algorithm",
+            help = "Store files with identical content only once [requires --hash]"
+        )]
+        deduplicate: bool,
+
+        #[structopt(
+            long,
+            requires = "hash-algorithm",
+            help = "Split files into content-defined chunks of about this many bytes, storing each distinct chunk once [requires --hash]"
+        )]
+        chunk_size: Option<NonZeroU32>,
+
+        #[structopt(
+            long = "sign",
+            name = "secret key",
+            parse(from_os_str),
+            help = "Sign the archive with the ed25519 secret key at this path"
+        )]
+        signing_key: Option<PathBuf>,
+
+        #[structopt(short, long, help = "Encrypt file data with a password")]
+        encrypt: bool,
+
+        #[structopt(
+            long = "recipient",
+            name = "public key",
+            parse(from_os_str),
+            number_of_values = 1,
+            help = "Encrypt file data to the X25519 public key at this path [may be repeated]"
+        )]
+        recipients: Vec<PathBuf>,
+
+        #[structopt(
+            short = "M",
+            long,
+            help = "Also encrypt file names, sizes and attributes [requires a password or recipient]"
+        )]
+        encrypt_metadata: bool,
+
         #[structopt(short, long, help = "Recursively handle provided paths")]
         recursive: bool,
 
@@ -127,6 +229,32 @@ enum Commands {
         )]
         output_path: Option<PathBuf>,
 
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to an X25519 secret key to decrypt the archive with, instead of a password"
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            long = "no-same-owner",
+            help = "Do not give extracted files their recorded owner, even when run as root"
+        )]
+        no_same_owner: bool,
+
+        #[structopt(
+            long = "numeric-owner",
+            help = "Give extracted files their recorded uid and gid rather than looking up users and groups by name"
+        )]
+        numeric_owner: bool,
+
+        #[structopt(
+            long = "no-verify",
+            help = "Do not check extracted data against the checksums recorded in the archive"
+        )]
+        no_verify: bool,
+
         #[structopt(
             name = "boxfile",
             parse(from_os_str),
@@ -141,6 +269,97 @@ enum Commands {
         about = "Test and verify integrity of archive [aliases: test]"
     )]
     Test {
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to an X25519 secret key to decrypt the archive with, instead of a password"
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            name = "boxfile",
+            parse(from_os_str),
+            help = "Path to the .box archive"
+        )]
+        path: PathBuf,
+    },
+
+    #[structopt(name = "sign", about = "Sign an archive with an ed25519 secret key")]
+    Sign {
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to the ed25519 secret key, as 32 raw bytes or 64 hex digits"
+        )]
+        key: PathBuf,
+
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to an X25519 secret key to decrypt the archive with, instead of a password"
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            short,
+            long,
+            help = "Write the signature to `<boxfile>.sig` instead of embedding it in the archive"
+        )]
+        detached: bool,
+
+        #[structopt(
+            name = "boxfile",
+            parse(from_os_str),
+            help = "Path to the .box archive"
+        )]
+        path: PathBuf,
+    },
+
+    #[structopt(
+        name = "keygen",
+        about = "Generate an X25519 key pair for encryption, or an ed25519 key pair for signing"
+    )]
+    Keygen {
+        #[structopt(short, long, help = "Generate an ed25519 signing key")]
+        signing: bool,
+
+        #[structopt(
+            name = "key",
+            parse(from_os_str),
+            help = "Path to write the secret key to; the public key is written to `<key>.pub`"
+        )]
+        path: PathBuf,
+    },
+
+    #[structopt(name = "verify", about = "Verify the signature of an archive")]
+    Verify {
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to the ed25519 public key, as 32 raw bytes or 64 hex digits"
+        )]
+        key: PathBuf,
+
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to an X25519 secret key to decrypt the archive with, instead of a password"
+        )]
+        identity: Option<PathBuf>,
+
+        #[structopt(
+            short,
+            long,
+            parse(from_os_str),
+            help = "Path to a detached signature [default: the embedded signature, or `<boxfile>.sig`]"
+        )]
+        signature: Option<PathBuf>,
+
         #[structopt(
             name = "boxfile",
             parse(from_os_str),
@@ -155,7 +374,7 @@ enum Commands {
     name = "box",
     about = "Brendan Molloy <https://github.com/bbqsrc/box>\nCreate, modify and extract box archives.",
     settings = &[SubcommandRequiredElseHelp, DisableHelpSubcommand, VersionlessSubcommands],
-    usage = "box (c|l|t|x) [FLAGS|OPTIONS] <boxfile> [files]..."
+    usage = "box (c|l|t|x|sign|verify|keygen) [FLAGS|OPTIONS] <boxfile> [files]..."
 )]
 struct CliOpts {
     #[structopt(short, long, help = "Show verbose output", global = true)]
@@ -221,9 +440,8 @@ fn from_acl_u16(acl: u16) -> String {
 }
 
 #[inline(always)]
-fn time(attr: Option<&[u8]>) -> String {
-    attr.and_then(|mut x| x.read_u64::<LittleEndian>().ok())
-        .map(|x| std::time::UNIX_EPOCH + std::time::Duration::new(x, 0))
+fn time(time: Option<Timestamp>) -> String {
+    time.and_then(Timestamp::to_system_time)
         .map(|x| {
             let datetime: chrono::DateTime<chrono::Utc> = x.into();
             datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
@@ -232,18 +450,25 @@ fn time(attr: Option<&[u8]>) -> String {
 }
 
 #[inline(always)]
-fn unix_acl(attr: Option<&[u8]>) -> String {
-    attr.map(|x| from_acl_u16(u16::from_le_bytes([x[0], x[1]])))
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+#[inline(always)]
+fn unix_acl(mode: Option<u32>) -> String {
+    mode.map(|x| from_acl_u16(x as u16))
         .unwrap_or_else(|| "-".into())
 }
 
-fn list(path: &Path, _selected_files: Vec<PathBuf>, verbose: bool) -> Result<()> {
+fn list(
+    path: &Path,
+    identity: Option<&Path>,
+    _selected_files: Vec<PathBuf>,
+    verbose: bool,
+) -> Result<()> {
     use humansize::{file_size_opts as options, FileSize};
 
-    let bf = BoxFileReader::open(path).map_err(|source| Error::CannotOpenArchive {
-        path: path.to_path_buf(),
-        source,
-    })?;
+    let bf = open_archive(path, identity)?;
     let metadata = bf.metadata();
 
     if verbose {
@@ -255,80 +480,673 @@ fn list(path: &Path, _selected_files: Vec<PathBuf>, verbose: bool) -> Result<()>
         0 => "None".into(),
         v => format!("{} bytes", v),
     };
-    println!("Box archive: {} (alignment: {})", path.display(), alignment);
-    println!("-------------  -------------  -------------  ---------------------  ----------  ---------  --------");
-    println!(" Method         Compressed     Length         Created                Attrs       CRC32      Path");
-    println!("-------------  -------------  -------------  ---------------------  ----------  ---------  --------");
+    let hash_algorithm = metadata.hash_algorithm().ok().flatten();
+    let (hash_header, hash_divider) = match hash_algorithm {
+        Some(algorithm) => (
+            format!("{:<64}   ", algorithm.to_string()),
+            format!("{}  ", "-".repeat(65)),
+        ),
+        None => (String::new(), String::new()),
+    };
+    let hash_cell = |value: String| {
+        if hash_algorithm.is_some() {
+            format!("{:<64}   ", value)
+        } else {
+            String::new()
+        }
+    };
+
+    let encrypted = if bf.has_encrypted_metadata() {
+        ", encrypted with metadata"
+    } else if bf.is_encrypted() {
+        ", encrypted"
+    } else {
+        ""
+    };
+
+    println!(
+        "Box archive: {} (alignment: {}{})",
+        path.display(),
+        alignment,
+        encrypted
+    );
+    println!("-------------  -------------  -------------  ---------------------  ----------  ---------  {}--------", hash_divider);
+    println!(" Method         Compressed     Length         Created                Attrs       CRC32      {}Path", hash_header);
+    println!("-------------  -------------  -------------  ---------------------  ----------  ---------  {}--------", hash_divider);
+    // Files listed after another with the same stored data are deduplicated copies of it.
+    let mut stored = HashSet::new();
     for result in bf.metadata().iter() {
         let record = result.record;
 
-        let acl = unix_acl(record.attr(bf.metadata(), "unix.mode"));
-        let time = time(record.attr(bf.metadata(), "created"));
-        let path = format_path(&result.path, record.as_directory().is_some());
+        let mut acl = unix_acl(record.mode(bf.metadata()));
+        // Marked as `ls -l` does for files with an ACL beyond their mode
+        if record.attr(bf.metadata(), ACL_ATTR).is_some()
+            || record.attr(bf.metadata(), DEFAULT_ACL_ATTR).is_some()
+        {
+            acl.push('+');
+        }
+        let time = time(record.created(bf.metadata()));
+        let path = format_path(&result.path, record.as_directory().is_some());
+
+        match record {
+            Record::Directory(_) => {
+                println!(
+                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {}{}",
+                    "<directory>",
+                    "-",
+                    "-",
+                    time,
+                    acl,
+                    "-",
+                    hash_cell("-".into()),
+                    path,
+                );
+            }
+            Record::Link(link_record) => {
+                let target = format_path(
+                    &link_record.target,
+                    bf.resolve_link(link_record)
+                        .map(|x| x.record.as_directory().is_some())
+                        .unwrap_or(false),
+                );
+
+                println!(
+                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {}{} -> {}",
+                    "<link>",
+                    "-",
+                    "-",
+                    time,
+                    acl,
+                    "-",
+                    hash_cell("-".into()),
+                    path,
+                    target,
+                );
+            }
+            Record::HardLink(link_record) => {
+                let target = bf
+                    .resolve_hard_link(link_record)
+                    .map(|x| format_path(&x.path, false))
+                    .unwrap_or_else(|_| "?".to_string());
+
+                println!(
+                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {}{} -> {}",
+                    "<hardlink>",
+                    "-",
+                    "-",
+                    time,
+                    acl,
+                    "-",
+                    hash_cell("-".into()),
+                    path,
+                    target,
+                );
+            }
+            Record::Special(special) => {
+                let (method, length) = match special.file_type {
+                    SpecialFileType::Fifo => ("<fifo>", "-".to_string()),
+                    SpecialFileType::Socket => ("<socket>", "-".to_string()),
+                    SpecialFileType::CharDevice => {
+                        ("<chardev>", format!("{},{}", special.major, special.minor))
+                    }
+                    SpecialFileType::BlockDevice => {
+                        ("<blockdev>", format!("{},{}", special.major, special.minor))
+                    }
+                };
+
+                println!(
+                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {}{}",
+                    method,
+                    "-",
+                    length,
+                    time,
+                    acl,
+                    "-",
+                    hash_cell("-".into()),
+                    path,
+                );
+            }
+            // Left out when iterating, as they have no name
+            Record::Unknown(_) => {}
+            Record::File(record) => {
+                // Files in a solid block share its length, and files stored as chunks share
+                // theirs with other files, so neither has a length of its own. Inline files
+                // are kept in the metadata and have no stored data at all.
+                let solid = record.attr(bf.metadata(), SOLID_ATTR);
+                let chunks = record.attr(bf.metadata(), CHUNKS_ATTR);
+                let length = if record.attr(bf.metadata(), INLINE_ATTR).is_some() {
+                    "(inline)".to_string()
+                } else if !stored.insert((record.data, record.length, solid, chunks)) {
+                    "(dedup)".to_string()
+                } else if solid.is_some() {
+                    "(solid)".to_string()
+                } else if chunks.is_some() {
+                    "(chunked)".to_string()
+                } else {
+                    record.length.file_size(options::BINARY).unwrap()
+                };
+                // Sparse files are longer than their stored data
+                let decompressed_length = bf
+                    .file_length(record)
+                    .unwrap_or(record.decompressed_length)
+                    .file_size(options::BINARY)
+                    .unwrap();
+                let crc32 = result
+                    .record
+                    .attr_value(bf.metadata(), CRC32_ATTR)
+                    .and_then(|x| x.as_u32())
+                    .map(|x| format!("{:x}", x))
+                    .unwrap_or_else(|| "-".to_string());
+                let hash = record
+                    .attr(bf.metadata(), HASH_ATTR)
+                    .map(hex)
+                    .unwrap_or_else(|| "-".to_string());
+
+                println!(
+                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {}{}",
+                    format!("{:?}", record.compression),
+                    length,
+                    decompressed_length,
+                    time,
+                    acl,
+                    crc32,
+                    hash_cell(hash),
+                    path,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn extract(
+    path: &Path,
+    output_path: &Path,
+    identity: Option<&Path>,
+    same_owner: bool,
+    numeric_owner: bool,
+    verify: bool,
+    _selected_files: Vec<PathBuf>,
+    _verbose: bool,
+) -> Result<()> {
+    println!("{} {}", path.display(), output_path.display());
+    let mut bf = open_archive(path, identity)?;
+    bf.set_same_owner(same_owner);
+    bf.set_numeric_owner(numeric_owner);
+    bf.set_verify_checksums(verify);
+    bf.extract_all(output_path)
+        .map_err(|source| Error::CannotExtractFiles { source })
+}
+
+#[inline(always)]
+fn test_file(bf: &BoxFileReader, record: &FileRecord) -> std::result::Result<(), TestFailure> {
+    bf.verify(record)
+        .map_err(|source| TestFailure::CannotVerify { source })
+}
+
+struct TestSummary {
+    /// The paths to test, along with everything under them, or everything if empty.
+    selected: Vec<BoxPath>,
+    verbose: bool,
+    visited: HashSet<Inode>,
+    passed: usize,
+    failed: usize,
+}
+
+impl TestSummary {
+    fn is_selected(&self, path: &str) -> bool {
+        if self.selected.is_empty() {
+            return true;
+        }
+        match BoxPath::new(path) {
+            Ok(path) => self
+                .selected
+                .iter()
+                .any(|x| path.depth() >= x.depth() && path.starts_with(x)),
+            Err(_) => false,
+        }
+    }
+
+    fn report(&mut self, path: &str, result: std::result::Result<(), TestFailure>) {
+        match result {
+            Ok(()) => {
+                self.passed += 1;
+                if self.verbose {
+                    println!(" OK        {}", path);
+                }
+            }
+            Err(err) => {
+                self.failed += 1;
+                println!(" FAILED    {}: {}", path, err);
+            }
+        }
+    }
+}
+
+fn test_inodes(
+    bf: &BoxFileReader,
+    inodes: &[Inode],
+    parent: Option<&str>,
+    summary: &mut TestSummary,
+) {
+    let metadata = bf.metadata();
+
+    for inode in inodes.iter().copied() {
+        let record = match metadata.record(inode) {
+            Some(v) => v,
+            None => {
+                let path = parent.unwrap_or("<root>");
+                if summary.is_selected(path) {
+                    summary.report(path, Err(TestFailure::MissingInode { inode }));
+                }
+                continue;
+            }
+        };
+
+        let path = match parent {
+            Some(parent) => format!("{}{}{}", parent, PATH_PLATFORM_SEP, record.name()),
+            None => record.name().to_string(),
+        };
+
+        if !summary.visited.insert(inode) {
+            if summary.is_selected(&path) {
+                summary.report(&path, Err(TestFailure::DuplicateInode { inode }));
+            }
+            continue;
+        }
+
+        // Directories have nothing to check of their own, but are walked into
+        if let Record::Directory(dir) = record {
+            test_inodes(bf, &dir.inodes, Some(&path), summary);
+            continue;
+        }
+        if !summary.is_selected(&path) {
+            continue;
+        }
+
+        match record {
+            Record::File(file) => summary.report(&path, test_file(bf, file)),
+            Record::Link(link) => summary.report(
+                &path,
+                bf.resolve_link(link)
+                    .map(|_| ())
+                    .map_err(|_| TestFailure::MissingLinkTarget {
+                        target: link.target.clone(),
+                    }),
+            ),
+            Record::HardLink(link) => summary.report(
+                &path,
+                bf.resolve_hard_link(link)
+                    .map(|_| ())
+                    .map_err(|_| TestFailure::MissingHardLinkTarget { inode: link.target }),
+            ),
+            // Nothing is stored for these beyond their record
+            Record::Directory(_) | Record::Special(_) | Record::Unknown(_) => {}
+        }
+    }
+}
+
+fn test(
+    path: &Path,
+    identity: Option<&Path>,
+    selected_files: Vec<PathBuf>,
+    verbose: bool,
+) -> Result<()> {
+    let bf = open_archive(path, identity)?;
+    let metadata = bf.metadata();
+
+    println!("Testing box archive: {}", path.display());
+
+    let selected = selected_files
+        .iter()
+        .map(|path| {
+            BoxPath::new(path).map_err(|source| Error::CannotHandlePath {
+                path: path.to_path_buf(),
+                source,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut summary = TestSummary {
+        selected,
+        verbose,
+        visited: HashSet::new(),
+        passed: 0,
+        failed: 0,
+    };
+
+    for path in summary.selected.clone() {
+        if metadata.inode(&path).is_none() {
+            summary.report(
+                &path.to_path_buf().display().to_string(),
+                Err(TestFailure::NotFound),
+            );
+        }
+    }
+
+    test_inodes(&bf, metadata.root_inodes(), None, &mut summary);
+
+    // Records out of reach of the root can only be found when testing the whole archive.
+    if summary.selected.is_empty() {
+        for inode in (1..=metadata.inode_count()).filter_map(|x| Inode::new(x).ok()) {
+            if !summary.visited.contains(&inode) {
+                summary.report(
+                    &format!("<inode {}>", inode.get()),
+                    Err(TestFailure::UnreachableInode { inode }),
+                );
+            }
+        }
+    }
+
+    println!(
+        "Tested {} records: {} passed, {} failed",
+        summary.passed + summary.failed,
+        summary.passed,
+        summary.failed
+    );
+
+    if summary.failed > 0 {
+        return Err(Error::TestFailed {
+            path: path.to_path_buf(),
+            count: summary.failed,
+        });
+    }
+
+    Ok(())
+}
+
+fn read_password(prompt: &str) -> Result<String> {
+    rpassword::prompt_password_stderr(prompt).map_err(|source| Error::CannotReadPassword { source })
+}
+
+fn read_new_password() -> Result<String> {
+    let password = read_password("Password: ")?;
+    if password != read_password("Confirm password: ")? {
+        return Err(Error::PasswordMismatch);
+    }
+    Ok(password)
+}
+
+/// Opens an archive for reading. An encrypted archive is unlocked with the identity at
+/// `identity` if given, or else with a password asked for on the terminal.
+fn open_archive(path: &Path, identity: Option<&Path>) -> Result<BoxFileReader> {
+    let mut bf = BoxFileReader::open(path).map_err(|source| Error::CannotOpenArchive {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    if bf.is_encrypted() {
+        match identity {
+            Some(identity) => {
+                let identity = Identity::from_bytes(read_key(identity)?);
+                bf.unlock_with_identity(&identity)
+            }
+            None => {
+                let password = read_password("Password: ")?;
+                bf.unlock_with_password(&password)
+            }
+        }
+        .map_err(|source| Error::CannotUnlockArchive {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    }
+
+    Ok(bf)
+}
+
+/// Opens an archive for reading its metadata, unlocking it as `open_archive` does only if the
+/// metadata is encrypted.
+fn open_metadata(path: &Path, identity: Option<&Path>) -> Result<BoxFileReader> {
+    let bf = BoxFileReader::open(path).map_err(|source| Error::CannotOpenArchive {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    if bf.has_encrypted_metadata() {
+        return open_archive(path, identity);
+    }
+
+    Ok(bf)
+}
+
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|x| u8::from_str_radix(x, 16).ok()))
+        .collect()
+}
+
+fn read_key(path: &Path) -> Result<[u8; 32]> {
+    let bytes = std::fs::read(path).map_err(|source| Error::CannotReadKey {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    // Keys are accepted either as raw bytes or hex-encoded text.
+    let bytes = if bytes.len() == 32 {
+        Some(bytes)
+    } else {
+        std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|x| unhex(x.trim()))
+    };
+
+    match bytes {
+        Some(bytes) if bytes.len() == 32 => {
+            let mut key = [0u8; 32];
+            key.copy_from_slice(&bytes);
+            Ok(key)
+        }
+        _ => Err(Error::InvalidKey {
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+fn read_keypair(path: &Path) -> Result<Keypair> {
+    let secret = SecretKey::from_bytes(&read_key(path)?).map_err(|_| Error::InvalidKey {
+        path: path.to_path_buf(),
+    })?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+fn read_recipient(path: &Path) -> Result<Recipient> {
+    Ok(Recipient::from_bytes(read_key(path)?))
+}
+
+fn write_key(path: &Path, key: &[u8], is_secret: bool) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if is_secret {
+            options.mode(0o600);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = is_secret;
+
+    options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", hex(key)))
+        .map_err(|source| Error::CannotWriteKey {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+fn keygen(path: &Path, signing: bool) -> Result<()> {
+    let (secret, public) = if signing {
+        let secret = SecretKey::generate(&mut rand::rngs::OsRng);
+        let public = PublicKey::from(&secret);
+        (secret.to_bytes(), public.to_bytes())
+    } else {
+        let identity = Identity::generate();
+        (identity.to_bytes(), identity.recipient().to_bytes())
+    };
+
+    let mut public_path = path.as_os_str().to_os_string();
+    public_path.push(".pub");
+
+    write_key(path, &secret, true)?;
+    write_key(Path::new(&public_path), &public, false)?;
+    println!("Public key: {}", hex(&public));
+
+    Ok(())
+}
+
+fn read_public_key(path: &Path) -> Result<PublicKey> {
+    PublicKey::from_bytes(&read_key(path)?).map_err(|_| Error::InvalidKey {
+        path: path.to_path_buf(),
+    })
+}
+
+fn read_signature(path: &Path) -> Result<Signature> {
+    let bytes = std::fs::read(path).map_err(|source| Error::CannotReadSignature {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    Signature::try_from(&bytes[..]).map_err(|_| Error::InvalidSignature {
+        path: path.to_path_buf(),
+    })
+}
+
+#[inline(always)]
+fn detached_signature_path(path: &Path) -> PathBuf {
+    let mut sig_path = path.as_os_str().to_os_string();
+    sig_path.push(".sig");
+    PathBuf::from(sig_path)
+}
 
-        match record {
-            Record::Directory(_) => {
-                println!(
-                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {}",
-                    "<directory>", "-", "-", time, acl, "-", path,
-                );
-            }
-            Record::Link(link_record) => {
-                let target = format_path(
-                    &link_record.target,
-                    bf.resolve_link(&link_record)
-                        .map(|x| x.record.as_directory().is_some())
-                        .unwrap_or(false),
-                );
+fn sign(path: &Path, key: &Path, identity: Option<&Path>, detached: bool) -> Result<()> {
+    let keypair = read_keypair(key)?;
+    let public_key = hex(keypair.public.as_bytes());
 
-                println!(
-                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {} -> {}",
-                    "<link>", "-", "-", time, acl, "-", path, target,
-                );
-            }
-            Record::File(record) => {
-                let length = record.length.file_size(options::BINARY).unwrap();
-                let decompressed_length = record
-                    .decompressed_length
-                    .file_size(options::BINARY)
-                    .unwrap();
-                let crc32 = record
-                    .attr(bf.metadata(), "crc32")
-                    .map(|x| Some(u32::from_le_bytes([x[0], x[1], x[2], x[3]])))
-                    .unwrap_or(None)
-                    .map(|x| format!("{:x}", x))
-                    .unwrap_or_else(|| "-".to_string());
+    if detached {
+        let bf = open_metadata(path, identity)?;
+        let signature = bf
+            .sign(&keypair)
+            .map_err(|source| Error::CannotSignArchive {
+                path: path.to_path_buf(),
+                source,
+            })?;
 
-                println!(
-                    " {:12}  {:>12}   {:>12}   {:<20}   {:<9}   {:>8}   {}",
-                    format!("{:?}", record.compression),
-                    length,
-                    decompressed_length,
-                    time,
-                    acl,
-                    crc32,
-                    path,
-                );
+        let sig_path = detached_signature_path(path);
+        std::fs::write(&sig_path, &signature.to_bytes()[..]).map_err(|source| {
+            Error::CannotCreateFile {
+                path: sig_path.clone(),
+                source,
             }
-        }
+        })?;
+        println!(
+            "Wrote signature for {} to {} (public key: {})",
+            path.display(),
+            sig_path.display(),
+            public_key
+        );
+    } else {
+        let is_sealed = BoxFileReader::open(path)
+            .map_err(|source| Error::CannotOpenArchive {
+                path: path.to_path_buf(),
+                source,
+            })?
+            .has_encrypted_metadata();
+        let bf = match (is_sealed, identity) {
+            (true, Some(identity)) => {
+                let identity = Identity::from_bytes(read_key(identity)?);
+                BoxFileWriter::open_with_identity(path, &identity)
+            }
+            (true, None) => {
+                let password = read_password("Password: ")?;
+                BoxFileWriter::open_with_password(path, &password)
+            }
+            (false, _) => BoxFileWriter::open(path),
+        };
+        let mut bf = bf.map_err(|source| Error::CannotOpenArchive {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        bf.set_signing_key(keypair)
+            .map_err(|source| Error::CannotSignArchive {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        bf.finish().map_err(|source| Error::CannotSignArchive {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        println!("Signed {} (public key: {})", path.display(), public_key);
     }
 
     Ok(())
 }
 
-fn extract(
+fn verify(
     path: &Path,
-    output_path: &Path,
-    _selected_files: Vec<PathBuf>,
-    _verbose: bool,
+    key: &Path,
+    identity: Option<&Path>,
+    signature: Option<PathBuf>,
 ) -> Result<()> {
-    println!("{} {}", path.display(), output_path.display());
-    let bf = BoxFileReader::open(path).map_err(|source| Error::CannotOpenArchive {
+    let public_key = read_public_key(key)?;
+    let bf = open_metadata(path, identity)?;
+
+    // Fall back to a detached signature next to the archive if none is embedded.
+    let sig_path = signature.or_else(|| {
+        let sig_path = detached_signature_path(path);
+        if bf.metadata().file_attr(SIGNATURE_ATTR).is_none() && sig_path.exists() {
+            Some(sig_path)
+        } else {
+            None
+        }
+    });
+
+    let result = match sig_path.as_ref() {
+        Some(sig_path) => bf.verify_detached_signature(&public_key, &read_signature(sig_path)?),
+        None => bf.verify_signature(&public_key),
+    };
+
+    result.map_err(|source| Error::SignatureVerificationFailed {
         path: path.to_path_buf(),
         source,
     })?;
-    bf.extract_all(output_path)
-        .map_err(|source| Error::CannotExtractFiles { source })
+
+    // The signature covers the data of files only through their digests
+    let bf = if bf.is_encrypted() && !bf.has_encrypted_metadata() {
+        open_archive(path, identity)?
+    } else {
+        bf
+    };
+    for item in bf.metadata().iter() {
+        if let Some(file) = item.record.as_file() {
+            bf.verify(file)
+                .map_err(|source| Error::SignatureVerificationFailed {
+                    path: path.to_path_buf(),
+                    source,
+                })?;
+        }
+    }
+
+    match sig_path {
+        Some(sig_path) => println!(
+            "Signature {} is valid for {}",
+            sig_path.display(),
+            path.display()
+        ),
+        None => println!("Embedded signature of {} is valid", path.display()),
+    }
+
+    Ok(())
 }
 
 type ParentDirs = (BoxPath, HashMap<String, Vec<u8>>);
@@ -355,6 +1173,7 @@ fn collect_parent_directories<P: AsRef<Path>>(path: P) -> Result<Vec<ParentDirs>
                     source,
                 })?,
                 metadata(
+                    path,
                     &path
                         .metadata()
                         .map_err(|source| Error::CannotReadFileMetadata {
@@ -371,7 +1190,7 @@ fn collect_parent_directories<P: AsRef<Path>>(path: P) -> Result<Vec<ParentDirs>
 
 #[cfg(unix)]
 #[inline(always)]
-fn metadata(meta: &std::fs::Metadata) -> HashMap<String, Vec<u8>> {
+fn metadata(path: &Path, meta: &std::fs::Metadata) -> HashMap<String, Vec<u8>> {
     let mut attrs = HashMap::new();
 
     macro_rules! attr {
@@ -380,43 +1199,109 @@ fn metadata(meta: &std::fs::Metadata) -> HashMap<String, Vec<u8>> {
         };
     }
 
-    attr!(attrs, "created", meta.ctime());
-    attr!(attrs, "modified", meta.mtime());
-    attr!(attrs, "accessed", meta.atime());
-    attr!(attrs, "unix.mode", meta.mode());
-    attr!(attrs, "unix.uid", meta.uid());
-    attr!(attrs, "unix.gid", meta.gid());
+    macro_rules! attr_time {
+        ($map:ident, $name:expr, $secs:expr, $nanos:expr) => {
+            $map.insert(
+                $name.into(),
+                Timestamp::new($secs, $nanos as u32).to_bytes(),
+            )
+        };
+    }
+
+    // Birth time, which the standard library reads with `statx` where the kernel has it
+    if let Ok(created) = meta.created() {
+        attrs.insert(CREATED_ATTR.into(), Timestamp::from(created).to_bytes());
+    }
+    attr_time!(attrs, MODIFIED_ATTR, meta.mtime(), meta.mtime_nsec());
+    attr_time!(attrs, ACCESSED_ATTR, meta.atime(), meta.atime_nsec());
+    attr_time!(attrs, CHANGED_ATTR, meta.ctime(), meta.ctime_nsec());
+    attr!(attrs, MODE_ATTR, meta.mode());
+    attr!(attrs, UID_ATTR, meta.uid());
+    attr!(attrs, GID_ATTR, meta.gid());
+    if let Some(name) = box_format::user_name(meta.uid()) {
+        attrs.insert(USER_ATTR.into(), name.into_bytes());
+    }
+    if let Some(name) = box_format::group_name(meta.gid()) {
+        attrs.insert(GROUP_ATTR.into(), name.into_bytes());
+    }
+
+    match box_format::read_xattrs(path) {
+        Ok(xattrs) => attrs.extend(xattrs),
+        Err(e) => tracing::warn!("Cannot read extended attributes of {:?}: {}", path, e),
+    }
+    match box_format::read_acls(path) {
+        Ok(acls) => attrs.extend(acls),
+        Err(e) => tracing::warn!("Cannot read ACLs of {:?}: {}", path, e),
+    }
 
     attrs
 }
 
 #[cfg(not(unix))]
 #[inline(always)]
-fn metadata(meta: &std::fs::Metadata) -> HashMap<String, Vec<u8>> {
+fn metadata(_path: &Path, meta: &std::fs::Metadata) -> HashMap<String, Vec<u8>> {
     let mut attrs = HashMap::new();
 
     macro_rules! attr_systime {
         ($map:ident, $name:expr, $data:expr) => {
             if let Ok(value) = $data {
-                let bytes = value
-                    .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-                    .to_le_bytes()
-                    .to_vec();
-
-                $map.insert($name.into(), bytes);
+                $map.insert($name.into(), Timestamp::from(value).to_bytes());
             }
         };
     }
 
-    attr_systime!(attrs, "created", meta.created());
-    attr_systime!(attrs, "modified", meta.modified());
-    attr_systime!(attrs, "accessed", meta.accessed());
+    attr_systime!(attrs, CREATED_ATTR, meta.created());
+    attr_systime!(attrs, MODIFIED_ATTR, meta.modified());
+    attr_systime!(attrs, ACCESSED_ATTR, meta.accessed());
 
     attrs
 }
 
+/// Identifies a file with more than one name, so that the names after the first can be
+/// stored as hard links to it.
+#[cfg(unix)]
+#[inline(always)]
+fn hard_link_key(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    if meta.nlink() > 1 {
+        Some((meta.dev(), meta.ino()))
+    } else {
+        None
+    }
+}
+
+/// The type and device numbers of a FIFO, device node or socket.
+#[cfg(unix)]
+fn special_file(meta: &std::fs::Metadata) -> Option<(SpecialFileType, u32, u32)> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = meta.file_type();
+    let rdev = meta.rdev() as libc::dev_t;
+    let (major, minor) = (libc::major(rdev) as u32, libc::minor(rdev) as u32);
+
+    if file_type.is_fifo() {
+        Some((SpecialFileType::Fifo, 0, 0))
+    } else if file_type.is_socket() {
+        Some((SpecialFileType::Socket, 0, 0))
+    } else if file_type.is_char_device() {
+        Some((SpecialFileType::CharDevice, major, minor))
+    } else if file_type.is_block_device() {
+        Some((SpecialFileType::BlockDevice, major, minor))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file(_meta: &std::fs::Metadata) -> Option<(SpecialFileType, u32, u32)> {
+    None
+}
+
+#[cfg(not(unix))]
+#[inline(always)]
+fn hard_link_key(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
 #[inline(always)]
 #[cfg(not(windows))]
 fn is_hidden<C: ClientState>(entry: &DirEntry<C>) -> bool {
@@ -436,31 +1321,6 @@ fn is_hidden<C: ClientState>(entry: &DirEntry<C>) -> bool {
     }
 }
 
-struct Crc32Reader<R: Read> {
-    inner: R,
-    hasher: crc32fast::Hasher,
-}
-
-impl<R: Read> Crc32Reader<R> {
-    pub fn new(inner: R) -> Crc32Reader<R> {
-        Crc32Reader {
-            inner,
-            hasher: crc32fast::Hasher::new(),
-        }
-    }
-
-    pub fn finalize(self) -> u32 {
-        self.hasher.finalize()
-    }
-}
-
-impl<R: Read> Read for Crc32Reader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.hasher.update(&buf);
-        self.inner.read(buf)
-    }
-}
-
 #[allow(clippy::too_many_arguments)]
 #[inline(always)]
 fn process_files<I: Iterator<Item = PathBuf>>(
@@ -489,6 +1349,8 @@ fn process_files<I: Iterator<Item = PathBuf>>(
         walker.into_iter()
     });
 
+    let mut hard_links = HashMap::new();
+
     for entry in iter {
         let entry = entry.map_err(|source| Error::CannotProcessDirEntry { source })?;
         let file_type = entry.file_type;
@@ -573,7 +1435,7 @@ fn process_files<I: Iterator<Item = PathBuf>>(
                     if verbose {
                         println!("{} -> {} (link)", &file_path.display(), &target_path);
                     }
-                    bf.link(box_path.clone(), target_path, metadata(&meta))
+                    bf.link(box_path.clone(), target_path, metadata(&file_path, &meta))
                         .map_err(|source| Error::CannotCreateLink {
                             path: box_path.clone(),
                             source,
@@ -584,7 +1446,7 @@ fn process_files<I: Iterator<Item = PathBuf>>(
                 if verbose {
                     println!("{} -> {} (link)", &file_path.display(), &target_path);
                 }
-                bf.link(box_path.clone(), target_path, metadata(&meta))
+                bf.link(box_path.clone(), target_path, metadata(&file_path, &meta))
                     .map_err(|source| Error::CannotCreateLink {
                         path: box_path.clone(),
                         source,
@@ -596,41 +1458,83 @@ fn process_files<I: Iterator<Item = PathBuf>>(
                 if verbose {
                     println!("{} (directory)", &file_path.display());
                 }
-                bf.mkdir(box_path.clone(), metadata(&meta))
+                bf.mkdir(box_path.clone(), metadata(&file_path, &meta))
                     .map_err(|source| Error::CannotCreateDirectory {
                         path: box_path.clone(),
                         source,
                     })?;
                 known_dirs.insert(box_path);
             }
+        } else if let Some((special_type, major, minor)) = special_file(&meta) {
+            if !known_files.contains(&box_path) {
+                if verbose {
+                    println!("{} ({:?})", &file_path.display(), special_type);
+                }
+                bf.mknod(
+                    box_path.clone(),
+                    special_type,
+                    major,
+                    minor,
+                    metadata(&file_path, &meta),
+                )
+                .map_err(|source| Error::CannotCreateSpecialFile {
+                    path: box_path.clone(),
+                    source,
+                })?;
+                known_files.insert(box_path);
+            }
         } else if !known_files.contains(&box_path) {
-            let file = std::fs::File::open(&file_path).map_err(|source| Error::CannotOpenFile {
-                path: file_path.to_path_buf(),
-                source,
-            })?;
-            let mut file = BufReader::new(Crc32Reader::new(file));
+            let hard_link_key = hard_link_key(&meta);
+            if let Some(target) = hard_link_key.and_then(|key| hard_links.get(&key)) {
+                if verbose {
+                    println!("{} -> {} (hard link)", &file_path.display(), target);
+                }
+                bf.hard_link(box_path.clone(), target, metadata(&file_path, &meta))
+                    .map_err(|source| Error::CannotCreateLink {
+                        path: box_path.clone(),
+                        source,
+                    })?;
+                known_files.insert(box_path);
+                continue;
+            }
+
+            let mut file =
+                std::fs::File::open(&file_path).map_err(|source| Error::CannotOpenFile {
+                    path: file_path.to_path_buf(),
+                    source,
+                })?;
             let record = bf
-                .insert(compression, box_path.clone(), &mut file, metadata(&meta))
+                .insert_sparse(
+                    compression,
+                    box_path.clone(),
+                    &mut file,
+                    metadata(&file_path, &meta),
+                )
                 .map_err(|source| Error::CannotAddFile {
                     path: file_path.to_path_buf(),
                     source,
                 })?;
-            if verbose {
-                let len = if record.decompressed_length == 0 {
+            let (length, decompressed_length) = (record.length, record.decompressed_length);
+            if verbose && bf.metadata().attr(&box_path, INLINE_ATTR).is_some() {
+                println!("{} (inline)", &file_path.display());
+            } else if verbose && bf.metadata().attr(&box_path, SOLID_ATTR).is_some() {
+                println!("{} (solid)", &file_path.display());
+            } else if verbose && bf.metadata().attr(&box_path, CHUNKS_ATTR).is_some() {
+                println!("{} (chunked)", &file_path.display());
+            } else if verbose && bf.metadata().attr(&box_path, SPARSE_ATTR).is_some() {
+                println!("{} (sparse)", &file_path.display());
+            } else if verbose {
+                let len = if decompressed_length == 0 {
                     100.0f64
                 } else {
-                    100.0 - (record.length as f64 / record.decompressed_length as f64 * 100.0)
+                    100.0 - (length as f64 / decompressed_length as f64 * 100.0)
                 };
                 println!("{} (compressed {:.*}%)", &file_path.display(), 2, len);
             }
 
-            let hash = file.into_inner().finalize().to_le_bytes().to_vec();
-            bf.set_attr(&box_path, "crc32", hash)
-                .map_err(|source| Error::CannotAddChecksum {
-                    path: file_path,
-                    source,
-                })?;
-
+            if let Some(key) = hard_link_key {
+                hard_links.insert(key, box_path.clone());
+            }
             known_files.insert(box_path);
         }
     }
@@ -641,16 +1545,55 @@ fn process_files<I: Iterator<Item = PathBuf>>(
         .map(|_| {})
 }
 
+/// How a new archive is to be laid out, signed and encrypted.
+struct CreateOptions {
+    compression: Compression,
+    alignment: Option<NonZeroU64>,
+    is_self_extracting: bool,
+    block_size: Option<NonZeroU32>,
+    solid_size: Option<NonZeroU32>,
+    inline_size: Option<NonZeroU32>,
+    hash_algorithm: Option<HashAlgorithm>,
+    deduplicate: bool,
+    chunk_size: Option<NonZeroU32>,
+    signing_key: Option<Keypair>,
+    password: Option<String>,
+    recipients: Vec<Recipient>,
+    encrypt_metadata: bool,
+    metadata_compression: Option<Compression>,
+    record_index: bool,
+}
+
 fn create(
     mut path: PathBuf,
     selected_files: Vec<PathBuf>,
-    compression: Compression,
+    options: CreateOptions,
     recursive: bool,
     allow_hidden: bool,
     verbose: bool,
-    alignment: Option<NonZeroU64>,
-    is_self_extracting: bool,
 ) -> Result<()> {
+    let CreateOptions {
+        compression,
+        alignment,
+        is_self_extracting,
+        block_size,
+        solid_size,
+        inline_size,
+        hash_algorithm,
+        deduplicate,
+        chunk_size,
+        signing_key,
+        password,
+        recipients,
+        encrypt_metadata,
+        metadata_compression,
+        record_index,
+    } = options;
+
+    if encrypt_metadata && password.is_none() && recipients.is_empty() {
+        return Err(Error::NoEncryptionKey);
+    }
+
     let original_path = path.clone();
 
     // if is_self_extracting {
@@ -666,18 +1609,68 @@ fn create(
         source,
     })?;
 
-    let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        .to_le_bytes();
+    let now = Timestamp::from(SystemTime::now()).to_bytes();
 
-    bf.set_file_attr("created", now.to_vec())
+    bf.set_file_attr(CREATED_ATTR, now.clone())
         .map_err(|source| Error::CannotSetAttribute {
-            key: "created".to_string(),
-            value: now.to_vec(),
+            key: CREATED_ATTR.to_string(),
+            value: now,
+            source,
+        })?;
+
+    if let Some(algorithm) = hash_algorithm {
+        bf.set_hash_algorithm(algorithm)
+            .map_err(|source| Error::CannotSetAttribute {
+                key: HASH_ALGORITHM_ATTR.to_string(),
+                value: algorithm.name().as_bytes().to_vec(),
+                source,
+            })?;
+    }
+
+    if let Some(keypair) = signing_key {
+        bf.set_signing_key(keypair)
+            .map_err(|source| Error::CannotSignArchive {
+                path: path.to_path_buf(),
+                source,
+            })?;
+    }
+
+    if let Some(password) = password {
+        bf.set_password(&password)
+            .map_err(|source| Error::CannotEncryptArchive {
+                path: path.to_path_buf(),
+                source,
+            })?;
+    }
+
+    for recipient in recipients.iter() {
+        bf.add_recipient(recipient)
+            .map_err(|source| Error::CannotEncryptArchive {
+                path: path.to_path_buf(),
+                source,
+            })?;
+    }
+
+    bf.set_encrypted_metadata(encrypt_metadata);
+    bf.set_trailer_compression(metadata_compression);
+    bf.set_record_index(record_index);
+    bf.set_deduplicate(deduplicate)
+        .map_err(|source| Error::CannotCreateArchive {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    bf.set_chunk_size(chunk_size)
+        .map_err(|source| Error::CannotCreateArchive {
+            path: path.to_path_buf(),
             source,
         })?;
+    bf.set_block_size(block_size);
+    bf.set_solid_size(solid_size)
+        .map_err(|source| Error::CannotCreateArchive {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    bf.set_inline_size(inline_size);
 
     process_files(
         selected_files.into_iter(),
@@ -732,10 +1725,26 @@ fn main() -> Result<()> {
     let opts = CliOpts::from_args();
 
     match opts.cmd {
-        Commands::List { path } => list(&path, opts.selected_files, opts.verbose),
-        Commands::Extract { path, output_path } => extract(
+        Commands::List { path, identity } => list(
+            &path,
+            identity.as_deref(),
+            opts.selected_files,
+            opts.verbose,
+        ),
+        Commands::Extract {
+            path,
+            output_path,
+            identity,
+            no_same_owner,
+            numeric_owner,
+            no_verify,
+        } => extract(
             &path,
             &output_path.unwrap_or_else(|| std::env::current_dir().expect("no pwd")),
+            identity.as_deref(),
+            !no_same_owner,
+            numeric_owner,
+            !no_verify,
             opts.selected_files,
             opts.verbose,
         ),
@@ -743,20 +1752,71 @@ fn main() -> Result<()> {
             path,
             alignment,
             compression,
+            block_size,
+            solid_size,
+            inline_size,
+            hash_algorithm,
+            deduplicate,
+            chunk_size,
+            signing_key,
+            encrypt,
+            recipients,
+            encrypt_metadata,
+            metadata_compression,
+            record_index,
             recursive,
             allow_hidden,
             is_self_extracting,
         } => create(
             path,
             opts.selected_files,
-            compression,
+            CreateOptions {
+                compression,
+                alignment,
+                is_self_extracting,
+                block_size,
+                solid_size,
+                inline_size,
+                hash_algorithm,
+                deduplicate,
+                chunk_size,
+                signing_key: signing_key.as_deref().map(read_keypair).transpose()?,
+                password: if encrypt {
+                    Some(read_new_password()?)
+                } else {
+                    None
+                },
+                recipients: recipients
+                    .iter()
+                    .map(|x| read_recipient(x))
+                    .collect::<Result<Vec<_>>>()?,
+                encrypt_metadata,
+                metadata_compression,
+                record_index,
+            },
             recursive,
             allow_hidden,
             opts.verbose,
-            alignment,
-            is_self_extracting,
         ),
-        Commands::Test { .. } => unimplemented!(),
+        Commands::Test { path, identity } => test(
+            &path,
+            identity.as_deref(),
+            opts.selected_files,
+            opts.verbose,
+        ),
+        Commands::Sign {
+            path,
+            key,
+            identity,
+            detached,
+        } => sign(&path, &key, identity.as_deref(), detached),
+        Commands::Verify {
+            path,
+            key,
+            identity,
+            signature,
+        } => verify(&path, &key, identity.as_deref(), signature),
+        Commands::Keygen { path, signing } => keygen(&path, signing),
     }
 }
 
@@ -765,6 +1825,9 @@ enum Error {
     #[error("Unknown compression method `{name}`")]
     UnknownCompressionFormat { name: String },
 
+    #[error("Unknown hash algorithm `{name}`")]
+    UnknownHashAlgorithm { name: String },
+
     #[error("Cannot handle path `{}`", .path.display())]
     CannotHandlePath {
         path: PathBuf,
@@ -800,6 +1863,13 @@ enum Error {
         source: std::io::Error,
     },
 
+    #[error("Cannot create special file `{}`", path)]
+    CannotCreateSpecialFile {
+        path: BoxPath,
+        #[source]
+        source: std::io::Error,
+    },
+
     #[error("Cannot read metadata of file `{}`", path.display())]
     CannotReadFileMetadata {
         path: PathBuf,
@@ -821,13 +1891,6 @@ enum Error {
         source: std::io::Error,
     },
 
-    #[error("Cannot add checksum for file `{}`", path.display())]
-    CannotAddChecksum {
-        path: PathBuf,
-        #[source]
-        source: std::io::Error,
-    },
-
     #[error("Cannot create archive `{}`", path.display())]
     CannotCreateArchive {
         path: PathBuf,
@@ -895,4 +1958,101 @@ enum Error {
         #[source]
         source: std::io::Error,
     },
+
+    #[error("Archive `{}` failed integrity test with {count} error(s)", .path.display())]
+    TestFailed { path: PathBuf, count: usize },
+
+    #[error("Cannot read key `{}`", .path.display())]
+    CannotReadKey {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid key `{}`", .path.display())]
+    InvalidKey { path: PathBuf },
+
+    #[error("Cannot write key `{}`", .path.display())]
+    CannotWriteKey {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Cannot read signature `{}`", .path.display())]
+    CannotReadSignature {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid ed25519 signature `{}`", .path.display())]
+    InvalidSignature { path: PathBuf },
+
+    #[error("Cannot sign archive `{}`", .path.display())]
+    CannotSignArchive {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Cannot read password")]
+    CannotReadPassword {
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Passwords do not match")]
+    PasswordMismatch,
+
+    #[error("Encrypting metadata requires a password or recipient")]
+    NoEncryptionKey,
+
+    #[error("Cannot encrypt archive `{}`", .path.display())]
+    CannotEncryptArchive {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Cannot unlock archive `{}`", .path.display())]
+    CannotUnlockArchive {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Signature verification failed for archive `{}`", .path.display())]
+    SignatureVerificationFailed {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+enum TestFailure {
+    #[error("{source}")]
+    CannotVerify {
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("link target `{target}` not found")]
+    MissingLinkTarget { target: BoxPath },
+
+    #[error("hard link target inode {} is not a file", .inode.get())]
+    MissingHardLinkTarget { inode: Inode },
+
+    #[error("no record found for inode {}", .inode.get())]
+    MissingInode { inode: Inode },
+
+    #[error("inode {} is referenced more than once", .inode.get())]
+    DuplicateInode { inode: Inode },
+
+    #[error("inode {} is not reachable from the root", .inode.get())]
+    UnreachableInode { inode: Inode },
+
+    #[error("not found in the archive")]
+    NotFound,
 }
@@ -1,31 +1,88 @@
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::path::PathBuf;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, UNIX_EPOCH};
 
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    Request,
+    ReplyXattr, Request,
 };
-use libc::{ENOENT, ENOSYS};
+use libc::{EIO, ENODATA, ENOENT, ENOSYS, ERANGE};
 use structopt::StructOpt;
 
-use box_format::{BoxFileReader, BoxMetadata, Inode};
+use box_format::{
+    BoxFileReader, BoxMetadata, Inode, Record, SpecialFileType, Timestamp, CREATED_ATTR,
+};
+
+struct BoxFs {
+    reader: BoxFileReader,
+    /// The number of hard links naming each file that has any.
+    hard_links: HashMap<Inode, u32>,
+    /// The data of open files that cannot be read from an offset without decompressing
+    /// everything before it, until they are released.
+    cache: HashMap<Inode, Vec<u8>>,
+}
 
-struct BoxFs(BoxFileReader, HashMap<Inode, Vec<u8>>);
+impl BoxFs {
+    fn new(reader: BoxFileReader) -> BoxFs {
+        let mut hard_links = HashMap::new();
+        for item in reader.metadata().iter() {
+            if let Some(link) = item.record.as_hard_link() {
+                *hard_links.entry(link.target).or_insert(0) += 1;
+            }
+        }
+
+        BoxFs {
+            reader,
+            hard_links,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Resolves a hard link to the file it names, as they share an inode.
+    fn resolve<'a>(&'a self, inode: Inode, record: &'a Record) -> Option<(Inode, &'a Record)> {
+        match record.as_hard_link() {
+            Some(link) => self
+                .reader
+                .metadata()
+                .record(link.target)
+                .map(|x| (link.target, x)),
+            None => Some((inode, record)),
+        }
+    }
+
+    /// The extended attributes of the record at a FUSE inode, which the root has none of.
+    fn xattrs(&self, ino: u64) -> Option<Vec<(&str, &[u8])>> {
+        let meta = self.reader.metadata();
+        match inode(ino) {
+            Some(inode) => {
+                let (_, record) = self.resolve(inode, meta.record(inode)?)?;
+                Some(record.xattrs(meta))
+            }
+            None => Some(vec![]),
+        }
+    }
+
+    fn file_attr(&self, inode: Inode, record: &Record) -> Option<FileAttr> {
+        let (inode, record) = self.resolve(inode, record)?;
+        let nlink = 1 + self.hard_links.get(&inode).copied().unwrap_or(0);
+        let mut attr = record.fuse_file_attr(self.reader.metadata(), inode, nlink);
+
+        // A sparse file is longer than the data stored for it, which is all that takes space.
+        if let Some(file) = record.as_file() {
+            attr.size = self.reader.file_length(file).ok()?;
+        }
+        Some(attr)
+    }
+}
 
 const TTL: Duration = Duration::from_secs(1);
 
 fn root_dir_attr(meta: &BoxMetadata) -> FileAttr {
-    let ctime = match meta.file_attr("created") {
-        Some(b) if b.len() == 8 => {
-            let secs = u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
-            UNIX_EPOCH
-                .checked_add(Duration::from_secs(secs))
-                .unwrap_or(UNIX_EPOCH)
-        }
-        _ => UNIX_EPOCH,
-    };
+    let ctime = meta
+        .file_attr(CREATED_ATTR)
+        .and_then(|x| Timestamp::from_bytes(x)?.to_system_time())
+        .unwrap_or(UNIX_EPOCH);
 
     FileAttr {
         ino: 1,
@@ -47,13 +104,12 @@ fn root_dir_attr(meta: &BoxMetadata) -> FileAttr {
 
 trait RecordExt {
     fn fuse_file_type(&self) -> FileType;
-    fn fuse_file_attr(&self, meta: &BoxMetadata, inode: Inode) -> FileAttr;
+    fn fuse_file_attr(&self, meta: &BoxMetadata, inode: Inode, nlink: u32) -> FileAttr;
 
     fn perm(&self, meta: &BoxMetadata) -> u16;
-    fn ctime(&self, meta: &BoxMetadata) -> SystemTime;
 }
 
-impl RecordExt for box_format::Record {
+impl RecordExt for Record {
     fn fuse_file_type(&self) -> FileType {
         use box_format::Record::*;
 
@@ -61,66 +117,89 @@ impl RecordExt for box_format::Record {
             File(_) => FileType::RegularFile,
             Directory(_) => FileType::Directory,
             Link(_) => FileType::Symlink,
+            HardLink(_) => FileType::RegularFile,
+            Special(special) => match special.file_type {
+                SpecialFileType::Fifo => FileType::NamedPipe,
+                SpecialFileType::CharDevice => FileType::CharDevice,
+                SpecialFileType::BlockDevice => FileType::BlockDevice,
+                SpecialFileType::Socket => FileType::Socket,
+            },
+            Unknown(_) => FileType::RegularFile,
         }
     }
 
-    fn fuse_file_attr(&self, meta: &BoxMetadata, inode: Inode) -> FileAttr {
-        let kind = self.fuse_file_type();
-        let nlink = 1;
-        let blocks = if kind == FileType::RegularFile { 1 } else { 0 };
-
+    #[allow(unknown_lints, clippy::manual_div_ceil)]
+    fn fuse_file_attr(&self, meta: &BoxMetadata, inode: Inode, nlink: u32) -> FileAttr {
         use box_format::Record::*;
+        // Counted in 512-byte units of the data stored, which leaves out holes
+        let blocks = match self {
+            File(record) => (record.decompressed_length + 511) / 512,
+            _ => 0,
+        };
         let size = match self {
             File(record) => record.decompressed_length,
             Directory(record) => record.inodes.len() as u64,
-            Link(_) => 1,
+            Link(_) | HardLink(_) => 1,
+            Special(_) | Unknown(_) => 0,
+        };
+        // Encoded as the kernel does, with the low bits of the minor number first.
+        let rdev = match self {
+            Special(special) => {
+                (special.minor & 0xff) | (special.major << 8) | ((special.minor & !0xff) << 12)
+            }
+            _ => 0,
         };
 
         let perm = self.perm(meta) & 0o0555;
-        let ctime = self.ctime(meta);
+        let crtime = self
+            .created(meta)
+            .and_then(Timestamp::to_system_time)
+            .unwrap_or(UNIX_EPOCH);
+        // Archives made before the other times were recorded kept the change time as created
+        let time = |x: Option<Timestamp>| x.and_then(Timestamp::to_system_time).unwrap_or(crtime);
 
         FileAttr {
             ino: inode.get() + 1,
             size,
             blocks,
-            atime: ctime,
-            mtime: ctime,
-            ctime,
-            crtime: ctime,
+            atime: time(self.accessed(meta)),
+            mtime: time(self.modified(meta)),
+            ctime: time(self.changed(meta)),
+            crtime,
             kind: self.fuse_file_type(),
             perm,
             nlink,
             uid: 501,
             gid: 20,
-            rdev: 0,
+            rdev,
             flags: 0,
         }
     }
 
     fn perm(&self, meta: &BoxMetadata) -> u16 {
-        match self.attr(meta, "unix.mode") {
-            Some(bytes) if bytes.len() == 2 => u16::from_le_bytes([bytes[0], bytes[1]]),
-            _ => {
+        match self.mode(meta) {
+            Some(mode) => (mode & 0o7777) as u16,
+            None => {
                 use box_format::Record::*;
                 match self {
-                    File(_) => 0o644,
+                    File(_) | HardLink(_) | Special(_) | Unknown(_) => 0o644,
                     Directory(_) => 0o755,
                     Link(_) => 0x644,
                 }
             }
         }
     }
+}
 
-    fn ctime(&self, meta: &BoxMetadata) -> SystemTime {
-        match self.attr(meta, "created") {
-            Some(b) if b.len() == 8 => {
-                let secs = u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]);
-                UNIX_EPOCH
-                    .checked_add(Duration::from_secs(secs))
-                    .unwrap_or(UNIX_EPOCH)
-            }
-            _ => UNIX_EPOCH,
-        }
+/// Replies with the size of an extended attribute value or list if asked for it with a `size`
+/// of zero, and otherwise with the data if it fits.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
     }
 }
 
@@ -140,11 +219,11 @@ impl Filesystem for BoxFs {
 
         let records = match inode(parent) {
             Some(inode) => match self
-                .0
+                .reader
                 .metadata()
                 .record(inode)
                 .and_then(|x| x.as_directory())
-                .map(|x| self.0.metadata().records(x))
+                .map(|x| self.reader.metadata().records(x))
             {
                 Some(v) => v,
                 None => {
@@ -152,16 +231,17 @@ impl Filesystem for BoxFs {
                     return;
                 }
             },
-            None => self.0.metadata().root_records(),
+            None => self.reader.metadata().root_records(),
         };
 
         match records
             .iter()
             .find(|(_inode, record)| record.name() == name)
         {
-            Some((inode, record)) => {
-                reply.entry(&TTL, &record.fuse_file_attr(self.0.metadata(), *inode), 0);
-            }
+            Some((inode, record)) => match self.file_attr(*inode, record) {
+                Some(file_attr) => reply.entry(&TTL, &file_attr, 0),
+                None => reply.error(ENOENT),
+            },
             None => {
                 reply.error(ENOENT);
             }
@@ -185,15 +265,12 @@ impl Filesystem for BoxFs {
             }
         };
 
-        let offset = offset as usize;
-        let size = size as usize;
-
-        if let Some(cached) = self.1.get(&inode) {
-            reply.data(&(&*cached)[offset..size + offset]);
-            return;
-        }
-
-        let record = match self.0.metadata().record(inode).and_then(|x| x.as_file()) {
+        let record = match self
+            .reader
+            .metadata()
+            .record(inode)
+            .and_then(|x| x.as_file())
+        {
             Some(v) => v,
             None => {
                 reply.error(ENOENT);
@@ -201,16 +278,34 @@ impl Filesystem for BoxFs {
             }
         };
 
-        let mut buf = Vec::with_capacity(size as usize);
-        match self.0.decompress(record, &mut buf) {
-            Ok(_) => {
-                reply.data(&(&*buf)[offset..size + offset]);
-                self.1.insert(inode, buf);
+        let offset = offset as usize;
+        let size = size as usize;
+
+        if self.reader.is_random_access(record) {
+            match self.reader.read_at(record, offset as u64, size) {
+                Ok(buf) => reply.data(&buf),
+                Err(err) => {
+                    log::error!("{}", err);
+                    reply.error(EIO);
+                }
             }
-            Err(_) => {
-                reply.error(ENOENT);
+            return;
+        }
+
+        if !self.cache.contains_key(&inode) {
+            let mut buf = Vec::with_capacity(record.decompressed_length as usize);
+            if let Err(err) = self.reader.decompress(record, &mut buf) {
+                log::error!("{}", err);
+                reply.error(EIO);
+                return;
             }
+            self.cache.insert(inode, buf);
         }
+
+        let cached = &self.cache[&inode];
+        let start = offset.min(cached.len());
+        let end = offset.saturating_add(size).min(cached.len());
+        reply.data(&cached[start..end]);
     }
 
     fn release(
@@ -224,7 +319,7 @@ impl Filesystem for BoxFs {
         reply: ReplyEmpty,
     ) {
         if let Some(inode) = inode(ino) {
-            self.1.remove(&inode);
+            self.cache.remove(&inode);
             reply.ok();
         } else {
             reply.error(ENOENT);
@@ -233,9 +328,13 @@ impl Filesystem for BoxFs {
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         match inode(ino) {
-            Some(inode) => match self.0.metadata().record(inode) {
-                Some(record) => {
-                    let file_attr = record.fuse_file_attr(self.0.metadata(), inode);
+            Some(inode) => match self
+                .reader
+                .metadata()
+                .record(inode)
+                .and_then(|record| self.file_attr(inode, record))
+            {
+                Some(file_attr) => {
                     reply.attr(&TTL, &file_attr);
                 }
                 None => {
@@ -243,7 +342,7 @@ impl Filesystem for BoxFs {
                 }
             },
             None => {
-                reply.attr(&TTL, &root_dir_attr(self.0.metadata()));
+                reply.attr(&TTL, &root_dir_attr(self.reader.metadata()));
                 return;
             }
         }
@@ -259,11 +358,11 @@ impl Filesystem for BoxFs {
     ) {
         let records = match inode(ino) {
             Some(inode) => match self
-                .0
+                .reader
                 .metadata()
                 .record(inode)
                 .and_then(|x| x.as_directory())
-                .map(|x| self.0.metadata().records(x))
+                .map(|x| self.reader.metadata().records(x))
             {
                 Some(v) => v,
                 None => {
@@ -271,12 +370,16 @@ impl Filesystem for BoxFs {
                     return;
                 }
             },
-            None => self.0.metadata().root_records(),
+            None => self.reader.metadata().root_records(),
         };
 
         for (i, (inode, record)) in records.iter().enumerate().skip(offset as usize) {
             log::info!("{:?}", record);
 
+            let (inode, record) = match self.resolve(*inode, record) {
+                Some(v) => v,
+                None => continue,
+            };
             let is_full = reply.add(
                 inode.get() + 1,
                 i as i64 + 1,
@@ -295,6 +398,45 @@ impl Filesystem for BoxFs {
     fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
         reply.error(ENOSYS);
     }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let xattrs = match self.xattrs(ino) {
+            Some(v) => v,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        match xattrs.iter().find(|(x, _)| Some(*x) == name.to_str()) {
+            Some((_, value)) => reply_xattr(reply, size, value),
+            None => reply.error(ENODATA),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let xattrs = match self.xattrs(ino) {
+            Some(v) => v,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // Names each followed by a NUL byte
+        let names = xattrs
+            .iter()
+            .flat_map(|(name, _)| name.bytes().chain(std::iter::once(0)))
+            .collect::<Vec<u8>>();
+        reply_xattr(reply, size, &names);
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -304,16 +446,21 @@ struct Options {
 
     #[structopt(parse(from_os_str))]
     mountpoint: PathBuf,
+
+    /// Do not check file data against the checksums recorded in the archive
+    #[structopt(long = "no-verify")]
+    no_verify: bool,
 }
 
 fn main() {
     env_logger::init();
     let opts = Options::from_args();
-    let bf = BoxFileReader::open(opts.box_file).unwrap();
+    let mut bf = BoxFileReader::open(opts.box_file).unwrap();
+    bf.set_verify_checksums(!opts.no_verify);
     log::info!("{:?}", &bf);
     let fsname = OsString::from(format!("fsname={}", bf.path().display()));
     let x = vec!["-o", "ro", "-o"];
     let mut options = x.iter().map(|o| o.as_ref()).collect::<Vec<&OsStr>>();
     options.push(&fsname);
-    fuse::mount(BoxFs(bf, HashMap::new()), &opts.mountpoint, &options).unwrap();
+    fuse::mount(BoxFs::new(bf), &opts.mountpoint, &options).unwrap();
 }